@@ -0,0 +1,51 @@
+// Times decoding Keen 4's shipped data: `cargo bench`, or `cargo bench -- 50` for more runs.
+// Each step is run a number of times and its median printed, so a one-off slow run doesn't skew it.
+
+use dopefish_decoder::{Game, lazy, map_renderer, parse, parse_audio, parse_graphics, parse_maps, read, versions};
+use std::time::{Duration, Instant};
+
+const DIR: &str = "data/keen4";
+
+fn main() {
+    let runs: usize = std::env::args().skip(1).find_map(|a| a.parse().ok()).unwrap_or(20);
+    let Ok(paths) = read::Paths::discover(DIR) else {
+        println!("Skipping, as there's no game in {}", DIR);
+        return
+    };
+    let read = |path: &str| std::fs::read(path).unwrap();
+    let (exe, graph, maps) = (read(paths.exe.as_deref().unwrap()), read(&paths.graph), read(&paths.maps));
+    let audio = read(paths.audio.as_deref().unwrap());
+    let detection = versions::determine(&exe, &graph.as_slice(), maps.len(), Some(&audio), versions::GivenGraphTables::default()).unwrap();
+    let offsets = &detection.offsets;
+    let table = |offset: usize, len: usize| &exe[offset .. offset + len];
+    let (graph_head, graph_dict) = (table(offsets.graph_head_offset, offsets.graph_head_len), table(offsets.graph_dict_offset, offsets.graph_dict_len));
+    let map_head = table(offsets.map_head_offset, offsets.map_head_len);
+    let audio_offsets = offsets.audio.unwrap();
+    let (audio_head, audio_dict) = (table(audio_offsets.head_offset, audio_offsets.head_len), table(audio_offsets.dict_offset, audio_offsets.dict_len));
+    let episode = detection.version.map(|v| v.episode());
+
+    println!("Median of {} runs:", runs);
+    time("graphics", runs, || { parse_graphics::parse(graph.as_slice(), graph_head, graph_dict, episode, false, None).unwrap(); });
+    time("maps", runs, || { parse_maps::parse(&maps.as_slice(), map_head, None).unwrap(); });
+    time("audio", runs, || { parse_audio::parse(&audio, audio_head, audio_dict).unwrap(); });
+    time("whole game, from disk", runs, || { Game::from_paths(&paths).unwrap(); });
+    let files = parse::Files { exe: Some(&exe), graph: &graph, maps: &maps, audio: None, tables: Default::default(), best_effort: false, keep_chunks: false };
+    time("one map, lazily", runs, || {
+        let game = lazy::LazyGame::new(&files).unwrap();
+        game.render_map(1, &map_renderer::RenderOptions::default()).unwrap();
+    });
+    time("one map, lazily on disk", runs, || {
+        let game = lazy::LazyGame::from_paths(&paths).unwrap();
+        game.render_map(1, &map_renderer::RenderOptions::default()).unwrap();
+    });
+}
+
+fn time(name: &str, runs: usize, mut f: impl FnMut()) {
+    let mut times: Vec<Duration> = (0..runs.max(1)).map(|_| {
+        let start = Instant::now();
+        f();
+        start.elapsed()
+    }).collect();
+    times.sort();
+    println!("{:>24}: {:>8.2}ms", name, times[times.len() / 2].as_secs_f64() * 1000.0);
+}
@@ -1,54 +1,58 @@
-// This is responsible for expanding carmackized data.
+// This is responsible for expanding carmackized data, and carmackizing it again.
 // https://moddingwiki.shikadi.net/wiki/Carmack_compression
 // https://github.com/camoto-project/gamecompjs/blob/master/formats/cmp-carmackize.js
 // https://github.com/camoto-project/gamearchivejs/blob/master/formats/arc-gamemaps-id-carmack.js
 
+use std::collections::HashMap;
 use anyhow::{Result, bail};
 
+const NEAR_POINTER: u8 = 0xA7;
+const FAR_POINTER: u8 = 0xA8;
+
 pub fn expand_with_length_header(compressed: &[u8]) -> Result<Vec<u8>> {
-    let length = (compressed[0] as usize) + ((compressed[1] as usize) << 8);
+    let Some(header) = compressed.get(0..2) else { bail!("Carmackized data is too short for its length header!") };
+    let length = (header[0] as usize) + ((header[1] as usize) << 8);
     let expanded = expand(&compressed[2..])?;
-    if expanded.len() != length { bail!("De-carmackization resulted in an unexpected length!") }
+    if expanded.len() != length { bail!("De-carmackization resulted in {} bytes, but the header says {}!", expanded.len(), length) }
     Ok(expanded)
 }
 
 fn expand(compressed: &[u8]) -> Result<Vec<u8>> {
     let mut out: Vec<u8> = Vec::new();
     let mut bytes = compressed.iter();
-    loop {
+    // Reading stops when reaching EOF with an even number of bytes.
+    while let Some(count) = bytes.next() {
         // Read the uint16.
-        let Some(count) = bytes.next() else {
-            break // Reached EOF with an even number of bytes.
-        };
         let Some(tag) = bytes.next() else {
             out.push(*count); // Reached EOF with an odd number of bytes.
             break
         };
         // Is it a pointer or literal?
-        if *tag == 0xA7 { // Near pointer.
-            let Some(distance) = bytes.next() else { bail!("Distance byte missing after near pointer!") };
+        let position = compressed.len() - bytes.len() - 2;
+        if *tag == NEAR_POINTER {
+            let Some(distance) = bytes.next() else { bail!("Distance byte missing after near pointer at byte {}!", position) };
             if *count == 0 { // Escape.
                 out.push(*distance);
                 out.push(*tag);
             } else {
-                let start = out.len() - (*distance as usize) * 2;
-                let len = (*count as usize) * 2;
-                let end = start + len;
-                out.extend_from_within(start..end);
+                if *distance == 0 { bail!("Near pointer at byte {} goes back 0 words!", position) }
+                let Some(start) = out.len().checked_sub((*distance as usize) * 2) else {
+                    bail!("Near pointer at byte {} goes back {} words, but only {} have been expanded!", position, distance, out.len() / 2)
+                };
+                copy_words(&mut out, start, *count as usize);
             }
-        } else if *tag == 0xA8 { // Far pointer.
+        } else if *tag == FAR_POINTER {
             if *count == 0 { // Escape.
-                let Some(escapee) = bytes.next() else { bail!("Low byte missing after escaped far pointer!") };
+                let Some(escapee) = bytes.next() else { bail!("Low byte missing after escaped far pointer at byte {}!", position) };
                 out.push(*escapee);
                 out.push(*tag);
             } else {
-                let Some(offset_le) = bytes.next() else { bail!("Far pointer offset low byte missing!") };
-                let Some(offset_be) = bytes.next() else { bail!("Far pointer offset high byte missing!") };
+                let Some(offset_le) = bytes.next() else { bail!("Far pointer offset low byte missing at byte {}!", position) };
+                let Some(offset_be) = bytes.next() else { bail!("Far pointer offset high byte missing at byte {}!", position) };
                 let offset = (*offset_le as usize) + ((*offset_be as usize) << 8);
                 let start = offset * 2;
-                let len = (*count as usize) * 2;
-                let end = start + len;
-                out.extend_from_within(start..end);
+                if start >= out.len() { bail!("Far pointer at byte {} points to word {}, but only {} have been expanded!", position, offset, out.len() / 2) }
+                copy_words(&mut out, start, *count as usize);
             }
         } else { // Literal.
             out.push(*count);
@@ -58,6 +62,80 @@ fn expand(compressed: &[u8]) -> Result<Vec<u8>> {
     Ok(out)
 }
 
+// Copies earlier words onto the end, one at a time like the game does, so a copy can run into what it's copying.
+fn copy_words(out: &mut Vec<u8>, start: usize, count: usize) {
+    for i in start .. start + count * 2 {
+        out.push(out[i]);
+    }
+}
+
+// The inverse of expand_with_length_header.
+pub fn compress_with_length_header(data: &[u8]) -> Vec<u8> {
+    let mut out: Vec<u8> = Vec::with_capacity(data.len() + 2);
+    out.extend_from_slice(&(data.len() as u16).to_le_bytes());
+    out.extend(compress(data));
+    out
+}
+
+// Repeats of earlier words become pointers back to them: Near pointers (3 bytes) reach up to 255 words back,
+// far pointers (4 bytes) are from the start. Either copies up to 255 words, and neither overlaps where it's copying to.
+// Words that happen to look like pointers are escaped with a zero count.
+fn compress(data: &[u8]) -> Vec<u8> {
+    const MAX_WORDS: usize = 255;
+    const MAX_CANDIDATES: usize = 1024; // How many earlier places to check per word, so long repetitive maps don't go slow.
+    let words: Vec<u16> = data.chunks_exact(2).map(|c| u16::from_le_bytes([c[0], c[1]])).collect();
+    let mut previous: HashMap<u16, Vec<usize>> = HashMap::new(); // Where each word has appeared so far.
+    let mut out: Vec<u8> = Vec::with_capacity(data.len());
+    let mut i = 0;
+    while i < words.len() {
+        // Find whichever earlier match saves the most bytes.
+        let mut best: Option<(usize, usize, bool)> = None; // Length, start, is near.
+        let mut best_saving: usize = 0;
+        let candidates = previous.get(&words[i]).map(|p| p.as_slice()).unwrap_or(&[]);
+        for start in candidates.iter().rev().take(MAX_CANDIDATES) {
+            let max_len = MAX_WORDS.min(i - start).min(words.len() - i);
+            let len = (0..max_len).take_while(|k| words[start + k] == words[i + k]).count();
+            let is_near = i - start <= MAX_WORDS;
+            if !is_near && *start > 0xffff { continue }
+            let cost = if is_near { 3 } else { 4 };
+            let saving = (len * 2).saturating_sub(cost);
+            if saving > best_saving {
+                best_saving = saving;
+                best = Some((len, *start, is_near));
+            }
+        }
+
+        let len = match best {
+            Some((len, start, true)) => {
+                out.extend_from_slice(&[len as u8, NEAR_POINTER, (i - start) as u8]);
+                len
+            },
+            Some((len, start, false)) => {
+                out.extend_from_slice(&[len as u8, FAR_POINTER]);
+                out.extend_from_slice(&(start as u16).to_le_bytes());
+                len
+            },
+            None => {
+                let [low, high] = words[i].to_le_bytes();
+                if high == NEAR_POINTER || high == FAR_POINTER {
+                    out.extend_from_slice(&[0, high, low]);
+                } else {
+                    out.extend_from_slice(&[low, high]);
+                }
+                1
+            },
+        };
+        for (k, word) in words.iter().enumerate().skip(i).take(len) {
+            previous.entry(*word).or_default().push(k);
+        }
+        i += len;
+    }
+    if data.len() % 2 == 1 {
+        out.push(data[data.len() - 1]); // An odd byte on the end is left as is.
+    }
+    out
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -67,5 +145,23 @@ mod tests {
         let expected: Vec<u8> = vec![0x12, 0xA7, 0xEE, 0xFF, 0x34, 0xA8, 0xCC, 0xDD];
         let output = expand(&input).unwrap();
         assert_eq!(output, expected);
+        assert!(expand(&[1, 0, 2, 0xA7, 2]).is_err()); // Back further than there is.
+        assert!(expand(&[1, 0, 1, 0xA8, 1, 0]).is_err()); // Not expanded yet.
+        assert!(expand(&[1, 0, 1, 0xA7, 0]).is_err()); // Back 0 words.
+    }
+
+    #[test]
+    fn test_compress() {
+        // An escaped word, a literal, then a 2 word near pointer back to both.
+        let input: Vec<u8> = vec![0x12, 0xA7,  0xEE, 0xFF,  0x12, 0xA7,  0xEE, 0xFF];
+        let expected: Vec<u8> = vec![0, 0xA7, 0x12,  0xEE, 0xFF,  2, 0xA7, 2];
+        assert_eq!(compress(&input), expected);
+        // A repeat of the first 3 words, too far back for a near pointer, becomes a 3 word far pointer.
+        let mut input: Vec<u8> = vec![1, 0, 2, 0, 3, 0];
+        input.extend((10..300u16).flat_map(|w| w.to_le_bytes()));
+        input.extend_from_slice(&[1, 0, 2, 0, 3, 0]);
+        let output = compress(&input);
+        assert_eq!(output[output.len() - 4 ..], [3, 0xA8, 0, 0]);
+        assert_eq!(expand(&output).unwrap(), input);
     }
 }
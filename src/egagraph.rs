@@ -1,60 +1,185 @@
-// This is responsible for splitting the EGAGRAPH into decompressed chunks.
+// This is responsible for splitting the EGAGRAPH into decompressed chunks, and packing chunks back into one.
 
 use crate::huffman;
-use anyhow::{Result, bail};
+use crate::source::ByteSource;
+use crate::threads;
+use anyhow::{Context, Result, bail};
+use std::borrow::Cow;
+use std::collections::VecDeque;
 
 // Wraps the data+head+dict together to make a kinda-iterator that returns all the chunks.
-// It's not quite an iterator because it has an extra 'next' function for tiles with a hardcoded length.
-pub struct ChunkIterator<'a> {
-    data: &'a [u8],
+// It's not quite an iterator because it has an extra 'next' function for tiles, which have no length header.
+// Each chunk can be decoded on its own too, with get, as the head says where they all start. Only that chunk is read
+// from the data, so it can be a file that's still on disk (see ByteSource).
+pub struct ChunkIterator<S> {
+    data: S,
     chunk_offsets: Vec<usize>, // aka 'graph_head' without the last value that points past the end of file.
-    dict: Vec<huffman::Node>,
+    dict: huffman::Dict,
     index: usize,
+    read: Option<Vec<Chunk>>, // Everything returned so far, for repacking, if keeping_chunks was called.
+    decoded: VecDeque<(Option<usize>, Result<Vec<u8>>)>, // Chunks decoded ahead of time, with the length they were given.
 }
 
-impl<'a> ChunkIterator<'a> {
-    pub fn new(data: &'a [u8], head: &[u8], dict: &[u8]) -> Result<Self> {
-        let chunk_offsets = parse_graph_head(&head, data.len())?;
-        let dict = huffman::parse_dict(dict);
-        Ok(ChunkIterator { data, chunk_offsets, dict, index: 0 })
+// A decompressed chunk, and whether it's stored with a length header (all but the tiles are).
+pub struct Chunk {
+    pub data: Vec<u8>,
+    pub has_length: bool,
+}
+
+impl<S: ByteSource> ChunkIterator<S> {
+    pub fn new(data: S, head: &[u8], dict: &[u8]) -> Result<Self> {
+        let chunk_offsets = parse_graph_head(head, data.len())?;
+        let dict = huffman::parse_dict(dict)?;
+        Ok(ChunkIterator { data, chunk_offsets, dict, index: 0, read: None, decoded: VecDeque::new() })
+    }
+
+    // Keeps a copy of every chunk returned, for into_chunks. It's off by default as only repacking needs them.
+    pub fn keeping_chunks(mut self) -> Self {
+        self.read = Some(Vec::new());
+        self
     }
 }
 
-impl<'a> ChunkIterator<'a> {
+// Chunks that are marked as empty in the head.
+const EMPTY: usize = 0xffffff;
+
+impl<S: ByteSource> ChunkIterator<S> {
     // This decompresses the usual case where the chunk has a length header.
-    pub fn next(&mut self) -> Vec<u8> {
-        let offset = self.chunk_offsets[self.index];
-        self.index += 1;
-        if offset == 0xffffff { return vec![] } // Empty chunk.
-        let chunk = &self.data[offset..];
-        let len = u32::from_le_bytes(chunk[0..4].try_into().unwrap()) as usize;
-        let compressed = &chunk[4..];
-        huffman::decompress(compressed, &self.dict, len)
-    }
-
-    // This decompresses chunks that have no length header, using all data up until the next chunk.
-    // Since this doesn't know when to stop decoding, it'll maybe have extra byte(s) at the end from huffman decoding excess bits.
-    pub fn next_with_auto_length(&mut self) -> Vec<u8> {
-        let offset = self.chunk_offsets[self.index];
-        self.index += 1;
-        if offset == 0xffffff { return vec![] }
-        // Find the offset of the next chunk.
-        for i in self.index..self.chunk_offsets.len() {
-            let next_offset = self.chunk_offsets[i];
-            if next_offset == 0xffffff { continue }
-            // Size the current chunk to go from its offset to the start of this next chunk.
-            let chunk = &self.data[offset..next_offset];
-            return huffman::decompress(chunk, &self.dict, 999999)
+    // A broken chunk is kept as empty, so the ones after it still line up if the caller carries on.
+    #[allow(clippy::should_implement_trait)] // It's not quite an iterator, see above.
+    pub fn next(&mut self) -> Result<Vec<u8>> {
+        let data = self.decompress_next(None);
+        if let Some(read) = &mut self.read {
+            read.push(Chunk { data: data.as_ref().map(|d| d.clone()).unwrap_or_default(), has_length: true });
+        }
+        data
+    }
+
+    // This decompresses chunks that have no length header (the tiles), whose length the caller knows from what they are.
+    pub fn next_with_length(&mut self, len: usize) -> Result<Vec<u8>> {
+        let data = self.decompress_next(Some(len));
+        if let Some(read) = &mut self.read {
+            read.push(Chunk { data: data.as_ref().map(|d| d.clone()).unwrap_or_default(), has_length: false });
+        }
+        data
+    }
+
+    // Decompresses any chunk that has a length header, without moving on.
+    pub fn get(&self, index: usize) -> Result<Vec<u8>> {
+        self.decompress(index, None)
+    }
+
+    // Decompresses any chunk that has no length header, given its length, without moving on.
+    pub fn get_with_length(&self, index: usize, len: usize) -> Result<Vec<u8>> {
+        self.decompress(index, Some(len))
+    }
+
+    // Decodes the chunks after the ones already read (or decoded ahead) across all the cores, for next and
+    // next_with_length to return in turn. Each is given as None if it has a length header, or else its length.
+    pub fn decode_ahead(&mut self, lengths: &[Option<usize>]) where S: Sync {
+        let first = self.index + self.decoded.len();
+        let chunks: Vec<(usize, Option<usize>)> = lengths.iter().enumerate().map(|(i, len)| (first + i, *len)).collect();
+        let decoded = threads::map(&chunks, |(index, len)| (*len, self.decompress(*index, *len)));
+        self.decoded.extend(decoded);
+    }
+
+    // Uses the chunk decoded ahead if it was decoded the way it's now asked for, otherwise decodes it now.
+    fn decompress_next(&mut self, known_len: Option<usize>) -> Result<Vec<u8>> {
+        let index = self.index;
+        if index < self.chunk_offsets.len() { self.index += 1 }
+        match self.decoded.pop_front() {
+            Some((len, result)) if len == known_len => result,
+            _ => {
+                self.decoded.clear(); // They'd no longer line up.
+                self.decompress(index, known_len)
+            },
+        }
+    }
+
+    fn decompress(&self, index: usize, known_len: Option<usize>) -> Result<Vec<u8>> {
+        let Some(offset) = self.chunk_offsets.get(index).copied() else {
+            bail!("There's no chunk {}, there are only {}!", index, self.chunk_offsets.len())
+        };
+        if offset == EMPTY { return Ok(vec![]) }
+        if offset > self.data.len() { bail!("Chunk {}'s offset {} is past the end of EGAGRAPH, which is {} bytes!", index, offset, self.data.len()) }
+        // The chunk's data goes up to the start of the next one, or the end of the file if it's last.
+        let end = self.next_offset(index).unwrap_or(self.data.len());
+        if end < offset { bail!("Chunk {} at offset {} overlaps the next chunk, at {}!", index, offset, end) }
+        let (len, start) = match known_len {
+            Some(len) => (len, offset),
+            None => {
+                if offset + 4 > self.data.len() {
+                    bail!("Chunk {}'s length at offset {} is past the end of EGAGRAPH, which is {} bytes!", index, offset, self.data.len())
+                }
+                let header = self.data.read_at(offset, 4).with_context(|| format!("Couldn't read chunk {}'s length at offset {}", index, offset))?;
+                (u32::from_le_bytes((*header).try_into().unwrap()) as usize, offset + 4)
+            },
+        };
+        // Empty if the next chunk starts within the header, or claims to be past the end of the file.
+        let compressed = match end.checked_sub(start) {
+            Some(compressed_len) if end <= self.data.len() => self.data.read_at(start, compressed_len)
+                .with_context(|| format!("Couldn't read chunk {} at offset {}", index, offset))?,
+            _ => Cow::Borrowed(&[][..]),
+        };
+        let decompressed = huffman::decompress(&compressed, &self.dict, len);
+        if decompressed.len() != len {
+            bail!("Chunk {} at offset {} only decompressed to {} of its {} bytes!", index, offset, decompressed.len(), len)
         }
-        // Couldn't find the next chunk, maybe this is the end of the file?
-        let chunk = &self.data[offset..];
-        huffman::decompress(chunk, &self.dict, 999999)
+        Ok(decompressed)
     }
 
-    // Rewind one chunk so it can be read again.
-    pub fn rewind_once(&mut self) {
-        self.index -= 1;
+    // Where the next non-empty chunk after this one starts, if there's one.
+    fn next_offset(&self, index: usize) -> Option<usize> {
+        self.chunk_offsets[index + 1..].iter().copied().find(|o| *o != EMPTY)
     }
+
+    pub fn is_finished(&self) -> bool {
+        self.index >= self.chunk_offsets.len()
+    }
+
+    // How many chunks there are, ie NUMCHUNKS.
+    pub fn chunk_count(&self) -> usize {
+        self.chunk_offsets.len()
+    }
+
+    // All the chunks that were read, eg to repack them. Empty unless keeping_chunks was called.
+    pub fn into_chunks(self) -> Vec<Chunk> {
+        self.read.unwrap_or_default()
+    }
+}
+
+// A packed EGAGRAPH, with its EGAHEAD and EGADICT.
+pub struct Packed {
+    pub graph: Vec<u8>,
+    pub head: Vec<u8>,
+    pub dict: Vec<u8>,
+}
+
+// The inverse of ChunkIterator: Makes a dictionary that suits all the chunks, then compresses them one after another.
+// Empty chunks take no space, and have the 0xFFFFFF offset.
+pub fn pack(chunks: &[Chunk]) -> Result<Packed> {
+    let mut counts = [0u32; 256];
+    for value in chunks.iter().flat_map(|c| c.data.iter()) {
+        counts[*value as usize] += 1;
+    }
+    let dict = huffman::build_dict(&counts);
+    let mut graph: Vec<u8> = Vec::new();
+    let mut offsets: Vec<usize> = Vec::with_capacity(chunks.len() + 1);
+    for chunk in chunks {
+        if chunk.data.is_empty() {
+            offsets.push(EMPTY);
+            continue
+        }
+        offsets.push(graph.len());
+        if chunk.has_length {
+            graph.extend_from_slice(&(chunk.data.len() as u32).to_le_bytes());
+        }
+        graph.extend(huffman::compress(&chunk.data, &dict));
+    }
+    offsets.push(graph.len()); // The end of file.
+    if graph.len() >= EMPTY { bail!("The graphics are too big for 3 byte offsets!") }
+    let head: Vec<u8> = offsets.iter().flat_map(|o| o.to_le_bytes()[..3].to_vec()).collect();
+    Ok(Packed { graph, head, dict: huffman::dict_bytes(&dict) })
 }
 
 // https://moddingwiki.shikadi.net/wiki/EGAGraph_Format
@@ -62,7 +187,7 @@ impl<'a> ChunkIterator<'a> {
 // This doesn't return the last one, as it is only used for validation and isn't the start of a chunk.
 // This also validates it.
 fn parse_graph_head(data: &[u8], graph_data_len: usize) -> Result<Vec<usize>> {
-    if data.len()%3 != 0 { bail!("Graph head isn't an even multiple of 3 bytes.") }
+    if data.is_empty() || !data.len().is_multiple_of(3) { bail!("Graph head isn't an even multiple of 3 bytes.") }
     let mut values: Vec<usize> = data
         .chunks_exact(3)
         .map(|c| (c[0] as usize) + ((c[1] as usize) << 8) + ((c[2] as usize) << 16))
@@ -72,3 +197,41 @@ fn parse_graph_head(data: &[u8], graph_data_len: usize) -> Result<Vec<usize>> {
     values.pop(); // Remove the final one which points to the end of file.
     Ok(values)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_pack() {
+        let chunks = vec![
+            Chunk { data: b"header".to_vec(), has_length: true },
+            Chunk { data: vec![], has_length: true },
+            Chunk { data: vec![7; 128], has_length: false },
+            Chunk { data: b"the end".to_vec(), has_length: true },
+        ];
+        let packed = pack(&chunks).unwrap();
+        let mut iterator = ChunkIterator::new(packed.graph.as_slice(), &packed.head, &packed.dict).unwrap();
+        assert_eq!(iterator.next().unwrap(), b"header");
+        assert_eq!(iterator.next().unwrap(), b"");
+        assert_eq!(iterator.next_with_length(128).unwrap(), vec![7; 128]);
+        assert_eq!(iterator.next().unwrap(), b"the end");
+        assert!(iterator.is_finished());
+        assert!(iterator.next().is_err());
+
+        assert!(iterator.into_chunks().is_empty()); // They weren't kept.
+
+        // Any chunk can be read on its own, or all of them decoded ahead.
+        let mut iterator = ChunkIterator::new(packed.graph.as_slice(), &packed.head, &packed.dict).unwrap().keeping_chunks();
+        assert_eq!(iterator.get(3).unwrap(), b"the end");
+        assert_eq!(iterator.get_with_length(2, 128).unwrap(), vec![7; 128]);
+        iterator.decode_ahead(&[None, None, Some(128), None]);
+        assert_eq!(iterator.next().unwrap(), b"header");
+        assert_eq!(iterator.next().unwrap(), b"");
+        assert_eq!(iterator.next_with_length(128).unwrap(), vec![7; 128]);
+        assert_eq!(iterator.next().unwrap(), b"the end");
+        let kept = iterator.into_chunks();
+        assert_eq!(kept.len(), 4);
+        assert!(kept[2].data == vec![7; 128] && !kept[2].has_length);
+    }
+}
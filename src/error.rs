@@ -0,0 +1,36 @@
+// This is the error type for the library's API, so callers can tell what went wrong without parsing messages.
+
+use std::fmt;
+
+#[derive(Debug)]
+pub enum Error {
+    Io { path: String, error: std::io::Error }, // A file couldn't be read or written.
+    NotFound(String), // A game folder was missing a file it needs.
+    Exe(String), // The exe couldn't be unpacked.
+    NeedsExe, // Some tables weren't given as files, so the exe is needed to find them in.
+    UnknownExe(String), // The exe's version couldn't be recognised, nor its tables found.
+    NoAudioTables, // Audio was given without its tables, and they couldn't be found in the exe.
+    Graphics(String), // EGAGRAPH, EGAHEAD or EGADICT couldn't be parsed.
+    Maps(String), // GAMEMAPS or MAPHEAD couldn't be parsed.
+    Audio(String), // AUDIO, AUDIOHED or AUDIODICT couldn't be parsed.
+    Export(String), // Writing the assets out failed.
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::Io { path, error } => write!(f, "Couldn't access {}: {}", path, error),
+            Error::NotFound(message) => write!(f, "{}", message),
+            Error::Exe(message) => write!(f, "Couldn't unpack the exe: {}", message),
+            Error::NeedsExe => write!(f, "An exe is needed for the tables that weren't given as files!"),
+            Error::UnknownExe(message) => write!(f, "{}", message),
+            Error::NoAudioTables => write!(f, "Couldn't find the audio tables in the exe, try giving them as files!"),
+            Error::Graphics(message) => write!(f, "Couldn't parse the graphics: {}", message),
+            Error::Maps(message) => write!(f, "Couldn't parse the maps: {}", message),
+            Error::Audio(message) => write!(f, "Couldn't parse the audio: {}", message),
+            Error::Export(message) => write!(f, "Couldn't export: {}", message),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
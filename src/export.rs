@@ -1,46 +1,382 @@
 // This is responsible for exporting the assets to eg pngs.
 
+use crate::egagraph;
+use crate::Game;
+use crate::parse_audio;
 use crate::parse_graphics;
 use crate::parse_maps;
+use crate::png;
 use crate::images;
-use anyhow::Result;
+use crate::map_renderer;
+use crate::opl;
+use crate::sound_renderer;
+use crate::sprite_groups;
+use crate::threads;
+use crate::tile_info;
+use crate::tiled;
+use crate::wav;
+use crate::versions::Episode;
+use anyhow::{Result, bail};
+use std::ops::RangeInclusive;
+use std::path::{Path, PathBuf};
 
-pub fn export(graphics: &parse_graphics::Graphics, maps: &[parse_maps::Map]) -> Result<()> {
-    println!("Exporting assets...");
+// The kinds of asset that can be exported.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Kind {
+    Fonts,
+    Pictures,
+    Sprites,
+    Tiles, // 8x8 and 16x16.
+    Misc, // Text screens, articles, the terminator text and demos.
+    Maps,
+    Audio,
+}
+
+impl Kind {
+    pub const ALL: [Kind; 7] = [Kind::Fonts, Kind::Pictures, Kind::Sprites, Kind::Tiles, Kind::Misc, Kind::Maps, Kind::Audio];
+    pub const GRAPHICS: [Kind; 5] = [Kind::Fonts, Kind::Pictures, Kind::Sprites, Kind::Tiles, Kind::Misc];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Kind::Fonts => "fonts",
+            Kind::Pictures => "pictures",
+            Kind::Sprites => "sprites",
+            Kind::Tiles => "tiles",
+            Kind::Misc => "misc",
+            Kind::Maps => "maps",
+            Kind::Audio => "audio",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Kind> {
+        Kind::ALL.into_iter().find(|k| k.name() == name)
+    }
+}
+
+// Optional extras for the export.
+#[derive(Clone, Default)]
+pub struct Options {
+    pub sprite_debug: bool, // Also write each sprite with its hit box and origin drawn over it.
+    pub info_plane: bool, // Also write each map with its sprite/info plane drawn over it.
+    pub png_level: png::Level, // How hard to compress the pngs.
+    pub repack: bool, // Also write the graphics back out as a new EGAGRAPH, EGAHEAD and EGADICT.
+    pub out_dir: Option<PathBuf>, // Where to write everything, the current folder if None. It's made if need be.
+    pub kinds: Option<Vec<Kind>>, // Which kinds of asset to write, all of them if None.
+    pub range: Option<RangeInclusive<usize>>, // Only write assets whose index (within their kind) is in this range.
+    pub progress: Option<fn(&Path)>, // Called after each file is written.
+    pub map_region: Option<map_renderer::Region>, // Only render this part of each map.
+    pub animate: bool, // Also write each map as an animated png, with its water, fire, lights etc moving.
+    pub animation_frames: bool, // Also write each frame of the maps' animations as its own png, with their timings.
+    pub tiled: bool, // Also write the tiles as Tiled tilesets, and each map as a Tiled map using them.
+}
+
+impl Options {
+    fn wants_kind(&self, kind: Kind) -> bool {
+        self.kinds.as_ref().is_none_or(|kinds| kinds.contains(&kind))
+    }
+
+    fn wants_index(&self, index: usize) -> bool {
+        self.range.as_ref().is_none_or(|range| range.contains(&index))
+    }
+
+    fn write(&self, name: &str, data: impl AsRef<[u8]>) -> Result<()> {
+        let path = match &self.out_dir {
+            Some(dir) => dir.join(name),
+            None => PathBuf::from(name),
+        };
+        std::fs::write(&path, data)?;
+        if let Some(progress) = self.progress {
+            progress(&path);
+        }
+        Ok(())
+    }
+}
+
+pub fn export(game: &Game, options: &Options) -> Result<()> {
+    let (graphics, maps, tile_info, audio, episode) = (&game.graphics, &game.maps, game.tile_info.as_ref(), game.audio.as_ref(), game.episode());
+    if let Some(dir) = &options.out_dir {
+        std::fs::create_dir_all(dir)?;
+    }
+    if options.wants_kind(Kind::Fonts) {
+        export_fonts(&graphics.fonts, options)?;
+    }
+    if options.wants_kind(Kind::Pictures) {
+        export_optionals(&graphics.pictures_unmasked, "OutputPictureUnmasked", options)?;
+        export_optionals(&graphics.pictures_masked, "OutputPictureMasked", options)?;
+    }
+    if options.wants_kind(Kind::Sprites) {
+        export_sprites(&graphics.sprites, episode, options)?;
+    }
+    if options.wants_kind(Kind::Tiles) {
+        export_images(&graphics.tiles_8_unmasked, "OutputTile8Unmasked", options)?;
+        export_images(&graphics.tiles_8_masked, "OutputTile8Masked", options)?;
+        export_optionals(&graphics.tiles_16_unmasked, "OutputTile16Unmasked", options)?;
+        export_optionals(&graphics.tiles_16_masked, "OutputTile16Masked", options)?;
+        if let Some(tile_info) = tile_info {
+            options.write("OutputTileInfo.json", tile_info.json())?;
+            options.write("OutputTileInfoBackground.csv", tile_info.background_csv())?;
+            options.write("OutputTileInfoForeground.csv", tile_info.foreground_csv())?;
+        }
+    }
+    if options.wants_kind(Kind::Misc) {
+        export_misc(graphics, options)?;
+    }
+    if options.repack {
+        export_repacked(game, options)?;
+    }
+    if let Some(audio) = audio && options.wants_kind(Kind::Audio) {
+        export_audio(audio, options)?;
+    }
+
+    if options.wants_kind(Kind::Maps) {
+        export_maps(graphics, maps, tile_info, episode, options)?;
+    }
+
+    Ok(())
+}
 
-    export_optionals(&graphics.pictures_unmasked, "OutputPictureUnmasked")?;
-    export_optionals(&graphics.pictures_masked, "OutputPictureMasked")?;
-    export_optionals(&graphics.sprites, "OutputSprite")?;
-    export_images(&graphics.tiles_8_unmasked, "OutputTile8Unmasked")?;
-    export_images(&graphics.tiles_8_masked, "OutputTile8Masked")?;
-    export_optionals(&graphics.tiles_16_unmasked, "OutputTile16Unmasked")?;
-    export_optionals(&graphics.tiles_16_masked, "OutputTile16Masked")?;
+fn export_maps(graphics: &parse_graphics::Graphics, maps: &[parse_maps::Map], tile_info: Option<&tile_info::TileInfo>, episode: Option<Episode>, options: &Options) -> Result<()> {
+    let animates = options.animate || options.animation_frames;
+    if animates && tile_info.is_none() { bail!("Animating the maps needs the tile info, which wasn't found!") }
+    let level = options.png_level;
+    if options.tiled {
+        threads::map(&tiled::tilesets(graphics, tile_info), |tileset| {
+            options.write(&format!("{}.png", tileset.name), tileset.image.png(level))?;
+            options.write(&format!("{}.tsx", tileset.name), &tileset.tsx)
+        }).into_iter().collect::<Result<()>>()?;
+    }
+    let render_options = map_renderer::RenderOptions { region: options.map_region, ..Default::default() };
+    let info_options = map_renderer::RenderOptions { info_plane: true, episode, region: options.map_region };
+    // Maps are named by their number in the map head, which can skip some.
+    let wanted: Vec<(usize, &parse_maps::Map)> = maps.iter().map(|map| (map.index, map)).filter(|(index, _)| options.wants_index(*index)).collect();
+
+    // Each map is rendered and encoded on its own, so they're spread across the cores.
+    threads::map(&wanted, |(index, map)| {
+        if options.tiled {
+            options.write(&format!("OutputMap{} - {}.tmx", index, map.name), tiled::tmx(map, graphics))?;
+        }
+        let image = map_renderer::render(map, graphics, &render_options);
+        options.write(&format!("OutputMap{} - {}.png", index, map.name), image.png(level))?;
+        if options.info_plane {
+            let image = map_renderer::render(map, graphics, &info_options);
+            options.write(&format!("OutputMapInfo{} - {}.png", index, map.name), image.png(level))?;
+        }
+        Ok(())
+    }).into_iter().collect::<Result<()>>()?;
 
-    for (index, map) in maps.iter().enumerate() {
-        let image = crate::map_renderer::render(&map, &graphics);
-        let png = image.png();
-        let path = format!("OutputMap{} - {}.png", index, map.name);
-        std::fs::write(path, &png)?;
+    // Whereas a map's animation can be hundreds of big frames, so they're done one map at a time to keep the memory down,
+    // with the frames spread across the cores instead.
+    let Some(tile_info) = tile_info else { return Ok(()) };
+    if !animates { return Ok(()) }
+    for (index, map) in wanted {
+        // Actors don't move in the animation, so they're only drawn over it if asked for.
+        let render_options = if options.info_plane { &info_options } else { &render_options };
+        let frames = map_renderer::render_animated(map, graphics, tile_info, render_options);
+        if options.animate {
+            options.write(&format!("OutputMapAnimated{} - {}.png", index, map.name), images::apng(&frames, level))?;
+        }
+        if options.animation_frames {
+            let numbered: Vec<(usize, &images::Image)> = frames.iter().map(|(image, _)| image).enumerate().collect();
+            write_pngs(&numbered, &format!("OutputMapFrame{}-", index), &format!(" - {}", map.name), options)?;
+            let mut timings = String::from("frame,tics\n");
+            for (frame_index, (_, tics)) in frames.iter().enumerate() {
+                timings += &format!("{},{}\n", frame_index, tics);
+            }
+            options.write(&format!("OutputMapFrames{} - {}.csv", index, map.name), timings)?;
+        }
     }
+    Ok(())
+}
+
+fn export_images(images: &[images::Image], prefix: &str, options: &Options) -> Result<()> {
+    let wanted: Vec<(usize, &images::Image)> = images.iter().enumerate().filter(|(index, _)| options.wants_index(*index)).collect();
+    write_pngs(&wanted, prefix, "", options)
+}
+
+fn export_optionals(images: &[Option<images::Image>], prefix: &str, options: &Options) -> Result<()> {
+    let wanted: Vec<(usize, &images::Image)> = images.iter().enumerate()
+        .filter_map(|(index, image)| Some((index, image.as_ref()?)))
+        .filter(|(index, _)| options.wants_index(*index))
+        .collect();
+    write_pngs(&wanted, prefix, "", options)
+}
 
+// Encodes the images across all the cores, as that's most of the time spent exporting, naming each by its index between
+// the prefix and suffix.
+fn write_pngs(images: &[(usize, &images::Image)], prefix: &str, suffix: &str, options: &Options) -> Result<()> {
+    threads::map(images, |(index, image)| options.write(&format!("{}{}{}.png", prefix, index, suffix), image.png(options.png_level))).into_iter().collect()
+}
+
+// Each font is written as an atlas of a 16x16 grid of characters, plus a csv of where each character is and how wide.
+fn export_fonts(fonts: &[Option<parse_graphics::Font>], options: &Options) -> Result<()> {
+    const GRID: usize = 16;
+    for (index, font) in fonts.iter().enumerate() {
+        let Some(font) = font else { continue };
+        if !options.wants_index(index) { continue }
+        let cell_width = font.widths.iter().max().copied().unwrap_or(0).max(1);
+        let mut atlas = images::Image::empty(cell_width * GRID, font.height * GRID);
+        let mut metrics = String::from("character,x,y,width,height\n");
+        for (character, (glyph, width)) in font.glyphs.iter().zip(&font.widths).enumerate() {
+            let x = (character % GRID) * cell_width;
+            let y = (character / GRID) * font.height;
+            if let Some(glyph) = glyph {
+                map_renderer::draw(glyph, &mut atlas, x, y);
+            }
+            metrics += &format!("{},{},{},{},{}\n", character, x, y, width, font.height);
+        }
+        options.write(&format!("OutputFont{}.png", index), atlas.png(options.png_level))?;
+        options.write(&format!("OutputFont{}.csv", index), metrics)?;
+    }
+    Ok(())
+}
+
+// Text screens are written with ANSI colours, articles as plain text, and demos as their raw input stream plus csvs of the decoded inputs and which map they're on.
+fn export_misc(graphics: &parse_graphics::Graphics, options: &Options) -> Result<()> {
+    for (index, screen) in graphics.text_screens.iter().enumerate() {
+        if !options.wants_index(index) { continue }
+        options.write(&format!("OutputScreen{}.ans", index), screen.ansi())?;
+    }
+    for (index, article) in graphics.articles.iter().enumerate() {
+        if !options.wants_index(index) { continue }
+        options.write(&format!("OutputArticle{}.txt", index), article)?;
+    }
+    export_images(&graphics.terminator_texts, "OutputTerminator", options)?;
+    let mut demos = String::from("demo,map,inputs,tics\n");
+    for (index, demo) in graphics.demos.iter().enumerate() {
+        if !options.wants_index(index) { continue }
+        let raw: Vec<u8> = demo.inputs.iter().flat_map(|i| [i.tics, i.input]).collect();
+        options.write(&format!("OutputDemo{}.bin", index), raw)?;
+        let mut inputs = String::from("tics,x,y,buttons\n");
+        for input in demo.inputs.iter() {
+            inputs += &format!("{},{},{},{}\n", input.tics, input.x(), input.y(), input.buttons());
+        }
+        options.write(&format!("OutputDemo{}.csv", index), inputs)?;
+        let tics: usize = demo.inputs.iter().map(|i| i.tics as usize).sum();
+        demos += &format!("{},{},{},{}\n", index, demo.map, demo.inputs.len(), tics);
+    }
+    options.write("OutputDemos.csv", demos)?;
     Ok(())
 }
 
-fn export_images(images: &[images::Image], prefix: &str) -> Result<()> {
-    for (index, image) in images.iter().enumerate() {
-        let png = image.png();
-        let path = format!("{}{}.png", prefix, index);
-        std::fs::write(path, &png)?;
+// Compresses the graphics chunks again with a fresh dictionary, and the maps with a fresh TED5 style GAMEMAPS,
+// checking they both decompress to the same thing.
+fn export_repacked(game: &Game, options: &Options) -> Result<()> {
+    let (graphics, maps) = (&game.graphics, &game.maps);
+    if graphics.chunks.is_empty() { bail!("The graphics chunks weren't kept, so they can't be repacked! Load the game with keep_chunks set.") }
+    let packed = egagraph::pack(&graphics.chunks)?;
+    let mut check = egagraph::ChunkIterator::new(packed.graph.as_slice(), &packed.head, &packed.dict)?;
+    for (index, chunk) in graphics.chunks.iter().enumerate() {
+        let data = if chunk.has_length { check.next()? } else { check.next_with_length(chunk.data.len())? };
+        if data != chunk.data { bail!("Repacked graphics chunk {} doesn't decompress to the original!", index) }
     }
+    let packed_maps = parse_maps::pack(maps, game.rlew_key, &game.tile_info_data)?;
+    if parse_maps::parse(&packed_maps.gamemaps.as_slice(), &packed_maps.map_head, None)? != *maps { bail!("The repacked maps don't decompress to the originals!") }
+
+    let extension = game.episode().map(|e| e.extension()).unwrap_or("CKx");
+    options.write(&format!("OutputEGAGRAPH.{}", extension), &packed.graph)?;
+    options.write(&format!("OutputEGAHEAD.{}", extension), &packed.head)?;
+    options.write(&format!("OutputEGADICT.{}", extension), &packed.dict)?;
+    options.write(&format!("OutputGAMEMAPS.{}", extension), &packed_maps.gamemaps)?;
+    options.write(&format!("OutputMAPHEAD.{}", extension), &packed_maps.map_head)?;
     Ok(())
 }
 
-fn export_optionals(images: &[Option<images::Image>], prefix: &str) -> Result<()> {
-    for (index, image) in images.iter().enumerate() {
-        let Some(image) = image else { continue };
-        let png = image.png();
-        let path = format!("{}{}.png", prefix, index);
-        std::fs::write(path, &png)?;
+// Sounds are rendered to wavs plus a csv of their lengths and priorities, and music is written both as the original imf and rendered to wav.
+fn export_audio(audio: &parse_audio::Audio, options: &Options) -> Result<()> {
+    for (index, sound) in audio.pc_sounds.iter().enumerate() {
+        let Some(sound) = sound else { continue };
+        if !options.wants_index(index) { continue }
+        let samples = sound_renderer::render_pc_sound(sound);
+        options.write(&format!("OutputSoundPc{}.wav", index), wav::wav(&samples, opl::SAMPLE_RATE))?;
+    }
+    for (index, sound) in audio.adlib_sounds.iter().enumerate() {
+        let Some(sound) = sound else { continue };
+        if !options.wants_index(index) { continue }
+        let samples = sound_renderer::render_adlib_sound(sound);
+        options.write(&format!("OutputSoundAdlib{}.wav", index), wav::wav(&samples, opl::SAMPLE_RATE))?;
+    }
+    let mut metrics = String::from("sound,pc_ticks,pc_priority,adlib_ticks,adlib_priority\n");
+    for (index, (pc, adlib)) in audio.pc_sounds.iter().zip(&audio.adlib_sounds).enumerate() {
+        if !options.wants_index(index) { continue }
+        let pc = pc.as_ref().map(|s| (s.data.len().to_string(), s.priority.to_string())).unwrap_or_default();
+        let adlib = adlib.as_ref().map(|s| (s.data.len().to_string(), s.priority.to_string())).unwrap_or_default();
+        metrics += &format!("{},{},{},{},{}\n", index, pc.0, pc.1, adlib.0, adlib.1);
+    }
+    options.write("OutputSounds.csv", metrics)?;
+    for (index, music) in audio.music.iter().enumerate() {
+        let Some(music) = music else { continue };
+        if !options.wants_index(index) { continue }
+        options.write(&format!("OutputMusic{}.imf", index), &music.imf)?;
+        let samples = sound_renderer::render_music(music);
+        options.write(&format!("OutputMusic{}.wav", index), wav::wav(&samples, opl::SAMPLE_RATE))?;
     }
     Ok(())
 }
+
+// Sprites are written as pngs, plus a csv of their origins, hit boxes and shifts.
+fn export_sprites(sprites: &[Option<parse_graphics::Sprite>], episode: Option<Episode>, options: &Options) -> Result<()> {
+    let mut metrics = String::from("sprite,width,height,origin_x,origin_y,clip_left,clip_top,clip_right,clip_bottom,shifts\n");
+    let wanted: Vec<(usize, &parse_graphics::Sprite)> = sprites.iter().enumerate()
+        .filter_map(|(index, sprite)| Some((index, sprite.as_ref()?)))
+        .filter(|(index, _)| options.wants_index(*index))
+        .collect();
+    let images: Vec<(usize, &images::Image)> = wanted.iter().map(|(index, sprite)| (*index, &sprite.image)).collect();
+    write_pngs(&images, "OutputSprite", "", options)?;
+    if options.sprite_debug {
+        threads::map(&wanted, |(index, sprite)| {
+            let debug = map_renderer::render_sprite_debug(sprite);
+            options.write(&format!("OutputSpriteDebug{}.png", index), debug.png(options.png_level))
+        }).into_iter().collect::<Result<()>>()?;
+    }
+    for (index, sprite) in wanted {
+        metrics += &format!("{},{},{},{},{},{},{},{},{},{}\n",
+            index, sprite.image.width, sprite.image.height, sprite.origin_x, sprite.origin_y,
+            sprite.clip_left, sprite.clip_top, sprite.clip_right, sprite.clip_bottom, sprite.shifts);
+    }
+    options.write("OutputSprites.csv", metrics)?;
+    let groups = episode.map(sprite_groups::groups).unwrap_or_default();
+    let wanted_groups: Vec<&sprite_groups::SpriteGroup> = groups.iter().filter(|group| options.wants_index(*group.sprites.start())).collect();
+    threads::map(&wanted_groups, |group| {
+        let frames: Vec<&parse_graphics::Sprite> = group.sprites.clone().filter_map(|i| sprites.get(i).and_then(|s| s.as_ref())).collect();
+        if frames.is_empty() { return Ok(()) }
+        options.write(&format!("OutputSpriteGroup - {}.png", group.name), images::apng(&align_sprites(&frames, group.tics), options.png_level))
+    }).into_iter().collect::<Result<()>>()?;
+    export_sprite_sheet(sprites, groups, options)
+}
+
+// Places each frame by its origin, so they line up the way the game draws them, on a canvas big enough for them all.
+fn align_sprites(frames: &[&parse_graphics::Sprite], tics: u32) -> Vec<(images::Image, u32)> {
+    let left = frames.iter().map(|s| s.origin_x).min().unwrap_or(0);
+    let top = frames.iter().map(|s| s.origin_y).min().unwrap_or(0);
+    let right = frames.iter().map(|s| s.origin_x + s.image.width as i32).max().unwrap_or(0);
+    let bottom = frames.iter().map(|s| s.origin_y + s.image.height as i32).max().unwrap_or(0);
+    frames.iter().map(|sprite| {
+        let mut image = images::Image::empty((right - left) as usize, (bottom - top) as usize);
+        map_renderer::draw(&sprite.image, &mut image, (sprite.origin_x - left) as usize, (sprite.origin_y - top) as usize);
+        (image, tics)
+    }).collect()
+}
+
+// Every sprite packed into one png, with a JSON atlas of where each is, their origins and hit boxes, and the groups.
+fn export_sprite_sheet(sprites: &[Option<parse_graphics::Sprite>], groups: &[sprite_groups::SpriteGroup], options: &Options) -> Result<()> {
+    const SHEET_WIDTH: usize = 1024;
+    let included: Vec<(usize, &parse_graphics::Sprite)> = sprites.iter().enumerate()
+        .filter_map(|(index, sprite)| Some((index, sprite.as_ref()?)))
+        .filter(|(index, _)| options.wants_index(*index))
+        .collect();
+    let images: Vec<&images::Image> = included.iter().map(|(_, sprite)| &sprite.image).collect();
+    let (sheet, positions) = images::pack(&images, SHEET_WIDTH);
+    options.write("OutputSpriteSheet.png", sheet.png(options.png_level))?;
+
+    let entries: Vec<String> = included.iter().zip(&positions).map(|((index, sprite), (x, y))| {
+        format!("    {{\"sprite\": {}, \"x\": {}, \"y\": {}, \"width\": {}, \"height\": {}, \"origin_x\": {}, \"origin_y\": {}, \"hit_box\": {{\"left\": {}, \"top\": {}, \"right\": {}, \"bottom\": {}}}}}",
+            index, x, y, sprite.image.width, sprite.image.height, sprite.origin_x, sprite.origin_y,
+            sprite.clip_left, sprite.clip_top, sprite.clip_right, sprite.clip_bottom)
+    }).collect();
+    let group_entries: Vec<String> = groups.iter().map(|group| {
+        let frames: Vec<String> = group.sprites.clone().map(|i| i.to_string()).collect();
+        format!("    {{\"name\": \"{}\", \"sprites\": [{}], \"tics\": {}}}", group.name, frames.join(", "), group.tics)
+    }).collect();
+    let json = format!("{{\n  \"image\": \"OutputSpriteSheet.png\",\n  \"sprites\": [\n{}\n  ],\n  \"groups\": [\n{}\n  ]\n}}\n", entries.join(",\n"), group_entries.join(",\n"));
+    options.write("OutputSpriteSheet.json", json)
+}
@@ -1,7 +1,10 @@
-// This is responsible for huffman decoding.
+// This is responsible for huffman decoding, and encoding for repacking.
 // https://moddingwiki.shikadi.net/wiki/Huffman_Compression
 
+use std::cmp::Reverse;
+use std::collections::BinaryHeap;
 use crate::bitstream;
+use anyhow::{Result, bail};
 
 pub struct Node {
     left: u8,
@@ -10,28 +13,286 @@ pub struct Node {
     right_is_leaf: bool,
 }
 
-pub fn parse_dict(data: &[u8]) -> Vec<Node> {
-    data.chunks(4).map(|c|
+// A parsed dictionary, along with a table for decoding several bits at a time.
+pub struct Dict {
+    nodes: Vec<Node>,
+    table: Vec<Entry>,
+}
+
+// What the next LOOKUP_BITS bits decode to: A value and how many of the bits its code used,
+// or for codes longer than that, the node they've got to (with bits as 0).
+#[derive(Clone, Copy)]
+struct Entry {
+    value: u8,
+    bits: u8,
+}
+
+const LOOKUP_BITS: u32 = 10; // A 1024 entry table, which covers nearly all of the games' codes.
+const START_NODE: usize = 254; // The root.
+
+// Checks every node points at another within the dictionary, so decoding can't run off the end of it.
+pub fn parse_dict(data: &[u8]) -> Result<Dict> {
+    if data.len() < NODE_COUNT * 4 { bail!("The huffman dictionary is {} bytes, it should be {}!", data.len(), NODE_COUNT * 4) }
+    let nodes: Vec<Node> = data.chunks_exact(4).take(NODE_COUNT).map(|c|
         Node { left: c[0], left_is_leaf: c[1]==0, right: c[2], right_is_leaf: c[3]==0 }
-    ).collect()
+    ).collect();
+    for (index, node) in nodes.iter().enumerate() {
+        for (value, is_leaf) in [(node.left, node.left_is_leaf), (node.right, node.right_is_leaf)] {
+            if !is_leaf && value as usize >= NODE_COUNT { bail!("Huffman node {} points to node {}, past the end of the dictionary!", index, value) }
+        }
+    }
+    Ok(Dict::new(nodes))
+}
+
+impl Dict {
+    // Walks the tree for every combination of the next LOOKUP_BITS bits, first bit lowest as they're read.
+    fn new(nodes: Vec<Node>) -> Self {
+        let table = (0..1usize << LOOKUP_BITS).map(|bits| {
+            let mut node_index = START_NODE;
+            for bit_index in 0..LOOKUP_BITS {
+                let (value, is_leaf) = nodes[node_index].child(bits & (1 << bit_index) != 0);
+                if is_leaf { return Entry { value, bits: bit_index as u8 + 1 } }
+                node_index = value as usize;
+            }
+            Entry { value: node_index as u8, bits: 0 }
+        }).collect();
+        Dict { nodes, table }
+    }
+}
+
+impl Node {
+    // The value or next node for a bit, and whether it's a value.
+    fn child(&self, bit: bool) -> (u8, bool) {
+        if bit { (self.right, self.right_is_leaf) } else { (self.left, self.left_is_leaf) }
+    }
 }
 
-pub fn decompress(data: &[u8], dict: &[Node], desired_length: usize) -> Vec<u8> {
-    let mut output: Vec<u8> = Vec::new();
-    const START_NODE: usize = 254;
+// Decodes up to the desired length, or until the data runs out (as the headerless chunks' is unknown).
+// Bits are read lowest first into a buffer, so each value usually takes one lookup in the table, and codes longer than
+// the table carry on down the tree a bit at a time.
+pub fn decompress(data: &[u8], dict: &Dict, desired_length: usize) -> Vec<u8> {
+    let mut output: Vec<u8> = Vec::with_capacity(desired_length.min(data.len() * 8));
+    if desired_length == 0 { return output }
+    let mut bytes = data.iter();
+    let mut buffer: u64 = 0;
+    let mut count: u32 = 0; // How many bits are in the buffer.
+    let mut refill = |buffer: &mut u64, count: &mut u32| {
+        while *count <= 56 {
+            let Some(byte) = bytes.next() else { break };
+            *buffer |= (*byte as u64) << *count;
+            *count += 8;
+        }
+    };
+    loop {
+        refill(&mut buffer, &mut count);
+        let entry = dict.table[(buffer & ((1 << LOOKUP_BITS) - 1)) as usize];
+        if entry.bits != 0 {
+            if entry.bits as u32 > count { break } // The data ran out partway through a code.
+            output.push(entry.value);
+            buffer >>= entry.bits;
+            count -= entry.bits as u32;
+        } else {
+            if count < LOOKUP_BITS { break }
+            buffer >>= LOOKUP_BITS;
+            count -= LOOKUP_BITS;
+            let mut node_index = entry.value as usize;
+            loop {
+                if count == 0 {
+                    refill(&mut buffer, &mut count);
+                    if count == 0 { return output }
+                }
+                let (value, is_leaf) = dict.nodes[node_index].child(buffer & 1 != 0);
+                buffer >>= 1;
+                count -= 1;
+                if is_leaf {
+                    output.push(value);
+                    break
+                }
+                node_index = value as usize;
+            }
+        }
+        if output.len() == desired_length { break } // Decoded enough; skip the leftover bits.
+    }
+    output
+}
+
+// Counts how many bits it takes to decode the desired length, or None if the data runs out first.
+// Useful for checking a dictionary matches some data, as the wrong one won't line up with the chunk boundaries.
+pub fn bits_needed(data: &[u8], dict: &Dict, desired_length: usize) -> Option<usize> {
+    if desired_length == 0 { return Some(0) }
     let mut node_index = START_NODE;
+    let mut decoded: usize = 0;
     let stream = bitstream::BitStream::new(data);
-    for bit in stream {
-        let node = &dict[node_index];
-        let (value, is_leaf) = if bit { (node.right, node.right_is_leaf) }
-                                           else { (node.left,  node.left_is_leaf) };
+    for (bit_index, bit) in stream.enumerate() {
+        let (value, is_leaf) = dict.nodes[node_index].child(bit);
         if is_leaf {
-            output.push(value);
-            if output.len() == desired_length { return output } // Decoded enough; skip the leftover bits.
+            decoded += 1;
+            if decoded == desired_length { return Some(bit_index + 1) }
             node_index = START_NODE;
         } else {
             node_index = value as usize;
         }
     }
+    None
+}
+
+// The number of nodes in a dictionary: A full tree of all 256 byte values.
+const NODE_COUNT: usize = 255;
+
+// Builds a dictionary from how often each byte value appears, by repeatedly joining the two least common subtrees.
+// Every value gets a code even if it doesn't appear, as the dictionary is a fixed size.
+// Nodes are numbered in the order they're made, so the last one (254) is the root, as the decoder expects.
+pub fn build_dict(counts: &[u32; 256]) -> Vec<Node> {
+    #[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+    enum Subtree { Leaf(u8), Node(u8) }
+    let mut heap: BinaryHeap<Reverse<(u64, usize, Subtree)>> = BinaryHeap::new(); // Count, then order added to break ties.
+    for (value, count) in counts.iter().enumerate() {
+        heap.push(Reverse((*count as u64, value, Subtree::Leaf(value as u8))));
+    }
+    let mut dict: Vec<Node> = Vec::with_capacity(NODE_COUNT);
+    while let (Some(Reverse((left_count, _, left))), Some(Reverse((right_count, _, right)))) = (heap.pop(), heap.pop()) {
+        let (left, left_is_leaf) = match left { Subtree::Leaf(v) => (v, true), Subtree::Node(n) => (n, false) };
+        let (right, right_is_leaf) = match right { Subtree::Leaf(v) => (v, true), Subtree::Node(n) => (n, false) };
+        heap.push(Reverse((left_count + right_count, 256 + dict.len(), Subtree::Node(dict.len() as u8))));
+        dict.push(Node { left, left_is_leaf, right, right_is_leaf });
+    }
+    dict
+}
+
+// The inverse of parse_dict: Each node is 2 little-endian u16s, values under 256 are leaves, otherwise 256 + a node index.
+// It's padded with an unused 256th node, to the 1024 bytes the games store.
+pub fn dict_bytes(dict: &[Node]) -> Vec<u8> {
+    let mut bytes: Vec<u8> = dict.iter().flat_map(|n|
+        [n.left, if n.left_is_leaf { 0 } else { 1 }, n.right, if n.right_is_leaf { 0 } else { 1 }]
+    ).collect();
+    bytes.resize((NODE_COUNT + 1) * 4, 0);
+    bytes
+}
+
+// Each byte value's code, as the bits from the root down (false = left).
+fn codes(dict: &[Node]) -> Vec<Vec<bool>> {
+    let mut codes: Vec<Vec<bool>> = vec![Vec::new(); 256];
+    let mut stack: Vec<(usize, Vec<bool>)> = vec![(NODE_COUNT - 1, Vec::new())]; // The root.
+    while let Some((node_index, prefix)) = stack.pop() {
+        let node = &dict[node_index];
+        for (value, is_leaf, bit) in [(node.left, node.left_is_leaf, false), (node.right, node.right_is_leaf, true)] {
+            let mut code = prefix.clone();
+            code.push(bit);
+            if is_leaf {
+                codes[value as usize] = code;
+            } else {
+                stack.push((value as usize, code));
+            }
+        }
+    }
+    codes
+}
+
+// Packs the codes LSB first, the way decompress reads them.
+// The last byte is padded with the start of the longest code, which is at least 8 bits with 256 values,
+// so decoding all of the data (as the headerless tile chunks are) doesn't find an extra byte in the padding.
+pub fn compress(data: &[u8], dict: &[Node]) -> Vec<u8> {
+    let codes = codes(dict);
+    let longest = codes.iter().max_by_key(|c| c.len()).unwrap();
+    let mut output: Vec<u8> = Vec::with_capacity(data.len());
+    let mut bit_count: usize = 0;
+    for bit in data.iter().flat_map(|value| codes[*value as usize].iter()) {
+        if bit_count.is_multiple_of(8) { output.push(0) }
+        if *bit { *output.last_mut().unwrap() |= 1 << (bit_count % 8) }
+        bit_count += 1;
+    }
+    for bit in longest.iter().take((8 - bit_count % 8) % 8) {
+        if *bit { *output.last_mut().unwrap() |= 1 << (bit_count % 8) }
+        bit_count += 1;
+    }
     output
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // The simple way, a bit at a time down the tree, which the table has to match.
+    fn decompress_bitwise(data: &[u8], dict: &Dict, desired_length: usize) -> Vec<u8> {
+        let mut output: Vec<u8> = Vec::new();
+        if desired_length == 0 { return output }
+        let mut node_index = START_NODE;
+        for bit in bitstream::BitStream::new(data) {
+            let (value, is_leaf) = dict.nodes[node_index].child(bit);
+            if is_leaf {
+                output.push(value);
+                if output.len() == desired_length { return output }
+                node_index = START_NODE;
+            } else {
+                node_index = value as usize;
+            }
+        }
+        output
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let data = b"Commander Keen in Goodbye, Galaxy!";
+        let mut counts = [0u32; 256];
+        for value in data {
+            counts[*value as usize] += 1;
+        }
+        let dict_bytes = dict_bytes(&build_dict(&counts));
+        assert_eq!(dict_bytes.len(), 1024);
+        let dict = parse_dict(&dict_bytes).unwrap();
+        let compressed = compress(data, &dict.nodes);
+        assert_eq!(decompress(&compressed, &dict, data.len()), data);
+        assert_eq!(decompress(&compressed, &dict, 999999), data); // Nothing extra from the padding.
+        assert_eq!(bits_needed(&compressed, &dict, data.len()).unwrap().div_ceil(8), compressed.len());
+    }
+
+    #[test]
+    fn test_long_codes() {
+        // Fibonacci counts make a lopsided tree, with codes far longer than the table.
+        let mut counts = [1u32; 256];
+        let (mut a, mut b) = (1u32, 1u32);
+        for count in counts.iter_mut().take(40) {
+            *count = a;
+            (a, b) = (b, a + b);
+        }
+        let dict = parse_dict(&dict_bytes(&build_dict(&counts))).unwrap();
+        let mut seed: u32 = 1;
+        let data: Vec<u8> = (0..5000).map(|_| {
+            seed = seed.wrapping_mul(1103515245).wrapping_add(12345);
+            (seed >> 16) as u8
+        }).collect();
+        let compressed = compress(&data, &dict.nodes);
+        assert_eq!(decompress(&compressed, &dict, data.len()), data);
+        for len in [0, 1, 7, 100, compressed.len() / 2, compressed.len() - 1] {
+            let part = &compressed[..len];
+            assert_eq!(decompress(part, &dict, usize::MAX), decompress_bitwise(part, &dict, usize::MAX));
+            assert_eq!(decompress(part, &dict, 33), decompress_bitwise(part, &dict, 33));
+        }
+    }
+
+    #[test]
+    fn test_matches_bitwise_on_keen4() {
+        let exe = std::fs::read("data/keen4/keen4.exe").unwrap();
+        let offsets = |head: &[u8], width: usize| -> Vec<usize> {
+            head.chunks_exact(width).map(|c| c.iter().rev().fold(0, |o, b| (o << 8) | *b as usize)).collect()
+        };
+        let graph = std::fs::read("data/keen4/egagraph.ck4").unwrap();
+        let audio = std::fs::read("data/keen4/audio.ck4").unwrap();
+        // Each file with its head's offsets and its dictionary, from the exe.
+        let files = [
+            (&graph, offsets(&exe[147072 .. 147072 + 14256], 3), parse_dict(&exe[231158 .. 231158 + 1024]).unwrap()),
+            (&audio, offsets(&exe[146416 .. 146416 + 652], 4), parse_dict(&exe[230134 .. 230134 + 1024]).unwrap()),
+        ];
+        for (data, offsets, dict) in files {
+            let mut offsets: Vec<usize> = offsets.into_iter().filter(|o| *o != 0xffffff).collect();
+            offsets.dedup();
+            for window in offsets.windows(2).filter(|w| w[0] < w[1]) {
+                let chunk = &data[window[0] .. window[1]];
+                assert_eq!(decompress(chunk, &dict, usize::MAX), decompress_bitwise(chunk, &dict, usize::MAX)); // Headerless.
+                if chunk.len() < 4 { continue }
+                let len = u32::from_le_bytes(chunk[0..4].try_into().unwrap()) as usize;
+                assert_eq!(decompress(&chunk[4..], &dict, len), decompress_bitwise(&chunk[4..], &dict, len));
+            }
+        }
+    }
+}
@@ -1,35 +1,49 @@
-// This is responsible for converting planar EGA images into RGBA images, and the image type.
+// This is responsible for converting planar EGA images into indexed images, and the image type.
 
+use anyhow::{Result, bail};
 use crate::palette;
 use crate::png;
 
 pub struct Image {
-    pub data: Vec<u32>, // 0xrrggbbaa
+    pub data: Vec<u8>, // Indices into palette::PALETTE, or palette::CLEAR_INDEX.
     pub width: usize,
     pub height: usize,
 }
 
 // Convert 4-planes ega data.
 pub fn parse_ega_rgbi(data: &[u8], width_div_8: usize, height: usize) -> Image {
-    let indexed_pixels = combine_planes(&data, width_div_8, height, 4);
-    let rgba: Vec<u32> = indexed_pixels.iter().map(|ix| palette::PALETTE[*ix as usize]).collect();
+    let indexed_pixels = combine_planes(data, width_div_8, height, 4);
     let width = width_div_8 * 8;
-    Image { data: rgba, width, height }
+    Image { data: indexed_pixels, width, height }
 }
 
 // Convert 5-planes masked ega data.
 pub fn parse_ega_rgbim(data: &[u8], width_div_8: usize, height: usize) -> Image {
-    let indexed_pixels = combine_planes(&data, width_div_8, height, 5);
-    fn rgba_from_masked_index(ix: &u8) -> u32 {
+    let indexed_pixels = combine_planes(data, width_div_8, height, 5);
+    fn unmask(ix: &u8) -> u8 {
         if ix & 1 == 0 { // Is the mask bit on?
-            return palette::PALETTE[(ix >> 1) as usize]; // Remove the mask bit for the palette lookup.
+            ix >> 1 // Remove the mask bit to get the colour.
         } else {
-            return palette::CLEAR;
+            palette::CLEAR_INDEX
         }
     }
-    let rgba: Vec<u32> = indexed_pixels.iter().map(rgba_from_masked_index).collect();
+    let pixels: Vec<u8> = indexed_pixels.iter().map(unmask).collect();
     let width = width_div_8 * 8;
-    Image { data: rgba, width, height }
+    Image { data: pixels, width, height }
+}
+
+// Convert 1bpp data, eg font characters, where each row is padded to a whole byte.
+// Set bits are drawn black, so they're easy to see, and the rest are clear.
+pub fn parse_mono(data: &[u8], width: usize, height: usize) -> Image {
+    let bytes_per_row = width.div_ceil(8);
+    let mut pixels: Vec<u8> = Vec::with_capacity(width * height);
+    for row in data.chunks_exact(bytes_per_row).take(height) {
+        for x in 0..width {
+            let is_set = row[x / 8] & (0x80 >> (x % 8)) != 0;
+            pixels.push(if is_set { 0 } else { palette::CLEAR_INDEX });
+        }
+    }
+    Image { data: pixels, width, height }
 }
 
 fn combine_planes(data: &[u8], width_div_8: usize, height: usize, planes: usize) -> Vec<u8> {
@@ -40,7 +54,7 @@ fn combine_planes(data: &[u8], width_div_8: usize, height: usize, planes: usize)
         let plane_mask = 1 << plane_index;
         let mut out_index = 0;
         for eight_pixels in plane {
-            if eight_pixels & 0x80 != 0 { indexed_pixels[out_index + 0] |= plane_mask; }
+            if eight_pixels & 0x80 != 0 { indexed_pixels[out_index] |= plane_mask; }
             if eight_pixels & 0x40 != 0 { indexed_pixels[out_index + 1] |= plane_mask; }
             if eight_pixels & 0x20 != 0 { indexed_pixels[out_index + 2] |= plane_mask; }
             if eight_pixels & 0x10 != 0 { indexed_pixels[out_index + 3] |= plane_mask; }
@@ -55,16 +69,134 @@ fn combine_planes(data: &[u8], width_div_8: usize, height: usize, planes: usize)
 }
 
 impl Image {
-    pub fn png(&self) -> Vec<u8> {
-        png::encode(self.width as u32, self.height as u32, &self.data)
+    // Writes an indexed png with the EGA palette, so the colours can be edited and re-imported losslessly.
+    // Images with clear pixels have it as a 17th entry, so they're 8bpp rather than 4bpp.
+    pub fn png(&self, level: png::Level) -> Vec<u8> {
+        let colours = indexed_palette(&[self], false);
+        png::encode(self.width as u32, self.height as u32, &self.data, &colours, level)
     }
 
-    // Makes a new empty image.
+    // Reads a png back into EGA colours, eg after it's been edited. It can be any kind of png, as long as every pixel is
+    // either fully clear (masked) or exactly one of the 16 colours. Any that aren't are listed with their coordinates.
+    pub fn from_png(data: &[u8]) -> Result<Self> {
+        const MAX_LISTED: usize = 10;
+        let decoded = png::decode(data)?;
+        let mut pixels: Vec<u8> = Vec::with_capacity(decoded.rgba.len());
+        let mut strays: Vec<String> = Vec::new();
+        let mut stray_count: usize = 0;
+        for (index, rgba) in decoded.rgba.iter().enumerate() {
+            if rgba & 0xff == 0 {
+                pixels.push(palette::CLEAR_INDEX);
+            } else if let Some(ix) = palette::PALETTE.iter().position(|colour| colour == rgba) {
+                pixels.push(ix as u8);
+            } else {
+                if strays.len() < MAX_LISTED {
+                    strays.push(format!("({}, {}) is #{:08x}", index % decoded.width, index / decoded.width, rgba));
+                }
+                stray_count += 1;
+                pixels.push(0);
+            }
+        }
+        if stray_count > 0 {
+            let more = if stray_count > MAX_LISTED { format!(", and {} more", stray_count - MAX_LISTED) } else { String::new() };
+            bail!("{} pixels aren't in the EGA palette: {}{}", stray_count, strays.join(", "), more)
+        }
+        Ok(Image { data: pixels, width: decoded.width, height: decoded.height })
+    }
+
+    // Makes a new empty (clear) image.
     pub fn empty(width: usize, height: usize) -> Self {
         Image {
-            data: vec![0; width*height],
+            data: vec![palette::CLEAR_INDEX; width*height],
             width,
             height,
         }
     }
 }
+
+// Packs the images into one, eg a sprite sheet, in rows from tallest to shortest with a pixel between them.
+// Returns where each image went, in the order given.
+pub fn pack(images: &[&Image], max_width: usize) -> (Image, Vec<(usize, usize)>) {
+    const GAP: usize = 1;
+    let mut order: Vec<usize> = (0..images.len()).collect();
+    order.sort_by_key(|i| std::cmp::Reverse(images[*i].height));
+    let mut positions: Vec<(usize, usize)> = vec![(0, 0); images.len()];
+    let (mut x, mut y, mut row_height, mut width) = (0, 0, 0, 0);
+    for i in order {
+        let image = images[i];
+        if x > 0 && x + image.width > max_width {
+            x = 0;
+            y += row_height + GAP;
+            row_height = 0;
+        }
+        positions[i] = (x, y);
+        x += image.width + GAP;
+        width = width.max(x - GAP);
+        row_height = row_height.max(image.height);
+    }
+    let mut sheet = Image::empty(width, y + row_height);
+    for (image, (x, y)) in images.iter().zip(&positions) {
+        for row in 0..image.height {
+            let out = (y + row) * sheet.width + x;
+            sheet.data[out .. out + image.width].copy_from_slice(&image.data[row * image.width .. (row + 1) * image.width]);
+        }
+    }
+    (sheet, positions)
+}
+
+// The EGA palette, always in the same order so every png's indices mean the same colour, plus clear at CLEAR_INDEX
+// only if it's needed. Clear takes a 17th entry, so those are written at 8 bits per pixel.
+fn indexed_palette(images: &[&Image], needs_clear: bool) -> Vec<u32> {
+    let mut colours = palette::PALETTE.to_vec();
+    if needs_clear || images.iter().any(|i| i.data.contains(&palette::CLEAR_INDEX)) {
+        colours.push(palette::CLEAR);
+    }
+    colours
+}
+
+// Writes same-sized images as a looping animated png, each shown for its number of tics (70ths of a second, as the game
+// counts them). Frames after the first only store what changed since the one before: The rectangle around the changes,
+// drawn over the previous frame with everything that didn't change left clear, which compresses far better.
+pub fn apng(frames: &[(Image, u32)], level: png::Level) -> Vec<u8> {
+    const TICS_PER_SECOND: u16 = 70;
+    let Some((first, _)) = frames.first() else { return Vec::new() };
+    let (width, height) = (first.width, first.height);
+    let images: Vec<&Image> = frames.iter().map(|(image, _)| image).collect();
+    let colours = indexed_palette(&images, true);
+    let clear = palette::CLEAR_INDEX;
+    let pixels: Vec<&Vec<u8>> = images.iter().map(|i| &i.data).collect();
+
+    struct Change { pixels: Vec<u8>, x: usize, y: usize, width: usize, height: usize, tics: u32, blend_over: bool }
+    let mut changes: Vec<Change> = vec![Change { pixels: pixels[0].clone(), x: 0, y: 0, width, height, tics: frames[0].1, blend_over: false }];
+    for (index, (_, tics)) in frames.iter().enumerate().skip(1) {
+        let (current, previous) = (&pixels[index], &pixels[index - 1]);
+        let changed = |i: usize| current[i] != previous[i];
+        let Some(first_changed) = (0..width * height).find(|i| changed(*i)) else {
+            changes.last_mut().unwrap().tics += tics; // Nothing changed, so show the previous frame for longer.
+            continue
+        };
+        let last_changed = (0..width * height).rev().find(|i| changed(*i)).unwrap();
+        let (top, bottom) = (first_changed / width, last_changed / width);
+        let rows = top..=bottom;
+        let left = rows.clone().filter_map(|y| (0..width).find(|x| changed(y * width + x))).min().unwrap();
+        let right = rows.clone().filter_map(|y| (0..width).rev().find(|x| changed(y * width + x))).max().unwrap();
+        // Something turning clear can't be drawn over the previous frame, so then it has to replace it.
+        let blend_over = !rows.clone().any(|y| (left..=right).any(|x| changed(y * width + x) && current[y * width + x] == clear));
+        let rect: Vec<u8> = rows.flat_map(|y| (left..=right).map(move |x| y * width + x))
+            .map(|i| if blend_over && !changed(i) { clear } else { current[i] })
+            .collect();
+        changes.push(Change { pixels: rect, x: left, y: top, width: right - left + 1, height: bottom - top + 1, tics: *tics, blend_over });
+    }
+
+    let png_frames: Vec<png::Frame> = changes.iter().map(|change| png::Frame {
+        x: change.x as u32,
+        y: change.y as u32,
+        width: change.width as u32,
+        height: change.height as u32,
+        pixels: &change.pixels,
+        delay_numerator: change.tics.min(u16::MAX as u32) as u16,
+        delay_denominator: TICS_PER_SECOND,
+        blend_over: change.blend_over,
+    }).collect();
+    png::encode_animated(width as u32, height as u32, &png_frames, &colours, level)
+}
@@ -0,0 +1,112 @@
+// This is responsible for knowing what the sprite/info plane values mean for each game,
+// ie which sprite chunk to draw for each actor that spawns there, and where.
+// Values that aren't listed here (eg scroll blocks, dart shooters) get drawn as a labelled box instead.
+
+use crate::versions::Episode;
+
+// An actor that spawns from the info plane.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Actor {
+    pub sprite: usize, // Index into Graphics::sprites.
+    pub placement: Placement,
+}
+
+// Where the spawn code puts an actor, relative to its tile.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Placement {
+    Tile, // At the tile's top-left, eg items, platforms, and things that fly or swim.
+    Floor, // Moved up so the bottom of its hit box is on the bottom of the tile, like things that walk.
+}
+
+// Returns the actor for an info plane value, if it's a known one.
+pub fn actor_for(episode: Episode, value: u16) -> Option<Actor> {
+    let table: &[(u16, usize, Placement)] = match episode {
+        Episode::Keen4 => &KEEN4,
+        Episode::Keen5 => &KEEN5,
+        Episode::Keen6 => &KEEN6,
+    };
+    table.iter().find(|(v, _, _)| *v == value).map(|(_, sprite, placement)| Actor { sprite: *sprite, placement: *placement })
+}
+
+// Returns the sprite chunk index for an info plane value, if it's a known actor.
+pub fn sprite_for(episode: Episode, value: u16) -> Option<usize> {
+    actor_for(episode, value).map(|actor| actor.sprite)
+}
+
+// Switches and doors instead hold the tile they act on (or lead to), as x * 256 + y.
+// Actor values are all small, so anything with an x is one of these.
+pub fn target_for(value: u16) -> Option<(usize, usize)> {
+    let x = (value >> 8) as usize;
+    let y = (value & 0xff) as usize;
+    if x == 0 { None } else { Some((x, y)) }
+}
+
+use Placement::{Floor, Tile};
+
+// (Info plane value, sprite chunk index, placement.)
+// Keen 4's values were matched up using the TED5 icons at the start of its masked tiles (icon = value) against the shareware data.
+// Many enemies come in 3 values, where the later ones only spawn on harder difficulties.
+const KEEN4: [(u16, usize, Placement); 62] = [
+    (1, 6, Floor), // Keen, facing right.
+    (2, 14, Floor), // Keen, facing left.
+    (3, 130, Tile), // World map Keen.
+    (4, 232, Floor), // Council member.
+    (5, 379, Floor), (49, 379, Floor), (50, 379, Floor), // Berkeloid.
+    (6, 205, Tile), // Princess Lindsey.
+    (7, 333, Floor), (51, 333, Floor), (52, 333, Floor), // Wormmouth.
+    (8, 319, Tile), (45, 319, Tile), (46, 319, Tile), // Skypest.
+    (9, 374, Tile), // Thundercloud.
+    (10, 213, Tile), // Foot.
+    (11, 209, Floor), // Inchworm.
+    (12, 367, Floor), // Bounder.
+    (13, 238, Floor), // Eggbird egg.
+    (14, 345, Floor), (47, 345, Floor), (48, 345, Floor), // Lick.
+    (15, 280, Tile), (87, 280, Tile), (88, 280, Tile), // Dopefish.
+    (16, 297, Tile), // Schoolfish.
+    (17, 307, Tile), (23, 307, Tile), (24, 307, Tile), // Sprite.
+    (18, 214, Floor), // Treasure eater.
+    (19, 264, Floor), // Mimrock.
+    (20, 301, Floor), (73, 301, Floor), (74, 301, Floor), // Arachnut.
+    (21, 201, Floor), // Mad mushroom.
+    (22, 191, Floor), (43, 191, Floor), (44, 191, Floor), // Poison slug.
+    (27, 360, Tile), (28, 360, Tile), (29, 360, Tile), (30, 360, Tile), // Platforms, moving up/right/down/left.
+    (32, 360, Tile), // Dropping platform.
+    (35, 306, Floor), // Wetsuit.
+    (42, 185, Tile), // Keen in his wetsuit, for the underwater level.
+    (57, 118, Tile), (58, 120, Tile), (59, 122, Tile), (60, 124, Tile), // Gems: red, yellow, blue, green.
+    (61, 103, Tile), // Shikadi soda, 100 points.
+    (62, 105, Tile), // Three-tooth gum, 200 points.
+    (63, 107, Tile), // Candy bar, 500 points.
+    (64, 109, Tile), // Jawbreaker, 1000 points.
+    (65, 111, Tile), // Doughnut, 2000 points.
+    (66, 113, Tile), // Ice cream cone, 5000 points.
+    (67, 115, Tile), // Lifewater flask, extra life.
+    (68, 127, Tile), // Neural stunner.
+    (70, 316, Tile), (71, 316, Tile), (72, 316, Tile), // Underwater mines, moving right/down/left.
+    (77, 251, Tile), (78, 251, Tile), // Blue bird, flying from the start on harder difficulties.
+];
+// Keen 5 and 6 aren't included in data/ so their tables haven't been checked against real data yet.
+// Until they are, their actors are drawn as labelled boxes (switches and doors are still drawn as links).
+const KEEN5: [(u16, usize, Placement); 0] = [];
+const KEEN6: [(u16, usize, Placement); 0] = [];
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_target_for() {
+        assert_eq!(target_for(5), None); // An actor.
+        assert_eq!(target_for(0x1203), Some((0x12, 3)));
+    }
+
+    #[test]
+    fn test_keen4_sprites_exist() {
+        let paths = crate::read::Paths::discover("data/keen4").unwrap();
+        let game = crate::Game::from_paths(&paths).unwrap();
+        for (value, sprite, _) in KEEN4 {
+            let image = game.graphics.sprites.get(sprite).and_then(|s| s.as_ref()).map(|s| &s.image);
+            assert!(image.is_some_and(|i| i.width > 0 && i.height > 0), "Info value {} has no sprite {}", value, sprite);
+        }
+    }
+}
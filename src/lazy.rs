@@ -0,0 +1,294 @@
+// This is responsible for decoding only the graphics and maps that are asked for, when they're first asked for, eg to
+// render one map without decoding the whole game. Each one is kept once it's decoded. EGAGRAPH and GAMEMAPS can be
+// left on disk (see ByteSource), so only the chunks and maps that are decoded are read.
+
+use crate::Error;
+use crate::egagraph::ChunkIterator;
+use crate::images::Image;
+use crate::info_plane;
+use crate::map_renderer::{self, RenderOptions, Tiles};
+use crate::parse;
+use crate::parse_graphics::{self, Sprite};
+use crate::parse_maps::{self, Map, MapHead};
+use crate::read;
+use crate::source::{ByteSource, SeekableReader};
+use crate::tile_info::TileInfo;
+use crate::versions::{self, ChunkLayout};
+use anyhow::{Context, bail};
+use std::sync::OnceLock;
+use std::collections::BTreeSet;
+use std::fs::File;
+
+// A game whose pictures, sprites, tiles and maps are decoded on demand, rather than all up front as with Game.
+pub struct LazyGame<S> {
+    pub detection: Option<versions::Detection>, // Which exe it was, or None if all the tables were given as files.
+    pub tile_info: Option<TileInfo>, // If MAPHEAD had any.
+    chunks: ChunkIterator<S>,
+    tables: parse_graphics::Tables,
+    gamemaps: S,
+    map_head: MapHead,
+    pictures_unmasked: Vec<OnceLock<Option<Image>>>,
+    pictures_masked: Vec<OnceLock<Option<Image>>>,
+    sprites: Vec<OnceLock<Option<Sprite>>>,
+    tiles_16_unmasked: Vec<OnceLock<Option<Image>>>,
+    tiles_16_masked: Vec<OnceLock<Option<Image>>>,
+    maps: Vec<OnceLock<Map>>,
+}
+
+impl<'a> LazyGame<&'a [u8]> {
+    // Only reads the tables: The exe's (or the table files), the picture and sprite tables, MAPHEAD and the tile info.
+    // The audio and best effort mode are ignored, as everything's decoded later, and errors are returned then.
+    pub fn new(files: &parse::Files<'a>) -> Result<Self, Error> {
+        Self::from_sources(files.exe, files.graph, files.maps, &files.tables)
+    }
+}
+
+impl LazyGame<SeekableReader<File>> {
+    // Reads the exe and any table files, but leaves EGAGRAPH and GAMEMAPS on disk until something's asked for.
+    pub fn from_paths(paths: &read::Paths) -> Result<Self, Error> {
+        read::open_lazy(paths)
+    }
+}
+
+impl<S: ByteSource> LazyGame<S> {
+    // As new, but with EGAGRAPH and GAMEMAPS read from as needed, eg from a SeekableReader over open files, or
+    // memory-mapped ones. The exe and tables are small, so they're given in memory.
+    pub fn from_sources(exe: Option<&[u8]>, graph: S, maps: S, tables: &parse::ExternalTables) -> Result<Self, Error> {
+        let exe_tables = parse::tables(exe, &graph, maps.len(), None, tables)?;
+        let episode = exe_tables.detection.as_ref().and_then(|d| d.version).map(|v| v.episode());
+        let chunks = ChunkIterator::new(graph, exe_tables.graph_head, exe_tables.graph_dict).map_err(graphics_error)?;
+        let table = |index: usize| chunks.get(index).map_err(graphics_error);
+        let tables = parse_graphics::Tables::new(&table(0)?, &table(1)?, &table(2)?, episode, chunks.chunk_count()).map_err(graphics_error)?;
+        let map_head = MapHead::parse(exe_tables.map_head, maps.len()).map_err(maps_error)?;
+        let layout = tables.layout;
+        Ok(LazyGame {
+            detection: exe_tables.detection,
+            tile_info: parse::tile_info(exe_tables.tile_info, layout.tiles_16, layout.masked_tiles_16)?,
+            chunks,
+            gamemaps: maps,
+            pictures_unmasked: cells(layout.pictures),
+            pictures_masked: cells(layout.masked_pictures),
+            sprites: cells(layout.sprites),
+            tiles_16_unmasked: cells(layout.tiles_16),
+            tiles_16_masked: cells(layout.masked_tiles_16),
+            maps: cells(map_head.offsets.len()),
+            tables,
+            map_head,
+        })
+    }
+
+    // Which of Keen 4, 5 or 6 this is, if known.
+    pub fn episode(&self) -> Option<versions::Episode> {
+        self.detection.as_ref().and_then(|d| d.version).map(|v| v.episode())
+    }
+
+    // How many of each kind of graphics there are, and where they are.
+    pub fn layout(&self) -> &ChunkLayout {
+        &self.tables.layout
+    }
+
+    pub fn map_count(&self) -> usize {
+        self.maps.len()
+    }
+
+    // These give None for empty slots, as Graphics does, and an error if the index is past the end or it's broken.
+
+    pub fn picture(&self, index: usize) -> Result<Option<&Image>, Error> {
+        let chunk = self.layout().start_pictures() + index;
+        cached(&self.pictures_unmasked, index, "unmasked picture", || {
+            parse_graphics::parse_picture(&self.chunks.get(chunk)?, &self.tables.pictures[index], false)
+        }).map(Option::as_ref).map_err(graphics_error)
+    }
+
+    pub fn masked_picture(&self, index: usize) -> Result<Option<&Image>, Error> {
+        let chunk = self.layout().start_masked_pictures() + index;
+        cached(&self.pictures_masked, index, "masked picture", || {
+            parse_graphics::parse_picture(&self.chunks.get(chunk)?, &self.tables.masked_pictures[index], true)
+        }).map(Option::as_ref).map_err(graphics_error)
+    }
+
+    pub fn sprite(&self, index: usize) -> Result<Option<&Sprite>, Error> {
+        let chunk = self.layout().start_sprites() + index;
+        cached(&self.sprites, index, "sprite", || {
+            parse_graphics::parse_sprite(&self.chunks.get(chunk)?, &self.tables.sprites[index])
+        }).map(Option::as_ref).map_err(graphics_error)
+    }
+
+    pub fn tile16(&self, index: usize) -> Result<Option<&Image>, Error> {
+        let chunk = self.layout().start_tile_16() + index;
+        cached(&self.tiles_16_unmasked, index, "unmasked 16x16 tile", || {
+            Ok(parse_graphics::parse_tile_16(&self.chunks.get_with_length(chunk, parse_graphics::TILE_16_LEN)?, false))
+        }).map(Option::as_ref).map_err(graphics_error)
+    }
+
+    // Numbered as in the maps' foreground plane, so 0 is always empty.
+    pub fn masked_tile16(&self, index: usize) -> Result<Option<&Image>, Error> {
+        let chunk = self.layout().start_masked_tile_16() + index;
+        cached(&self.tiles_16_masked, index, "masked 16x16 tile", || {
+            Ok(parse_graphics::parse_tile_16(&self.chunks.get_with_length(chunk, parse_graphics::MASKED_TILE_16_LEN)?, true))
+        }).map(Option::as_ref).map_err(graphics_error)
+    }
+
+    // Counting only the maps that are there, as Game::maps does. Map::index is its number in the map head.
+    pub fn map(&self, index: usize) -> Result<&Map, Error> {
+        cached(&self.maps, index, "map", || {
+            let (number, offset) = self.map_head.offsets[index];
+            parse_maps::parse_map(&self.gamemaps, self.map_head.rlew_key, number, offset)
+        }).map_err(maps_error)
+    }
+
+    // Only reads the map's header, unless it's decoded already, eg to list the maps.
+    pub fn map_name(&self, index: usize) -> Result<String, Error> {
+        if let Some(map) = self.maps.get(index).and_then(OnceLock::get) { return Ok(map.name.clone()) }
+        let Some((_, offset)) = self.map_head.offsets.get(index) else {
+            return Err(Error::Maps(format!("There's no map {}, there are only {}!", index, self.maps.len())))
+        };
+        parse_maps::parse_map_name(&self.gamemaps, *offset).map_err(maps_error)
+    }
+
+    // Renders a map, decoding just the tiles it uses, plus the actors' sprites if drawing the info plane.
+    pub fn render_map(&self, index: usize, options: &RenderOptions) -> Result<Image, Error> {
+        let map = self.map(index)?;
+        self.decode_for(map, options, None)?;
+        Ok(map_renderer::render(map, self, options))
+    }
+
+    // Likewise for a map's animation, which also needs every tile its animated tiles turn into.
+    pub fn render_animated(&self, index: usize, options: &RenderOptions) -> Result<Vec<(Image, u32)>, Error> {
+        let Some(tile_info) = &self.tile_info else { return Err(Error::Maps("Animating a map needs the tile info, which wasn't found!".into())) };
+        let map = self.map(index)?;
+        self.decode_for(map, options, Some(tile_info))?;
+        Ok(map_renderer::render_animated(map, self, tile_info, options))
+    }
+
+    // Decodes everything the map shows, as rendering only draws what's already decoded. Tiles and sprites out of range
+    // are skipped, as the renderer leaves them out too.
+    fn decode_for(&self, map: &Map, options: &RenderOptions, tile_info: Option<&TileInfo>) -> Result<(), Error> {
+        let mut backgrounds: BTreeSet<usize> = BTreeSet::new();
+        let mut foregrounds: BTreeSet<usize> = BTreeSet::new();
+        let mut sprites: BTreeSet<usize> = BTreeSet::new();
+        for tile in map.tiles.iter().flatten() {
+            backgrounds.insert(tile.background as usize);
+            foregrounds.extend(tile.foreground.map(|f| f as usize));
+            if options.info_plane && let (Some(info), Some(episode)) = (tile.info, options.episode) {
+                sprites.extend(info_plane::sprite_for(episode, info));
+            }
+        }
+        if let Some(tile_info) = tile_info {
+            follow_animations(&mut backgrounds, |t| tile_info.background.get(t).and_then(|i| i.next_tile(t)));
+            follow_animations(&mut foregrounds, |t| tile_info.foreground.get(t).and_then(|i| i.next_tile(t)));
+        }
+        for tile in backgrounds.into_iter().filter(|t| *t < self.tiles_16_unmasked.len()) {
+            self.tile16(tile)?;
+        }
+        for tile in foregrounds.into_iter().filter(|t| *t < self.tiles_16_masked.len()) {
+            self.masked_tile16(tile)?;
+        }
+        for sprite in sprites.into_iter().filter(|s| *s < self.sprites.len()) {
+            self.sprite(sprite)?;
+        }
+        Ok(())
+    }
+}
+
+// Only what's been decoded so far, see decode_for.
+impl<S> Tiles for LazyGame<S> {
+    fn tile_16(&self, tile: u16) -> Option<&Image> {
+        self.tiles_16_unmasked.get(tile as usize)?.get()?.as_ref()
+    }
+
+    fn masked_tile_16(&self, tile: u16) -> Option<&Image> {
+        self.tiles_16_masked.get(tile as usize)?.get()?.as_ref()
+    }
+
+    fn sprite(&self, index: usize) -> Option<&Sprite> {
+        self.sprites.get(index)?.get()?.as_ref()
+    }
+}
+
+fn cells<T>(count: usize) -> Vec<OnceLock<T>> {
+    (0..count).map(|_| OnceLock::new()).collect()
+}
+
+// The one that was decoded already, or decodes it and keeps it.
+fn cached<'c, T>(cells: &'c [OnceLock<T>], index: usize, what: &str, decode: impl FnOnce() -> anyhow::Result<T>) -> anyhow::Result<&'c T> {
+    let Some(cell) = cells.get(index) else { bail!("There's no {} {}, there are only {}!", what, index, cells.len()) };
+    if let Some(value) = cell.get() { return Ok(value) }
+    let value = decode().with_context(|| format!("Couldn't read {} {}", what, index))?;
+    Ok(cell.get_or_init(|| value))
+}
+
+// Adds every tile the tiles animate into, and those tiles' next ones, and so on.
+fn follow_animations(tiles: &mut BTreeSet<usize>, next_tile: impl Fn(usize) -> Option<usize>) {
+    let mut to_follow: Vec<usize> = tiles.iter().copied().collect();
+    while let Some(tile) = to_follow.pop() {
+        if let Some(next) = next_tile(tile) && tiles.insert(next) {
+            to_follow.push(next);
+        }
+    }
+}
+
+fn graphics_error(error: anyhow::Error) -> Error {
+    Error::Graphics(format!("{:#}", error))
+}
+
+fn maps_error(error: anyhow::Error) -> Error {
+    Error::Maps(format!("{:#}", error))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parse::{ExternalTables, Files};
+
+    #[test]
+    fn test_matches_full_parse() {
+        let read = |name: &str| std::fs::read(format!("data/keen4/{}", name)).unwrap();
+        let (exe, graph, maps) = (read("keen4.exe"), read("egagraph.ck4"), read("gamemaps.ck4"));
+        let files = Files { exe: Some(&exe), graph: &graph, maps: &maps, audio: None, tables: ExternalTables::default(), best_effort: false, keep_chunks: false };
+        let game = parse::parse(&files).unwrap();
+        let lazy = LazyGame::new(&files).unwrap();
+        assert_eq!(lazy.map_count(), game.maps.len());
+        let same = |a: Option<&Image>, b: Option<&Image>| a.map(|i| (i.width, &i.data)) == b.map(|i| (i.width, &i.data));
+        assert!(same(lazy.picture(3).unwrap(), game.graphics.pictures_unmasked[3].as_ref()));
+        assert!(same(lazy.sprite(7).unwrap().map(|s| &s.image), game.graphics.sprites[7].as_ref().map(|s| &s.image)));
+        assert!(lazy.picture(game.graphics.pictures_unmasked.len()).is_err());
+
+        // A map on its own only decodes the tiles it uses, but looks the same.
+        let options = RenderOptions { info_plane: true, episode: game.episode(), ..Default::default() };
+        let image = lazy.render_map(2, &options).unwrap();
+        assert_eq!(lazy.map(2).unwrap(), &game.maps[2]);
+        assert_eq!(image.data, map_renderer::render(&game.maps[2], &game.graphics, &options).data);
+        assert!(lazy.tiles_16_unmasked.iter().any(|t| t.get().is_none()));
+        let options = RenderOptions { region: Some(map_renderer::Region { x: 0, y: 0, width: 12, height: 12 }), ..Default::default() };
+        let frames = lazy.render_animated(5, &options).unwrap();
+        let expected = map_renderer::render_animated(&game.maps[5], &game.graphics, game.tile_info.as_ref().unwrap(), &options);
+        assert!(frames.len() > 1);
+        assert_eq!(frames.len(), expected.len());
+        assert!(frames.iter().zip(&expected).all(|((a, a_tics), (b, b_tics))| a.data == b.data && a_tics == b_tics));
+    }
+
+    #[test]
+    fn test_from_disk() {
+        let paths = read::Paths::discover("data/keen4").unwrap();
+        let lazy = LazyGame::from_paths(&paths).unwrap();
+        let read = |path: &str| std::fs::read(path).unwrap();
+        let (exe, graph, maps) = (read(paths.exe.as_deref().unwrap()), read(&paths.graph), read(&paths.maps));
+        let files = Files { exe: Some(&exe), graph: &graph, maps: &maps, audio: None, tables: ExternalTables::default(), best_effort: false, keep_chunks: false };
+        let in_memory = LazyGame::new(&files).unwrap();
+        assert_eq!(lazy.map_name(3).unwrap(), in_memory.map(3).unwrap().name);
+        assert!(lazy.maps[3].get().is_none());
+        assert!(lazy.map_name(lazy.map_count()).is_err());
+        let options = RenderOptions { info_plane: true, episode: lazy.episode(), ..Default::default() };
+        assert_eq!(lazy.render_map(3, &options).unwrap().data, in_memory.render_map(3, &options).unwrap().data);
+
+        // It can be shared between threads, which each decode what they need.
+        std::thread::scope(|scope| {
+            for index in 0..4 {
+                let lazy = &lazy;
+                scope.spawn(move || lazy.render_map(index, &RenderOptions::default()).unwrap());
+            }
+        });
+        assert!(lazy.maps[..4].iter().all(|m| m.get().is_some()));
+    }
+}
@@ -0,0 +1,72 @@
+// Dopefish Decoder: Reads Commander Keen 4-6's graphics, maps and audio.
+// Load a Game from its files, then use its parsed parts directly or export them all.
+
+mod bitstream;
+mod carmackization;
+mod error;
+mod huffman;
+mod lzexe;
+mod opl;
+mod rlew;
+mod threads;
+pub mod egagraph;
+pub mod export;
+pub mod images;
+pub mod info_plane;
+pub mod lazy;
+pub mod map_renderer;
+pub mod palette;
+pub mod parse;
+pub mod parse_audio;
+pub mod parse_graphics;
+pub mod parse_maps;
+pub mod parse_misc;
+pub mod png;
+pub mod read;
+pub mod sound_renderer;
+pub mod source;
+pub mod sprite_groups;
+pub mod tile_info;
+pub mod tiled;
+pub mod versions;
+pub mod wav;
+
+pub use error::Error;
+
+// A parsed game.
+pub struct Game {
+    pub detection: Option<versions::Detection>, // Which exe it was, or None if all the tables were given as files.
+    pub graphics: parse_graphics::Graphics,
+    pub maps: Vec<parse_maps::Map>,
+    pub tile_info: Option<tile_info::TileInfo>, // If MAPHEAD had any, and its tile counts could be worked out.
+    pub rlew_key: u16, // MAPHEAD's, to pack the maps with again.
+    pub tile_info_data: Vec<u8>, // MAPHEAD's tile info as it was, to write back after the map offsets when packing them.
+    pub audio: Option<parse_audio::Audio>, // If the audio file was given.
+    pub problems: Vec<String>, // What was skipped in best effort mode, eg a broken map, and why.
+}
+
+impl Game {
+    pub fn from_paths(paths: &read::Paths) -> Result<Self, Error> {
+        read::read(paths)
+    }
+
+    pub fn from_bytes(files: &parse::Files) -> Result<Self, Error> {
+        parse::parse(files)
+    }
+
+    // The exact build of the exe, if it was recognised.
+    pub fn version(&self) -> Option<versions::ExeVersion> {
+        self.detection.as_ref().and_then(|d| d.version)
+    }
+
+    // Which of Keen 4, 5 or 6 this is, if known.
+    pub fn episode(&self) -> Option<versions::Episode> {
+        self.version().map(|v| v.episode())
+    }
+
+    // Writes everything out as pngs, wavs etc to options.out_dir, or the current folder if that's None.
+    pub fn export(&self, options: &export::Options) -> Result<(), Error> {
+        export::export(self, options)
+            .map_err(|e| Error::Export(format!("{:#}", e)))
+    }
+}
@@ -0,0 +1,367 @@
+// This is responsible for unpacking LZEXE-compressed executables, which is how the retail Keen 5 and 6 ship.
+// It does the same job as UNLZEXE, producing the same bytes, so the version size table still matches.
+// https://keenwiki.shikadi.net/wiki/UNLZEXE
+
+use anyhow::{Result, bail};
+
+#[derive(Debug, PartialEq)]
+enum Version {
+    V0_90,
+    V0_91,
+}
+
+// Is this an exe with an LZEXE stub?
+pub fn is_packed(exe: &[u8]) -> bool {
+    version(exe).is_some()
+}
+
+// The stub is recognised by a 2-paragraph header with no relocations and the LZ09/LZ91 signature where the table would be.
+fn version(exe: &[u8]) -> Option<Version> {
+    if word(exe, 0)? != 0x5a4d || word(exe, 4)? != 2 || word(exe, 0x0c)? != 0x1c || word(exe, 0x0d)? != 0 { return None }
+    match exe.get(0x1c..0x20)? {
+        b"LZ09" => Some(Version::V0_90),
+        b"LZ91" => Some(Version::V0_91),
+        _ => None,
+    }
+}
+
+// Reads the Nth 16-bit word, as the exe header is described in words.
+fn word(data: &[u8], index: usize) -> Option<u16> {
+    let bytes = data.get(index * 2 .. index * 2 + 2)?;
+    Some(u16::from_le_bytes(bytes.try_into().unwrap()))
+}
+
+fn word_at(data: &[u8], offset: usize) -> Result<u16> {
+    let Some(bytes) = data.get(offset .. offset + 2) else { bail!("LZEXE data ended early at offset {}!", offset) };
+    Ok(u16::from_le_bytes(bytes.try_into().unwrap()))
+}
+
+fn byte_at(data: &[u8], offset: usize) -> Result<u8> {
+    let Some(byte) = data.get(offset) else { bail!("LZEXE data ended early at offset {}!", offset) };
+    Ok(*byte)
+}
+
+pub fn unpack(exe: &[u8]) -> Result<Vec<u8>> {
+    let Some(version) = version(exe) else { bail!("Not an LZEXE-packed exe!") };
+    let in_head: Vec<u16> = (0..0x10).map(|i| word(exe, i).unwrap()).collect();
+    let mut out_head = in_head.clone();
+
+    // The stub's segment starts with the original registers and sizes.
+    let stub_offset = ((in_head[0x0b] as usize) + (in_head[4] as usize)) << 4;
+    let info: Vec<u16> = (0..8).map(|i| word_at(exe, stub_offset + i * 2)).collect::<Result<_>>()?;
+    out_head[0x0a] = info[0]; // IP.
+    out_head[0x0b] = info[1]; // CS.
+    out_head[0x08] = info[2]; // SP.
+    out_head[0x07] = info[3]; // SS.
+    // info[4]: size of the compressed load module, in paragraphs.
+    // info[5]: how much the load module grows when decompressed, in paragraphs.
+    // info[6]: size of the decompressor plus compressed relocation table, in bytes.
+    out_head[0x0c] = 0x1c; // Relocation table comes straight after the header.
+
+    // Relocations, which get padded out to a 512 byte boundary like UNLZEXE does.
+    let relocations = match version {
+        Version::V0_90 => relocations_0_90(exe, stub_offset)?,
+        Version::V0_91 => relocations_0_91(exe, stub_offset)?,
+    };
+    out_head[3] = relocations.len() as u16;
+    let mut header: Vec<u8> = Vec::new();
+    for value in &out_head[..0x0e] {
+        header.extend_from_slice(&value.to_le_bytes());
+    }
+    for (offset, segment) in &relocations {
+        header.extend_from_slice(&offset.to_le_bytes());
+        header.extend_from_slice(&segment.to_le_bytes());
+    }
+    let padding = (0x200 - (header.len() & 0x1ff)) & 0x1ff;
+    header.resize(header.len() + padding, 0);
+    out_head[4] = (header.len() >> 4) as u16;
+
+    // Load module.
+    let compressed_offset = ((in_head[0x0b] as usize + in_head[4] as usize).wrapping_sub(info[4] as usize)) << 4;
+    let load_module = expand(exe, compressed_offset)?;
+
+    // Fix up the memory requirements and sizes now that it's unpacked.
+    if in_head[6] != 0 {
+        out_head[5] = out_head[5].wrapping_sub(info[5].wrapping_add(info[6].wrapping_add(16 - 1) >> 4).wrapping_add(9));
+        if in_head[6] != 0xffff {
+            out_head[6] = out_head[6].wrapping_sub(in_head[5].wrapping_sub(out_head[5]));
+        }
+    }
+    let total_len = load_module.len() + header.len();
+    out_head[1] = (total_len & 0x1ff) as u16; // Bytes in the last page.
+    out_head[2] = ((total_len + 0x1ff) >> 9) as u16; // Pages.
+    for (index, value) in out_head[..0x0e].iter().enumerate() {
+        header[index * 2 .. index * 2 + 2].copy_from_slice(&value.to_le_bytes());
+    }
+
+    let mut output = header;
+    output.extend_from_slice(&load_module);
+    Ok(output)
+}
+
+// 0.90 stores a count then that many offsets for each of the 16 64k segments.
+fn relocations_0_90(exe: &[u8], stub_offset: usize) -> Result<Vec<(u16, u16)>> {
+    let mut position = stub_offset + 0x19d;
+    let mut relocations: Vec<(u16, u16)> = Vec::new();
+    for segment_index in 0..0x10 {
+        let segment: u16 = segment_index * 0x1000;
+        let count = word_at(exe, position)?;
+        position += 2;
+        for _ in 0..count {
+            let offset = word_at(exe, position)?;
+            position += 2;
+            relocations.push((offset, segment));
+        }
+    }
+    Ok(relocations)
+}
+
+// 0.91 stores the distance from the previous relocation, as a byte or (after a 0 byte) a word.
+// A word of 0 skips ahead 0xfff paragraphs, and 1 is the end.
+fn relocations_0_91(exe: &[u8], stub_offset: usize) -> Result<Vec<(u16, u16)>> {
+    let mut position = stub_offset + 0x158;
+    let mut relocations: Vec<(u16, u16)> = Vec::new();
+    let mut offset: u16 = 0;
+    let mut segment: u16 = 0;
+    loop {
+        let mut span = byte_at(exe, position)? as u16;
+        position += 1;
+        if span == 0 {
+            span = word_at(exe, position)?;
+            position += 2;
+            if span == 0 {
+                segment = segment.wrapping_add(0x0fff);
+                continue
+            } else if span == 1 {
+                break
+            }
+        }
+        offset = offset.wrapping_add(span);
+        segment = segment.wrapping_add((offset & !0x0f) >> 4);
+        offset &= 0x0f;
+        relocations.push((offset, segment));
+    }
+    Ok(relocations)
+}
+
+// Reads control bits from 16-bit words interleaved with the data bytes.
+// The next word is read as soon as the last bit of the current one is used, which matters for where the bytes fall.
+struct Bits<'a> {
+    data: &'a [u8],
+    position: usize,
+    buffer: u16,
+    count: u8,
+}
+
+impl<'a> Bits<'a> {
+    fn new(data: &'a [u8], position: usize) -> Result<Self> {
+        let buffer = word_at(data, position)?;
+        Ok(Bits { data, position: position + 2, buffer, count: 16 })
+    }
+
+    fn bit(&mut self) -> Result<bool> {
+        let bit = self.buffer & 1 != 0;
+        self.count -= 1;
+        if self.count == 0 {
+            self.buffer = word_at(self.data, self.position)?;
+            self.position += 2;
+            self.count = 16;
+        } else {
+            self.buffer >>= 1;
+        }
+        Ok(bit)
+    }
+
+    fn byte(&mut self) -> Result<u8> {
+        let byte = byte_at(self.data, self.position)?;
+        self.position += 1;
+        Ok(byte)
+    }
+}
+
+// Expands the LZ77-style compressed load module.
+fn expand(exe: &[u8], offset: usize) -> Result<Vec<u8>> {
+    let mut bits = Bits::new(exe, offset)?;
+    let mut out: Vec<u8> = Vec::new();
+    loop {
+        if bits.bit()? { // Literal.
+            out.push(bits.byte()?);
+            continue
+        }
+        let (span, len): (u16, usize) = if !bits.bit()? { // Short copy: 2 bit length, 1 byte distance.
+            let mut len = (bits.bit()? as usize) << 1;
+            len |= bits.bit()? as usize;
+            (bits.byte()? as u16 | 0xff00, len + 2)
+        } else { // Long copy: 13 bit distance, 3 bit length, with an extra length byte if that's 0.
+            let low = bits.byte()? as u16;
+            let high = bits.byte()?;
+            let span = low | (((high as u16) & !0x07) << 5) | 0xe000;
+            let mut len = (high as usize & 0x07) + 2;
+            if len == 2 {
+                len = bits.byte()? as usize;
+                if len == 0 { break } // End of the load module.
+                if len == 1 { continue } // Segment change, which doesn't matter here.
+                len += 1;
+            }
+            (span, len)
+        };
+        let distance = 0x10000 - span as usize; // Span is a negative offset.
+        if distance > out.len() { bail!("LZEXE copy goes back before the start of the data!") }
+        for _ in 0..len {
+            out.push(out[out.len() - distance]);
+        }
+    }
+    Ok(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Writes control bits into reserved words, reserving the next as soon as one fills, to match how Bits reads.
+    struct Packer {
+        out: Vec<u8>,
+        word_position: usize,
+        count: usize,
+    }
+
+    impl Packer {
+        fn bit(&mut self, bit: bool) {
+            if bit { self.out[self.word_position + self.count / 8] |= 1 << (self.count % 8) }
+            self.count += 1;
+            if self.count == 16 {
+                self.word_position = self.out.len();
+                self.out.extend_from_slice(&[0, 0]);
+                self.count = 0;
+            }
+        }
+    }
+
+    // A minimal LZEXE 0.91 packer, with a stub that isn't runnable but has everything the unpacker reads.
+    fn pack(exe: &[u8]) -> Vec<u8> {
+        let head: Vec<u16> = (0..0x10).map(|i| word(exe, i).unwrap()).collect();
+        let load_module = &exe[(head[4] as usize) << 4 ..];
+
+        // Compress the load module with greedy matching, following chains of earlier positions with the same 2 bytes.
+        const MAX_CHAIN: usize = 64;
+        let mut packer = Packer { out: vec![0, 0], word_position: 0, count: 0 };
+        let mut latest: Vec<usize> = vec![usize::MAX; 0x10000]; // Most recent position of each 2 byte prefix.
+        let mut previous: Vec<usize> = vec![usize::MAX; load_module.len()]; // Earlier position with the same prefix.
+        let prefix = |i: usize| (load_module[i] as usize) << 8 | load_module[i + 1] as usize;
+        let mut i = 0;
+        while i < load_module.len() {
+            let (mut best_len, mut best_distance) = (0, 0);
+            if i + 1 < load_module.len() {
+                let mut candidate = latest[prefix(i)];
+                for _ in 0..MAX_CHAIN {
+                    if candidate == usize::MAX || i - candidate > 0x2000 { break }
+                    let len = (0..256.min(load_module.len() - i))
+                        .take_while(|j| load_module[i + j] == load_module[candidate + j])
+                        .count();
+                    if len > best_len { (best_len, best_distance) = (len, i - candidate) }
+                    candidate = previous[candidate];
+                }
+            }
+            if best_len >= 2 && best_distance <= 0x100 && best_len <= 5 {
+                packer.bit(false);
+                packer.bit(false);
+                packer.bit((best_len - 2) & 2 != 0);
+                packer.bit((best_len - 2) & 1 != 0);
+                packer.out.push((0x100 - best_distance) as u8);
+            } else if best_len >= 3 {
+                let span = (0x10000 - best_distance) as u16;
+                packer.bit(false);
+                packer.bit(true);
+                packer.out.push(span as u8);
+                if best_len <= 9 {
+                    packer.out.push(((span >> 5) as u8 & 0xf8) | (best_len - 2) as u8);
+                } else {
+                    packer.out.push((span >> 5) as u8 & 0xf8);
+                    packer.out.push((best_len - 1) as u8);
+                }
+            } else {
+                best_len = 1;
+                packer.bit(true);
+                packer.out.push(load_module[i]);
+            }
+            for j in i .. (i + best_len).min(load_module.len() - 1) {
+                previous[j] = latest[prefix(j)];
+                latest[prefix(j)] = j;
+            }
+            i += best_len;
+        }
+        packer.bit(false); // End marker.
+        packer.bit(true);
+        packer.out.extend_from_slice(&[0, 0, 0]);
+        let mut compressed = packer.out;
+        compressed.resize(compressed.len().next_multiple_of(16), 0);
+        let compressed_paragraphs = (compressed.len() >> 4) as u16;
+
+        // Relocation table, as distances from the previous one.
+        let mut relocations: Vec<u8> = Vec::new();
+        let mut previous: usize = 0;
+        for r in 0..head[3] as usize {
+            let offset = word(exe, 0x0e + r * 2).unwrap() as usize;
+            let segment = word(exe, 0x0e + r * 2 + 1).unwrap() as usize;
+            let mut span = (segment << 4) + offset - previous;
+            previous = (segment << 4) + offset;
+            while span > 0xffff {
+                relocations.extend_from_slice(&[0, 0, 0]);
+                span -= 0xfff0;
+            }
+            if span < 0x100 {
+                relocations.push(span as u8);
+            } else {
+                relocations.push(0);
+                relocations.extend_from_slice(&(span as u16).to_le_bytes());
+            }
+        }
+        relocations.extend_from_slice(&[0, 1, 0]);
+
+        // Stub segment: the original registers and sizes, then filler where the decompressor code would be.
+        let mut stub: Vec<u8> = Vec::new();
+        let stub_len = (0x158 + relocations.len()) as u16;
+        for value in [head[0x0a], head[0x0b], head[0x08], head[0x07], compressed_paragraphs, 0, stub_len, 0] {
+            stub.extend_from_slice(&value.to_le_bytes());
+        }
+        stub.resize(0x158, 0x90);
+        stub.extend_from_slice(&relocations);
+
+        // Header.
+        let growth = ((stub_len + 16 - 1) >> 4) + 9;
+        let min_alloc = head[5] + growth;
+        let max_alloc = if head[6] == 0xffff { 0xffff } else { head[6] + growth };
+        let total_len = 0x20 + compressed.len() + stub.len();
+        let packed_head: [u16; 0x0e] = [
+            0x5a4d, (total_len & 0x1ff) as u16, ((total_len + 0x1ff) >> 9) as u16, 0, 2, min_alloc, max_alloc,
+            0, 0x80, head[9], 0x0e, compressed_paragraphs, 0x1c, 0,
+        ];
+        let mut output: Vec<u8> = Vec::new();
+        for value in packed_head {
+            output.extend_from_slice(&value.to_le_bytes());
+        }
+        output.extend_from_slice(b"LZ91");
+        output.extend_from_slice(&compressed);
+        output.extend_from_slice(&stub);
+        output
+    }
+
+    #[test]
+    fn test_expand() {
+        // Literal 'a', short copy of 3 back 1, literal 'b', long copy of 4 back 2, end.
+        let input: Vec<u8> = vec![0b10110001, 0b10, b'a', 0xff, b'b', 0xfe, 0xfa, 0, 0, 0];
+        let output = expand(&input, 0).unwrap();
+        assert_eq!(output, b"aaaababab");
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let exe = std::fs::read("data/keen4/keen4.exe").unwrap();
+        let packed = pack(&exe);
+        assert!(is_packed(&packed));
+        assert!(!is_packed(&exe));
+        assert!(packed.len() < exe.len());
+        let unpacked = unpack(&packed).unwrap();
+        assert!(unpacked == exe);
+    }
+}
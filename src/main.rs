@@ -1,30 +1,362 @@
-use anyhow::Result;
-
-mod bitstream;
-mod carmackization;
-mod egagraph;
-mod export;
-mod huffman;
-mod images;
-mod map_renderer;
-mod palette;
-mod parse_graphics;
-mod parse_maps;
-mod parse;
-mod png;
-mod read;
-mod rlew;
-mod versions;
+use anyhow::{Result, bail};
+use std::path::{Path, PathBuf};
+
+use dopefish_decoder::{Error, Game, export, images, map_renderer, parse_maps, png, read, tiled};
+use export::Kind;
+
+const USAGE: &str = "Usage:
+dopefish-decoder <command> <game> [options]
+
+Commands:
+  info <game>                     Show the detected version and how many of each asset there are.
+  list <game> [kinds]             List the assets, eg: list ./KEEN4 maps sprites
+  extract <game> [kinds]          Write the assets out as pngs, wavs etc. All of them if no kinds are given.
+  render-map <game> <n>           Write just map n.
+  check-png <files>               Check edited pngs can be read back in.
+  import-tmx <game> <tmx files>   Put maps edited in Tiled back into a new GAMEMAPS and MAPHEAD.
+
+The kinds are: graphics (which is fonts, pictures, sprites, tiles and misc), maps, audio, or all.
+
+The game is a folder such as ./KEEN4, whose files are found by their names, eg KEEN4E.EXE, EGAGRAPH.CK4 and GAMEMAPS.CK4.
+Or give the files themselves: /Path/To/Keen456.exe /Foo/EGAGRAPH.CK456 GAMEMAPS.CK456
+Tables can instead come from their own files with: --maphead MAPHEAD.CK456 --egahead EGAHEAD.CK456 --egadict EGADICT.CK456
+The exe can be left out when all three are given.
+Add --audio AUDIO.CK456 for the sounds and music. Its tables can be given with: --audiohed AUDIOHED.CK456 --audiodict AUDIODCT.CK456
+
+Options:
+  --out <dir>          Where to write to, instead of the current folder.
+  --range <a-b>        Only assets with indexes a to b (or just a) within each kind.
+  --sprite-debug       Also draw each sprite's hit box and origin.
+  --info-plane         Also render each map with its actors drawn over it.
+  --animate            Also write each map as an animated png, with its water, fire, lights etc moving.
+  --frames             Also write each frame of the maps' animations as a png, with their timings.
+  --region <x,y,w,h>   Only render this part of the maps, in tiles.
+  --tiled              Also write the tiles as Tiled tilesets, and each map as a Tiled map (.tmx).
+  --png-level <0-9>    Choose between faster and smaller pngs, the default is 6.
+  --repack             Also write the graphics and maps back out as a new EGAGRAPH, EGAHEAD, EGADICT, GAMEMAPS and MAPHEAD.
+  --best-effort        Skip broken maps and graphics, eg in a damaged mod, listing what was skipped.
+  --quiet              Only print errors.
+  --verbose            Also print every file as it's written.";
+
+// The parsed command line.
+struct Cli {
+    command: Option<String>, // None for the old style of just giving the files, which extracts everything.
+    positionals: Vec<String>,
+    paths: read::Paths, // Anything given by flags, which take priority over a game folder's files.
+    options: export::Options,
+    quiet: bool,
+}
+
+impl Cli {
+    fn say(&self, message: &str) {
+        if !self.quiet {
+            println!("{}", message);
+        }
+    }
+}
 
 fn main() -> Result<()>{
-    let args: Vec<String> = std::env::args().collect();
-    
-    println!("-=[ Dopefish Decoder ]=-");
-    if args.len() < 4 {
-        println!("Usage:");
-        println!("dopefish-decoder /Path/To/Keen456.exe /Foo/EGAGRAPH.CK456 GAMEMAPS.CK456");
-    } else {
-        read::read(&args[1], &args[2], &args[3])?;
+    let cli = parse_args(std::env::args().skip(1).collect())?;
+    cli.say("-=[ Dopefish Decoder ]=-");
+    match cli.command.as_deref() {
+        Some("info") => info(&cli),
+        Some("list") => list(&cli),
+        Some("extract") => extract(&cli),
+        Some("render-map") => render_map(&cli),
+        Some("check-png") => check_pngs(&cli),
+        Some("import-tmx") => import_tmx(&cli),
+        _ if cli.positionals.is_empty() => {
+            println!("{}", USAGE);
+            Ok(())
+        },
+        _ => extract(&cli),
+    }
+}
+
+fn parse_args(args: Vec<String>) -> Result<Cli> {
+    let mut cli = Cli {
+        command: None,
+        positionals: Vec::new(),
+        paths: read::Paths::default(),
+        options: export::Options::default(),
+        quiet: false,
+    };
+    let mut arg_iter = args.into_iter();
+    while let Some(arg) = arg_iter.next() {
+        let mut value = || arg_iter.next().ok_or_else(|| anyhow::anyhow!("{} needs a value!", arg));
+        match arg.as_str() {
+            "--maphead" => cli.paths.tables.map_head = Some(value()?),
+            "--egahead" => cli.paths.tables.graph_head = Some(value()?),
+            "--egadict" => cli.paths.tables.graph_dict = Some(value()?),
+            "--audio" => cli.paths.audio = Some(value()?),
+            "--audiohed" => cli.paths.tables.audio_head = Some(value()?),
+            "--audiodict" => cli.paths.tables.audio_dict = Some(value()?),
+            "--sprite-debug" => cli.options.sprite_debug = true,
+            "--info-plane" => cli.options.info_plane = true,
+            "--png-level" => cli.options.png_level = parse_png_level(&value()?)?,
+            "--repack" => cli.options.repack = true,
+            "--animate" => cli.options.animate = true,
+            "--frames" => cli.options.animation_frames = true,
+            "--tiled" => cli.options.tiled = true,
+            "--region" => cli.options.map_region = Some(parse_region(&value()?)?),
+            "--out" => cli.options.out_dir = Some(PathBuf::from(value()?)),
+            "--range" => cli.options.range = Some(parse_range(&value()?)?),
+            "--best-effort" => cli.paths.best_effort = true,
+            "--quiet" | "-q" => cli.quiet = true,
+            "--verbose" | "-v" => cli.options.progress = Some(|path| println!("Wrote {}", path.display())),
+            // Kept from before there were subcommands.
+            "--check-png" => {
+                cli.command = Some("check-png".to_string());
+                cli.positionals.push(value()?);
+            },
+            _ if arg.starts_with("--") => bail!("Unknown option {}!\n\n{}", arg, USAGE),
+            "info" | "list" | "extract" | "render-map" | "check-png" | "import-tmx" if cli.command.is_none() && cli.positionals.is_empty() => cli.command = Some(arg),
+            _ => cli.positionals.push(arg),
+        }
+    }
+    if cli.quiet {
+        cli.options.progress = None;
+    }
+    Ok(cli)
+}
+
+// Eg "3-7", or just "3".
+fn parse_range(text: &str) -> Result<std::ops::RangeInclusive<usize>> {
+    let parse = |t: &str| t.trim().parse::<usize>().map_err(|_| anyhow::anyhow!("Couldn't understand the range {}, it should be like 3-7!", text));
+    match text.split_once('-') {
+        Some((start, end)) => Ok(parse(start)?..=parse(end)?),
+        None => {
+            let index = parse(text)?;
+            Ok(index..=index)
+        },
+    }
+}
+
+// 0 (uncompressed) to 9 (smallest).
+fn parse_png_level(text: &str) -> Result<png::Level> {
+    match text.trim().parse::<u8>() {
+        Ok(level) if level <= 9 => Ok(png::Level(level)),
+        _ => bail!("Couldn't understand the png level {}, it should be 0-9!", text),
+    }
+}
+
+// Eg "10,5,20,12" for 20x12 tiles from 10,5.
+fn parse_region(text: &str) -> Result<map_renderer::Region> {
+    let numbers: Vec<usize> = text.split(',').map(|t| t.trim().parse()).collect::<Result<_, _>>()
+        .map_err(|_| anyhow::anyhow!("Couldn't understand the region {}, it should be like 10,5,20,12!", text))?;
+    let [x, y, width, height] = numbers[..] else { bail!("The region needs 4 numbers: x,y,width,height!") };
+    Ok(map_renderer::Region { x, y, width, height })
+}
+
+// Splits trailing kind names such as "maps" or "graphics" off the positionals, leaving the game's folder or files.
+fn split_kinds(positionals: &[String]) -> Result<(&[String], Option<Vec<Kind>>)> {
+    let mut game_len = positionals.len();
+    let mut kinds: Vec<Kind> = Vec::new();
+    while game_len > 0 {
+        let word = positionals[game_len - 1].as_str();
+        let found: Vec<Kind> = match word {
+            "all" => Kind::ALL.to_vec(),
+            "graphics" => Kind::GRAPHICS.to_vec(),
+            _ => match Kind::from_name(word) {
+                Some(kind) => vec![kind],
+                None => break,
+            },
+        };
+        kinds.extend(found.into_iter().filter(|k| !kinds.contains(k)).collect::<Vec<_>>());
+        game_len -= 1;
+    }
+    if game_len == 0 && !positionals.is_empty() { bail!("No game was given!\n\n{}", USAGE) }
+    Ok((&positionals[..game_len], if kinds.is_empty() { None } else { Some(kinds) }))
+}
+
+// Works out where the game's files are, from a folder or the files themselves, then loads it.
+fn load(cli: &Cli, game: &[String], wants_audio: bool) -> Result<Game> {
+    let flags = &cli.paths;
+    let tables = &flags.tables;
+    let has_all_tables = tables.map_head.is_some() && tables.graph_head.is_some() && tables.graph_dict.is_some();
+    let mut paths = match game {
+        [dir] if Path::new(dir).is_dir() => {
+            let found = read::Paths::discover(dir)?;
+            cli.say(&format!("Found {}{} and {}", found.exe.as_ref().map(|e| format!("{}, ", e)).unwrap_or_default(), found.graph, found.maps));
+            found
+        },
+        [exe, graph, maps] => read::Paths { exe: Some(exe.clone()), graph: graph.clone(), maps: maps.clone(), ..Default::default() },
+        [graph, maps] if has_all_tables => read::Paths { graph: graph.clone(), maps: maps.clone(), ..Default::default() },
+        _ => bail!("Couldn't tell where the game is from: {}\n\n{}", game.join(" "), USAGE),
+    };
+    let discovered_audio = flags.audio.is_none() && paths.audio.is_some();
+    let pick = |flag: &Option<String>, found: &mut Option<String>| if flag.is_some() { *found = flag.clone() };
+    pick(&flags.audio, &mut paths.audio);
+    pick(&tables.map_head, &mut paths.tables.map_head);
+    pick(&tables.graph_head, &mut paths.tables.graph_head);
+    pick(&tables.graph_dict, &mut paths.tables.graph_dict);
+    pick(&tables.audio_head, &mut paths.tables.audio_head);
+    pick(&tables.audio_dict, &mut paths.tables.audio_dict);
+    if !wants_audio {
+        paths.audio = None;
+    }
+    paths.best_effort = flags.best_effort;
+    paths.keep_chunks = cli.options.repack;
+
+    cli.say("Reading and parsing...");
+    let game = match Game::from_paths(&paths) {
+        // Audio that was only found by looking in the folder shouldn't stop everything else.
+        Err(Error::NoAudioTables) if discovered_audio => {
+            cli.say("Couldn't find the audio tables in the exe, so skipping the audio.");
+            paths.audio = None;
+            Game::from_paths(&paths)?
+        },
+        result => result?,
+    };
+    match &game.detection {
+        Some(detection) => match detection.version {
+            Some(version) => cli.say(&format!("Detected version: {:?} ({})", version, detection.confidence.description())),
+            None => cli.say(&format!("Detected version: Unknown ({})", detection.confidence.description())),
+        },
+        None => cli.say("Using external tables, so the exe isn't needed."),
+    }
+    for problem in &game.problems {
+        eprintln!("{}", problem);
+    }
+    Ok(game)
+}
+
+fn info(cli: &Cli) -> Result<()> {
+    let game = load(cli, &cli.positionals, true)?;
+    let graphics = &game.graphics;
+    let count = |images: &[Option<images::Image>]| images.iter().flatten().count();
+    println!("Episode: {}", game.episode().map(|e| format!("{:?}", e)).unwrap_or("Unknown".to_string()));
+    println!("Fonts: {}", graphics.fonts.iter().flatten().count());
+    println!("Pictures: {} unmasked, {} masked", count(&graphics.pictures_unmasked), count(&graphics.pictures_masked));
+    println!("Sprites: {}", graphics.sprites.iter().flatten().count());
+    println!("8x8 tiles: {} unmasked, {} masked", graphics.tiles_8_unmasked.len(), graphics.tiles_8_masked.len());
+    println!("16x16 tiles: {} unmasked, {} masked", count(&graphics.tiles_16_unmasked), count(&graphics.tiles_16_masked));
+    println!("Text screens: {}, articles: {}, demos: {}", graphics.text_screens.len(), graphics.articles.len(), graphics.demos.len());
+    println!("Maps: {}", game.maps.len());
+    match &game.tile_info {
+        Some(tile_info) => println!("Tile info: {} background, {} foreground", tile_info.background.len(), tile_info.foreground.len()),
+        None => println!("Tile info: Not found"),
+    }
+    match &game.audio {
+        Some(audio) => println!("Sounds: {}, music: {}", audio.pc_sounds.iter().flatten().count(), audio.music.iter().flatten().count()),
+        None => println!("Audio: Not loaded"),
+    }
+    Ok(())
+}
+
+fn list(cli: &Cli) -> Result<()> {
+    let (game, kinds) = split_kinds(&cli.positionals)?;
+    let kinds = kinds.unwrap_or(Kind::ALL.to_vec());
+    let game = load(cli, game, kinds.contains(&Kind::Audio))?;
+    let graphics = &game.graphics;
+    let wanted = |index: &usize| cli.options.range.as_ref().is_none_or(|r| r.contains(index));
+    for kind in kinds {
+        println!("{}:", kind.name());
+        match kind {
+            Kind::Fonts => for (index, font) in graphics.fonts.iter().enumerate().filter(|(i, _)| wanted(i)) {
+                let Some(font) = font else { continue };
+                println!("  {}: {} glyphs, {} high", index, font.glyphs.iter().flatten().count(), font.height);
+            },
+            Kind::Pictures => for (which, pictures) in [("unmasked", &graphics.pictures_unmasked), ("masked", &graphics.pictures_masked)] {
+                for (index, picture) in pictures.iter().enumerate().filter(|(i, _)| wanted(i)) {
+                    let Some(picture) = picture else { continue };
+                    println!("  {} {}: {}x{}", which, index, picture.width, picture.height);
+                }
+            },
+            Kind::Sprites => for (index, sprite) in graphics.sprites.iter().enumerate().filter(|(i, _)| wanted(i)) {
+                let Some(sprite) = sprite else { continue };
+                println!("  {}: {}x{}, origin {},{}", index, sprite.image.width, sprite.image.height, sprite.origin_x, sprite.origin_y);
+            },
+            Kind::Tiles => {
+                println!("  8x8: {} unmasked, {} masked", graphics.tiles_8_unmasked.len(), graphics.tiles_8_masked.len());
+                println!("  16x16: {} unmasked, {} masked", graphics.tiles_16_unmasked.len(), graphics.tiles_16_masked.len());
+            },
+            Kind::Misc => {
+                for (index, article) in graphics.articles.iter().enumerate().filter(|(i, _)| wanted(i)) {
+                    println!("  article {}: {}", index, article.lines().next().unwrap_or_default());
+                }
+                println!("  text screens: {}", graphics.text_screens.len());
+                for (index, demo) in graphics.demos.iter().enumerate().filter(|(i, _)| wanted(i)) {
+                    println!("  demo {}: map {}, {} inputs", index, demo.map, demo.inputs.len());
+                }
+            },
+            Kind::Maps => for map in game.maps.iter().filter(|m| wanted(&m.index)) {
+                println!("  {}: {} ({}x{})", map.index, map.name, map.width, map.height);
+            },
+            Kind::Audio => {
+                let Some(audio) = &game.audio else {
+                    println!("  Not loaded");
+                    continue
+                };
+                for (index, (pc, adlib)) in audio.pc_sounds.iter().zip(&audio.adlib_sounds).enumerate().filter(|(i, _)| wanted(i)) {
+                    if pc.is_none() && adlib.is_none() { continue }
+                    let len = |data: Option<&Vec<u8>>| data.map(|d| d.len().to_string()).unwrap_or("-".to_string());
+                    println!("  sound {}: pc {} ticks, adlib {} ticks", index, len(pc.as_ref().map(|s| &s.data)), len(adlib.as_ref().map(|s| &s.data)));
+                }
+                for (index, music) in audio.music.iter().enumerate().filter(|(i, _)| wanted(i)) {
+                    let Some(music) = music else { continue };
+                    println!("  music {}: {} bytes", index, music.imf.len());
+                }
+            },
+        }
+    }
+    Ok(())
+}
+
+fn extract(cli: &Cli) -> Result<()> {
+    let (game, kinds) = split_kinds(&cli.positionals)?;
+    let wants_audio = kinds.as_ref().is_none_or(|k| k.contains(&Kind::Audio));
+    let game = load(cli, game, wants_audio)?;
+    cli.say(&format!("Maps: {}, sprites: {}, 16x16 tiles: {}", game.maps.len(), game.graphics.sprites.len(), game.graphics.tiles_16_unmasked.len()));
+    cli.say("Exporting assets...");
+    let options = export::Options { kinds, ..cli.options.clone() };
+    game.export(&options)?;
+    Ok(())
+}
+
+fn render_map(cli: &Cli) -> Result<()> {
+    let Some((index, game)) = cli.positionals.split_last() else { bail!("Which map?\n\n{}", USAGE) };
+    let Ok(index) = index.parse::<usize>() else { bail!("The map should be a number, not {}!", index) };
+    let game = load(cli, game, false)?;
+    let Some(map) = game.maps.iter().find(|m| m.index == index) else { bail!("There's no map {}!", index) };
+    cli.say(&format!("Rendering map {}: {}", index, map.name));
+    let options = export::Options { kinds: Some(vec![Kind::Maps]), range: Some(index..=index), repack: false, ..cli.options.clone() };
+    game.export(&options)?;
+    Ok(())
+}
+
+fn check_pngs(cli: &Cli) -> Result<()> {
+    for path in &cli.positionals {
+        let image = images::Image::from_png(&std::fs::read(path)?)?;
+        println!("{}: {}x{}, every pixel is an EGA colour or clear.", path, image.width, image.height);
+    }
+    Ok(())
+}
+
+// Replaces the maps the .tmx files came from (by the index they were exported with), then packs them all up again.
+fn import_tmx(cli: &Cli) -> Result<()> {
+    let split = cli.positionals.iter().position(|p| p.to_lowercase().ends_with(".tmx")).unwrap_or(cli.positionals.len());
+    let (game, tmx_paths) = cli.positionals.split_at(split);
+    if tmx_paths.is_empty() { bail!("Which .tmx files?\n\n{}", USAGE) }
+    let mut game = load(cli, game, false)?;
+    for path in tmx_paths {
+        let text = std::fs::read_to_string(path)?;
+        let imported = tiled::parse_tmx(&text).map_err(|e| anyhow::anyhow!("{}: {}", path, e))?;
+        cli.say(&format!("Importing map {}: {}", imported.index, imported.name));
+        // Maps are in order of their number, which can skip some, and new ones go in an unused slot.
+        match game.maps.binary_search_by_key(&imported.index, |map| map.index) {
+            Ok(position) => game.maps[position] = imported,
+            Err(position) => game.maps.insert(position, imported),
+        }
+    }
+    let packed = parse_maps::pack(&game.maps, game.rlew_key, &game.tile_info_data)?;
+    let out_dir = cli.options.out_dir.clone().unwrap_or_default();
+    std::fs::create_dir_all(&out_dir)?;
+    let extension = game.episode().map(|e| e.extension()).unwrap_or("CKx");
+    for (name, data) in [("GAMEMAPS", &packed.gamemaps), ("MAPHEAD", &packed.map_head)] {
+        let path = out_dir.join(format!("Output{}.{}", name, extension));
+        std::fs::write(&path, data)?;
+        cli.say(&format!("Wrote {}", path.display()));
     }
     Ok(())
 }
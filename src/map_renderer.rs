@@ -1,52 +1,444 @@
 // This is responsible for rendering a map.
 
 use crate::images::Image;
-use crate::parse_maps::Map;
-use crate::parse_graphics::Graphics;
-
-pub fn render(map: &Map, graphics: &Graphics) -> Image {
-    let tile_size: usize = 16;
-    let mut map_image = Image::empty(map.width * tile_size, map.height * tile_size);
-    for (y, row) in map.tiles.iter().enumerate() {
-        for (x, tile) in row.iter().enumerate() {
-            // Background:
-            // Have to unwrap the image twice: Once if it's in range, and secondly if there is an image in that slot.
-            if let Some(opt_image) = graphics.tiles_16_unmasked.get(tile.background as usize) {
-                if let Some(image) = opt_image {
-                    draw(image, &mut map_image, x * tile_size, y * tile_size);
-                }
+use crate::info_plane;
+use crate::palette;
+use crate::parse_maps::{Map, MapTile};
+use crate::parse_graphics::{Graphics, Sprite};
+use crate::tile_info::TileInfo;
+use crate::versions::Episode;
+use std::collections::{BTreeSet, HashMap};
+
+const TILE_SIZE: usize = 16;
+
+#[derive(Default)]
+pub struct RenderOptions {
+    pub info_plane: bool, // Draw the actors from the sprite/info plane over the map.
+    pub episode: Option<Episode>, // Which game, to know what the info plane values mean. Unknown values are drawn as labelled boxes.
+    pub region: Option<Region>, // Only render part of the map.
+}
+
+// A rectangle of the map, in tiles.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Region {
+    pub x: usize,
+    pub y: usize,
+    pub width: usize,
+    pub height: usize,
+}
+
+impl Region {
+    // The part of the map to render, kept within the map.
+    fn for_options(map: &Map, options: &RenderOptions) -> Region {
+        let region = options.region.unwrap_or(Region { x: 0, y: 0, width: map.width, height: map.height });
+        let x = region.x.min(map.width);
+        let y = region.y.min(map.height);
+        Region { x, y, width: region.width.min(map.width - x), height: region.height.min(map.height - y) }
+    }
+
+    // The tiles in the region, with their positions relative to it.
+    fn tiles<'a>(&self, map: &'a Map) -> impl Iterator<Item = (usize, usize, &'a MapTile)> {
+        let region = *self;
+        map.tiles.iter().enumerate().skip(region.y).take(region.height)
+            .flat_map(move |(y, row)| row.iter().enumerate().skip(region.x).take(region.width).map(move |(x, tile)| (x - region.x, y - region.y, tile)))
+    }
+}
+
+// Where the tiles and sprites to draw come from: Graphics has them all, lazy::LazyGame has the ones decoded so far.
+pub trait Tiles {
+    fn tile_16(&self, tile: u16) -> Option<&Image>;
+    fn masked_tile_16(&self, tile: u16) -> Option<&Image>;
+    fn sprite(&self, index: usize) -> Option<&Sprite>;
+}
+
+// Have to unwrap the images twice: Once if they're in range, and secondly if there is an image in that slot.
+impl Tiles for Graphics {
+    fn tile_16(&self, tile: u16) -> Option<&Image> {
+        self.tiles_16_unmasked.get(tile as usize)?.as_ref()
+    }
+
+    fn masked_tile_16(&self, tile: u16) -> Option<&Image> {
+        self.tiles_16_masked.get(tile as usize)?.as_ref()
+    }
+
+    fn sprite(&self, index: usize) -> Option<&Sprite> {
+        self.sprites.get(index)?.as_ref()
+    }
+}
+
+pub fn render(map: &Map, graphics: &impl Tiles, options: &RenderOptions) -> Image {
+    render_tiles(map, graphics, options, |tile| (tile.background, tile.foreground))
+}
+
+// Renders the map, asking which background and foreground each tile shows, eg for animating them.
+fn render_tiles(map: &Map, graphics: &impl Tiles, options: &RenderOptions, shown: impl Fn(&MapTile) -> (u16, Option<u16>)) -> Image {
+    let tile_size = TILE_SIZE;
+    let region = Region::for_options(map, options);
+    let rows = || region.tiles(map);
+    let mut map_image = Image::empty(region.width * tile_size, region.height * tile_size);
+    for (x, y, tile) in rows() {
+        let (background, foreground) = shown(tile);
+
+        // Background:
+        if let Some(image) = graphics.tile_16(background) {
+            draw(image, &mut map_image, x * tile_size, y * tile_size);
+        }
+
+        // Foreground:
+        if let Some(foreground) = foreground // Does this tile have a foreground?
+            && let Some(image) = graphics.masked_tile_16(foreground) {
+            draw(image, &mut map_image, x * tile_size, y * tile_size);
+        }
+    }
+
+    // Sprites go in a second pass so they aren't covered by the tiles to their right and below.
+    if options.info_plane {
+        for (x, y, tile) in rows() {
+            let Some(info) = tile.info else { continue };
+            if let Some((target_x, target_y)) = info_plane::target_for(info) {
+                // The target is in map tiles, so make it relative to the region too.
+                let region_target = (target_x as i32 - region.x as i32, target_y as i32 - region.y as i32);
+                draw_link(&mut map_image, (x as i32, y as i32), region_target);
+                continue
+            }
+            let actor = options.episode.and_then(|episode| info_plane::actor_for(episode, info));
+            match actor.and_then(|actor| graphics.sprite(actor.sprite).map(|sprite| (actor, sprite))) {
+                Some((actor, sprite)) => {
+                    // The origin is where the image goes relative to the actor, which the spawn code puts on its tile.
+                    let actor_x = (x * tile_size) as i32;
+                    let actor_y = match actor.placement {
+                        info_plane::Placement::Tile => (y * tile_size) as i32,
+                        info_plane::Placement::Floor => ((y + 1) * tile_size) as i32 - 1 - sprite.clip_bottom,
+                    };
+                    draw_clipped(&sprite.image, &mut map_image, actor_x + sprite.origin_x, actor_y + sprite.origin_y);
+                },
+                None => draw_label(info, &mut map_image, x * tile_size, y * tile_size),
             }
+        }
+    }
+    map_image
+}
+
+// Animated tiles all start together when the level loads, so the map loops once all their loops line up. That can be a
+// long time when their lengths don't divide nicely, so past this (in tics, 70ths of a second) only the loops of the
+// most common tiles are lined up, and the others jump when it starts again.
+const MAX_LOOP_TICS: u32 = 70 * 20;
+
+// Where an animated tile goes: Which tiles it shows for how many tics, from the start until it loops (or stops).
+struct Cycle {
+    frames: Vec<(usize, u32)>, // Tile and tics.
+    loop_start: Option<usize>, // The frame it goes back to after the last. None if the last frame stays forever.
+}
 
-            // Foreground:
-            if let Some(foreground) = tile.foreground { // Does this tile have a foreground?
-                if let Some(opt_image) = graphics.tiles_16_masked.get(foreground as usize) { // Is this in valid range?
-                    if let Some(image) = opt_image { // Does this tile slot have an image?
-                        draw(image, &mut map_image, x * tile_size, y * tile_size);
-                    }
+impl Cycle {
+    // Follows a tile's animation through the tile info.
+    fn new(start: usize, time_of: impl Fn(usize) -> Option<u8>, next_of: impl Fn(usize) -> Option<usize>) -> Self {
+        let mut frames: Vec<(usize, u32)> = Vec::new();
+        let mut tile = start;
+        loop {
+            if let Some(position) = frames.iter().position(|(t, _)| *t == tile) {
+                return Cycle { frames, loop_start: Some(position) }
+            }
+            let time = time_of(tile).unwrap_or(0) as u32;
+            match next_of(tile) {
+                Some(next) if time != 0 => {
+                    frames.push((tile, time));
+                    tile = next;
+                },
+                _ => { // It stops here.
+                    frames.push((tile, 0));
+                    return Cycle { frames, loop_start: None }
+                },
+            }
+        }
+    }
+
+    // How long until it starts looping, and how long each loop is.
+    fn prefix_and_loop_tics(&self) -> (u32, u32) {
+        match self.loop_start {
+            Some(start) => (self.frames[..start].iter().map(|f| f.1).sum(), self.frames[start..].iter().map(|f| f.1).sum()),
+            None => (self.frames.iter().map(|f| f.1).sum(), 0),
+        }
+    }
+
+    fn tile_at(&self, tics: u32) -> usize {
+        let (prefix, loop_tics) = self.prefix_and_loop_tics();
+        let (frames, mut tics) = match self.loop_start {
+            Some(start) if tics >= prefix => (&self.frames[start..], (tics - prefix) % loop_tics),
+            _ => (&self.frames[..], tics),
+        };
+        for (tile, time) in frames {
+            if tics < *time { return *tile }
+            tics -= time;
+        }
+        frames.last().unwrap().0 // Stopped.
+    }
+
+    // When it changes tile, within one loop starting at the given time.
+    fn change_times(&self, from: u32, until: u32, into: &mut BTreeSet<u32>) {
+        let mut time = 0;
+        let mut index = 0;
+        while time < until && index < self.frames.len() {
+            time += self.frames[index].1;
+            if time >= from && time < until { into.insert(time); }
+            index += 1;
+            if index == self.frames.len() && let Some(start) = self.loop_start { index = start }
+            if self.frames.get(index).is_none_or(|f| f.1 == 0) { break }
+        }
+    }
+}
+
+fn gcd(a: u32, b: u32) -> u32 {
+    if b == 0 { a } else { gcd(b, a % b) }
+}
+
+// How long until all the tiles' loops line up. If that's too long, it's the longest multiple of the loops of the tiles
+// used the most that fits, so those still line up.
+fn loop_tics(map: &Map, options: &RenderOptions, backgrounds: &HashMap<u16, Cycle>, foregrounds: &HashMap<u16, Cycle>) -> u32 {
+    let mut uses: HashMap<u32, usize> = HashMap::new(); // How many tiles loop in each length.
+    for (_, _, tile) in Region::for_options(map, options).tiles(map) {
+        let background = backgrounds.get(&tile.background);
+        let foreground = tile.foreground.and_then(|f| foregrounds.get(&f));
+        for cycle in [background, foreground].into_iter().flatten() {
+            let (_, loop_tics) = cycle.prefix_and_loop_tics();
+            if loop_tics != 0 { *uses.entry(loop_tics).or_default() += 1 }
+        }
+    }
+    let mut lengths: Vec<(u32, usize)> = uses.into_iter().collect();
+    lengths.sort_by_key(|(length, count)| (std::cmp::Reverse(*count), *length));
+    let mut lcm: u32 = 1;
+    let mut is_cut_off = false;
+    for (length, _) in lengths {
+        let next = (lcm / gcd(lcm, length)) as u64 * length as u64;
+        if next <= MAX_LOOP_TICS as u64 { lcm = next as u32 } else { is_cut_off = true }
+    }
+    if is_cut_off { MAX_LOOP_TICS / lcm * lcm } else { lcm }
+}
+
+// Renders one loop of the map's tile animations, eg water, fire and lights, as frames and how many tics each shows for.
+// A map without any animated tiles gives a single frame.
+pub fn render_animated(map: &Map, graphics: &impl Tiles, tile_info: &TileInfo, options: &RenderOptions) -> Vec<(Image, u32)> {
+    let background_cycle = |tile: usize| Cycle::new(tile,
+        |t| tile_info.background.get(t).map(|i| i.animation_time),
+        |t| tile_info.background.get(t).and_then(|i| i.next_tile(t)));
+    let foreground_cycle = |tile: usize| Cycle::new(tile,
+        |t| tile_info.foreground.get(t).map(|i| i.animation_time),
+        |t| tile_info.foreground.get(t).and_then(|i| i.next_tile(t)));
+    let mut backgrounds: HashMap<u16, Cycle> = HashMap::new();
+    let mut foregrounds: HashMap<u16, Cycle> = HashMap::new();
+    for (_, _, tile) in Region::for_options(map, options).tiles(map) {
+        backgrounds.entry(tile.background).or_insert_with(|| background_cycle(tile.background as usize));
+        if let Some(foreground) = tile.foreground {
+            foregrounds.entry(foreground).or_insert_with(|| foreground_cycle(foreground as usize));
+        }
+    }
+    backgrounds.retain(|_, c| c.frames.len() > 1);
+    foregrounds.retain(|_, c| c.frames.len() > 1);
+
+    // Skip any tiles' lead-ins, then go for long enough for all the loops to line up.
+    let cycles = || backgrounds.values().chain(foregrounds.values());
+    let start = cycles().map(|c| c.prefix_and_loop_tics().0).max().unwrap_or(0);
+    let loop_tics = loop_tics(map, options, &backgrounds, &foregrounds);
+    let end = start + loop_tics;
+    let mut times: BTreeSet<u32> = BTreeSet::from([start]);
+    for cycle in cycles() {
+        cycle.change_times(start, end, &mut times);
+    }
+    if backgrounds.is_empty() && foregrounds.is_empty() {
+        return vec![(render(map, graphics, options), 0)]
+    }
+
+    let times: Vec<u32> = times.into_iter().collect();
+    times.iter().enumerate().map(|(index, time)| {
+        let image = render_tiles(map, graphics, options, |tile| {
+            let background = backgrounds.get(&tile.background).map(|c| c.tile_at(*time) as u16).unwrap_or(tile.background);
+            let foreground = tile.foreground.map(|f| foregrounds.get(&f).map(|c| c.tile_at(*time) as u16).unwrap_or(f));
+            (background, foreground)
+        });
+        let next = times.get(index + 1).copied().unwrap_or(end);
+        (image, next - time)
+    }).collect()
+}
+
+// 3x5 digits for labelling unknown info plane values, each row is 3 bits.
+const DIGITS: [[u8; 5]; 10] = [
+    [0b111, 0b101, 0b101, 0b101, 0b111], // 0
+    [0b010, 0b110, 0b010, 0b010, 0b111], // 1
+    [0b111, 0b001, 0b111, 0b100, 0b111], // 2
+    [0b111, 0b001, 0b111, 0b001, 0b111], // 3
+    [0b101, 0b101, 0b111, 0b001, 0b001], // 4
+    [0b111, 0b100, 0b111, 0b001, 0b111], // 5
+    [0b111, 0b100, 0b111, 0b101, 0b111], // 6
+    [0b111, 0b001, 0b001, 0b001, 0b001], // 7
+    [0b111, 0b101, 0b111, 0b101, 0b111], // 8
+    [0b111, 0b101, 0b111, 0b001, 0b111], // 9
+];
+
+// Draws a tile-sized box with the value written in it. Big values spill out to the right.
+pub fn draw_label(value: u16, onto: &mut Image, x: usize, y: usize) {
+    let box_colour = 13; // Light magenta.
+    let text_colour = 15; // White.
+    let background_colour = 0; // Black.
+    let mut plot = |px: usize, py: usize, colour: u8| {
+        if px < onto.width && py < onto.height {
+            onto.data[py * onto.width + px] = colour;
+        }
+    };
+    for i in 0..16 {
+        plot(x + i, y, box_colour);
+        plot(x + i, y + 15, box_colour);
+        plot(x, y + i, box_colour);
+        plot(x + 15, y + i, box_colour);
+    }
+    let text = value.to_string();
+    let text_x = x + 2;
+    let text_y = y + 5;
+    for px in text_x - 1 .. text_x + text.len() * 4 {
+        for py in text_y - 1 .. text_y + 6 {
+            plot(px, py, background_colour);
+        }
+    }
+    for (index, digit) in text.bytes().enumerate() {
+        let rows = DIGITS[(digit - b'0') as usize];
+        for (row_index, row) in rows.iter().enumerate() {
+            for column in 0..3 {
+                if row & (0b100 >> column) != 0 {
+                    plot(text_x + index * 4 + column, text_y + row_index, text_colour);
                 }
             }
+        }
+    }
+}
+
+// Draws a switch or door's link to the tile it targets: Boxes on both, joined by a line. Either end may be off the edges.
+fn draw_link(onto: &mut Image, from: (i32, i32), to: (i32, i32)) {
+    let link_colour = 14; // Yellow.
+    let tile_size = TILE_SIZE as i32;
+    let mut plot = |px: i32, py: i32| {
+        if px >= 0 && py >= 0 && (px as usize) < onto.width && (py as usize) < onto.height {
+            onto.data[py as usize * onto.width + px as usize] = link_colour;
+        }
+    };
+    for (tile_x, tile_y) in [from, to] {
+        for i in 2 .. tile_size - 2 {
+            plot(tile_x * tile_size + i, tile_y * tile_size + 2);
+            plot(tile_x * tile_size + i, tile_y * tile_size + tile_size - 3);
+            plot(tile_x * tile_size + 2, tile_y * tile_size + i);
+            plot(tile_x * tile_size + tile_size - 3, tile_y * tile_size + i);
+        }
+    }
+    // A dotted line between their centres.
+    let centre = |(tile_x, tile_y): (i32, i32)| (tile_x * tile_size + tile_size / 2, tile_y * tile_size + tile_size / 2);
+    let (from_x, from_y) = centre(from);
+    let (to_x, to_y) = centre(to);
+    let steps = (to_x - from_x).abs().max((to_y - from_y).abs()).max(1);
+    for step in (0 ..= steps).step_by(2) {
+        plot(from_x + (to_x - from_x) * step / steps, from_y + (to_y - from_y) * step / steps);
+    }
+}
 
-            // TODO sprites?
+// Draws where part of the image may be off the edges.
+fn draw_clipped(sprite: &Image, onto: &mut Image, x: i32, y: i32) {
+    for sprite_y in 0 .. sprite.height {
+        let out_y = y + sprite_y as i32;
+        if out_y < 0 || out_y >= onto.height as i32 { continue }
+        for sprite_x in 0 .. sprite.width {
+            let out_x = x + sprite_x as i32;
+            if out_x < 0 || out_x >= onto.width as i32 { continue }
+            let colour = sprite.data[sprite_y * sprite.width + sprite_x];
+            if colour != palette::CLEAR_INDEX {
+                onto.data[out_y as usize * onto.width + out_x as usize] = colour;
+            }
         }
     }
-    map_image
 }
 
-fn draw(sprite: &Image, onto: &mut Image, x: usize, y: usize) {
+// Draws a sprite with its hit box outlined, and a cross at the object's position (which the origin is relative to).
+// The image is enlarged if needed so everything fits.
+pub fn render_sprite_debug(sprite: &Sprite) -> Image {
+    let width = sprite.image.width as i32;
+    let height = sprite.image.height as i32;
+    let left = 0.min(sprite.origin_x).min(sprite.clip_left) - 1; // Make room for the cross.
+    let top = 0.min(sprite.origin_y).min(sprite.clip_top) - 1;
+    let right = 0.max(sprite.origin_x + width - 1).max(sprite.clip_right) + 1;
+    let bottom = 0.max(sprite.origin_y + height - 1).max(sprite.clip_bottom) + 1;
+    let mut image = Image::empty((right - left + 1) as usize, (bottom - top + 1) as usize);
+    draw(&sprite.image, &mut image, (sprite.origin_x - left) as usize, (sprite.origin_y - top) as usize);
+
+    let mut plot = |x: i32, y: i32, colour: u8| {
+        image.data[((y - top) * (right - left + 1) + (x - left)) as usize] = colour;
+    };
+    let hit_box_colour = 12; // Light red.
+    for x in sprite.clip_left ..= sprite.clip_right {
+        plot(x, sprite.clip_top, hit_box_colour);
+        plot(x, sprite.clip_bottom, hit_box_colour);
+    }
+    for y in sprite.clip_top ..= sprite.clip_bottom {
+        plot(sprite.clip_left, y, hit_box_colour);
+        plot(sprite.clip_right, y, hit_box_colour);
+    }
+    let origin_colour = 14; // Yellow.
+    for (x, y) in [(0, 0), (-1, 0), (1, 0), (0, -1), (0, 1)] {
+        plot(x, y, origin_colour);
+    }
+    image
+}
+
+pub fn draw(sprite: &Image, onto: &mut Image, x: usize, y: usize) {
     for sprite_y in 0 .. sprite.height {
         for sprite_x in 0 .. sprite.width {
             let out_offset = (y + sprite_y) * onto.width + (x + sprite_x);
             let colour = sprite.data[sprite_y * sprite.width + sprite_x];
-            let alpha = colour & 0xff;
-            if alpha == 0xff {
-                onto.data[out_offset] = colour;
-            } else if alpha == 0 {
-                // Do nothing, it's clear.
-            } else {
-                // Its an mixed opacity... This shouldn't happen in the context of Keen mapping,
-                // so simply show the new colour.
+            if colour != palette::CLEAR_INDEX {
                 onto.data[out_offset] = colour;
             }
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_cycle() {
+        // Tile 1 leads into 2 -> 3 -> 2 ..., and 5 stops at 6.
+        let times = [0, 4, 2, 3, 0, 5, 0];
+        let nexts = [None, Some(2), Some(3), Some(2), None, Some(6), None];
+        let cycle = |start| Cycle::new(start, |t| times.get(t).copied(), |t| nexts.get(t).copied().flatten());
+
+        let looping = cycle(1);
+        assert_eq!(looping.prefix_and_loop_tics(), (4, 5));
+        assert_eq!([0, 3, 4, 5, 6, 7, 9, 14].map(|t| looping.tile_at(t)), [1, 1, 2, 2, 3, 3, 2, 2]);
+        let mut times = BTreeSet::new();
+        looping.change_times(4, 9, &mut times);
+        assert_eq!(times.into_iter().collect::<Vec<_>>(), vec![4, 6]);
+
+        let stopping = cycle(5);
+        assert_eq!(stopping.loop_start, None);
+        assert_eq!([0, 4, 5, 100].map(|t| stopping.tile_at(t)), [5, 5, 6, 6]);
+        assert_eq!(cycle(0).frames.len(), 1);
+    }
+
+    #[test]
+    fn test_loop_tics() {
+        // Tiles 1 and 2 loop every 500 tics, 3 and 4 every 499, and 5 and 6 every 2.
+        let times = [0, 250, 250, 250, 249, 1, 1];
+        let nexts = [None, Some(2), Some(1), Some(4), Some(3), Some(6), Some(5)];
+        let cycle = |start| Cycle::new(start, |t| times.get(t).copied(), |t| nexts.get(t).copied().flatten());
+        let backgrounds: HashMap<u16, Cycle> = (1..7).map(|t| (t, cycle(t as usize))).collect();
+        let map = |row: &[u16]| Map {
+            index: 0,
+            name: String::new(),
+            width: row.len(),
+            height: 1,
+            tiles: vec![row.iter().map(|b| MapTile { background: *b, foreground: None, info: None }).collect()],
+        };
+        let options = RenderOptions::default();
+        let foregrounds = HashMap::new();
+        assert_eq!(loop_tics(&map(&[3, 5, 5]), &options, &backgrounds, &foregrounds), 998); // They line up in time.
+        // Otherwise it's as many loops of the most used tiles as fit.
+        assert_eq!(loop_tics(&map(&[1, 1, 3]), &options, &backgrounds, &foregrounds), 1000);
+        assert_eq!(loop_tics(&map(&[1, 3, 3, 5]), &options, &backgrounds, &foregrounds), 998);
+    }
+}
@@ -0,0 +1,171 @@
+// This is a basic OPL2 (AdLib) FM synthesiser, good enough to hear what the sounds and music are.
+// It isn't cycle accurate: Envelopes are linear in decibels, and tremolo, vibrato, key scaling and rhythm mode are ignored.
+// https://moddingwiki.shikadi.net/wiki/OPL_chip
+
+pub const SAMPLE_RATE: u32 = 44100;
+
+const CHIP_RATE: f64 = 49716.0; // The OPL2's own sample rate, which frequency numbers are relative to.
+const SILENT: f64 = 96.0; // Attenuation in dB at which an operator can't be heard.
+const MODULATOR_SLOTS: [usize; 9] = [0, 1, 2, 8, 9, 10, 16, 17, 18]; // Carriers are these + 3.
+const MULTIPLIERS: [f64; 16] = [0.5, 1.0, 2.0, 3.0, 4.0, 5.0, 6.0, 7.0, 8.0, 9.0, 10.0, 10.0, 12.0, 12.0, 15.0, 15.0];
+
+#[derive(Clone, Copy, PartialEq)]
+enum Stage {
+    Attack,
+    Decay,
+    Sustain,
+    Release,
+}
+
+#[derive(Clone, Copy)]
+struct Operator {
+    phase: f64, // In cycles.
+    attenuation: f64, // Envelope, in dB.
+    stage: Stage,
+}
+
+pub struct Opl {
+    registers: [u8; 256],
+    operators: [Operator; 22], // Indexed by slot, some of which don't exist.
+    feedback: [[f64; 2]; 9], // The last two modulator outputs per channel.
+    sine: Vec<f64>,
+}
+
+impl Opl {
+    pub fn new() -> Self {
+        const TABLE_LEN: usize = 1024;
+        Opl {
+            registers: [0; 256],
+            operators: [Operator { phase: 0.0, attenuation: SILENT, stage: Stage::Release }; 22],
+            feedback: [[0.0; 2]; 9],
+            sine: (0..TABLE_LEN).map(|i| (i as f64 / TABLE_LEN as f64 * std::f64::consts::TAU).sin()).collect(),
+        }
+    }
+
+    pub fn write(&mut self, register: u8, value: u8) {
+        let register = register as usize;
+        if (0xb0..=0xb8).contains(&register) {
+            let channel = register - 0xb0;
+            let was_on = self.registers[register] & 0x20 != 0;
+            let is_on = value & 0x20 != 0;
+            let slots = [MODULATOR_SLOTS[channel], MODULATOR_SLOTS[channel] + 3];
+            if is_on && !was_on {
+                for slot in slots {
+                    self.operators[slot].phase = 0.0;
+                    self.operators[slot].stage = Stage::Attack;
+                }
+            } else if was_on && !is_on {
+                for slot in slots {
+                    self.operators[slot].stage = Stage::Release;
+                }
+            }
+        }
+        self.registers[register] = value;
+    }
+
+    // Renders this many samples at SAMPLE_RATE.
+    pub fn generate(&mut self, samples: usize, output: &mut Vec<i16>) {
+        const VOLUME: f64 = 8000.0; // Per channel, leaving headroom for several playing at once.
+        for _ in 0..samples {
+            let mut mix = 0.0;
+            for channel in 0..9 {
+                mix += self.channel_sample(channel);
+            }
+            output.push((mix * VOLUME).clamp(i16::MIN as f64, i16::MAX as f64) as i16);
+        }
+    }
+
+    fn channel_sample(&mut self, channel: usize) -> f64 {
+        let modulator = MODULATOR_SLOTS[channel];
+        let carrier = modulator + 3;
+        if self.operators[modulator].attenuation >= SILENT && self.operators[carrier].attenuation >= SILENT
+            && self.operators[carrier].stage == Stage::Release {
+            return 0.0 // Skip silent channels, as most of them are most of the time.
+        }
+        let fnum = self.registers[0xa0 + channel] as usize + ((self.registers[0xb0 + channel] as usize & 3) << 8);
+        let block = (self.registers[0xb0 + channel] >> 2) & 7;
+        let frequency = fnum as f64 * CHIP_RATE / (1 << (20 - block)) as f64;
+        let feedback_level = (self.registers[0xc0 + channel] >> 1) & 7;
+        let is_additive = self.registers[0xc0 + channel] & 1 != 0;
+
+        // The modulator can modulate itself, by up to 2 cycles.
+        let [previous, last] = self.feedback[channel];
+        let feedback = if feedback_level == 0 { 0.0 } else { (previous + last) * 2f64.powi(feedback_level as i32 - 7) };
+        let modulator_out = self.operator_sample(modulator, frequency, feedback);
+        self.feedback[channel] = [last, modulator_out];
+        if is_additive {
+            modulator_out + self.operator_sample(carrier, frequency, 0.0)
+        } else {
+            // The modulator shifts the carrier's phase by up to 4 cycles.
+            self.operator_sample(carrier, frequency, modulator_out * 4.0)
+        }
+    }
+
+    fn operator_sample(&mut self, slot: usize, frequency: f64, modulation: f64) -> f64 {
+        let characteristic = self.registers[0x20 + slot];
+        let level = self.registers[0x40 + slot] & 0x3f;
+        let attack_rate = self.registers[0x60 + slot] >> 4;
+        let decay_rate = self.registers[0x60 + slot] & 0xf;
+        let sustain_level = (self.registers[0x80 + slot] >> 4) as f64 * 3.0;
+        let release_rate = self.registers[0x80 + slot] & 0xf;
+        let waveform = if self.registers[0x01] & 0x20 != 0 { self.registers[0xe0 + slot] & 3 } else { 0 };
+        let is_sustained = characteristic & 0x20 != 0;
+        let multiplier = MULTIPLIERS[(characteristic & 0xf) as usize];
+
+        let operator = &mut self.operators[slot];
+        match operator.stage {
+            Stage::Attack => {
+                operator.attenuation -= SILENT / attack_samples(attack_rate);
+                if operator.attenuation <= 0.0 {
+                    operator.attenuation = 0.0;
+                    operator.stage = Stage::Decay;
+                }
+            },
+            Stage::Decay => {
+                operator.attenuation += SILENT / decay_samples(decay_rate);
+                if operator.attenuation >= sustain_level {
+                    operator.attenuation = sustain_level;
+                    operator.stage = Stage::Sustain;
+                }
+            },
+            Stage::Sustain => {
+                if !is_sustained { // Percussive sounds carry on fading while the key is held.
+                    operator.attenuation += SILENT / decay_samples(release_rate);
+                }
+            },
+            Stage::Release => operator.attenuation += SILENT / decay_samples(release_rate),
+        }
+        operator.attenuation = operator.attenuation.min(SILENT);
+
+        let phase = operator.phase + modulation;
+        operator.phase = (operator.phase + frequency * multiplier / SAMPLE_RATE as f64).fract();
+        let attenuation = operator.attenuation + level as f64 * 0.75;
+        if attenuation >= SILENT { return 0.0 }
+        let fraction = phase.rem_euclid(1.0);
+        let sine = self.sine[(fraction * self.sine.len() as f64) as usize % self.sine.len()];
+        let wave = match waveform {
+            0 => sine,
+            1 => sine.max(0.0), // Half sine.
+            2 => sine.abs(),
+            _ => if fraction % 0.5 < 0.25 { sine.abs() } else { 0.0 }, // Quarter sine.
+        };
+        wave * 10f64.powf(-attenuation / 20.0)
+    }
+}
+
+// How long it takes to go from silent to full volume. Rate 15 is instant, 0 never happens.
+fn attack_samples(rate: u8) -> f64 {
+    match rate {
+        0 => f64::INFINITY,
+        15 => 1.0,
+        _ => (2826.24 / (1 << (rate - 1)) as f64 * SAMPLE_RATE as f64 / 1000.0).max(1.0),
+    }
+}
+
+// How long it takes to fade from full volume to silent.
+fn decay_samples(rate: u8) -> f64 {
+    match rate {
+        0 => f64::INFINITY,
+        _ => (39280.64 / (1 << (rate - 1)) as f64 * SAMPLE_RATE as f64 / 1000.0).max(1.0),
+    }
+}
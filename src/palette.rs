@@ -18,3 +18,6 @@ pub const PALETTE: [u32; 16] = [
 ];
 
 pub const CLEAR: u32 = 0;
+
+// Images hold indices into PALETTE, and this for masked out pixels.
+pub const CLEAR_INDEX: u8 = 16;
@@ -1,28 +1,127 @@
 // This file's responsible for parsing the raw file data into types:
 
-use anyhow::Result;
+use crate::Error;
+use crate::Game;
 use crate::versions;
+use crate::parse_audio;
 use crate::parse_graphics;
 use crate::parse_maps;
-use crate::export;
+use crate::source::ByteSource;
+use crate::tile_info;
 
-pub fn parse(exe: &[u8], graph_data: &[u8], maps: &[u8]) -> Result<()> {
-    println!("Parsing...");
+// The game's files, already in memory. Game decodes everything, so all of them are read anyway. To leave EGAGRAPH and
+// GAMEMAPS on disk and only read the parts that are decoded, see lazy::LazyGame.
+pub struct Files<'a> {
+    pub exe: Option<&'a [u8]>, // Unpacked, if it was LZEXE'd. Only needed for the tables that aren't in `tables`.
+    pub graph: &'a [u8],
+    pub maps: &'a [u8],
+    pub audio: Option<&'a [u8]>,
+    pub tables: ExternalTables<'a>,
+    pub best_effort: bool, // Skip broken maps and graphics chunks, listing them in Game::problems, rather than failing.
+    pub keep_chunks: bool, // Keep the decompressed graphics chunks in Graphics::chunks, to repack them.
+}
+
+// Tables that were given as their own files (eg by mods or source ports), which take priority over the exe's.
+#[derive(Default, Clone, Copy)]
+pub struct ExternalTables<'a> {
+    pub map_head: Option<&'a [u8]>,
+    pub graph_head: Option<&'a [u8]>,
+    pub graph_dict: Option<&'a [u8]>,
+    pub audio_head: Option<&'a [u8]>,
+    pub audio_dict: Option<&'a [u8]>,
+}
 
-    // Extract necessary tables from the exe:
-    let offsets = versions::determine(exe.len())?;
-    let map_head = &exe[offsets.map_head_offset .. offsets.map_head_offset + offsets.map_head_len];
-    let graph_head = &exe[offsets.graph_head_offset .. offsets.graph_head_offset + offsets.graph_head_len];
-    let graph_dict = &exe[offsets.graph_dict_offset .. offsets.graph_dict_offset + offsets.graph_dict_len];
+pub fn parse(files: &Files) -> Result<Game, Error> {
+    let Tables { detection, map_head, graph_head, graph_dict, tile_info: tile_info_data } = tables(files.exe, &files.graph, files.maps.len(), files.audio, &files.tables)?;
+    let mut problems: Vec<String> = Vec::new();
 
     // Parse all the graphics:
-    let graphics = parse_graphics::parse(&graph_data, &graph_head, &graph_dict)?;
+    let episode = detection.as_ref().and_then(|d| d.version).map(|v| v.episode());
+    let graphics = parse_graphics::parse(files.graph, graph_head, graph_dict, episode, files.keep_chunks, files.best_effort.then_some(&mut problems)).map_err(|e| Error::Graphics(format!("{:#}", e)))?;
+    let tile_info = tile_info(tile_info_data, graphics.tiles_16_unmasked.len(), graphics.tiles_16_masked.len())?;
 
     // Parse the maps:
-    let maps = parse_maps::parse(&maps, map_head)?;
+    let maps = parse_maps::parse(&files.maps, map_head, files.best_effort.then_some(&mut problems)).map_err(|e| Error::Maps(format!("{:#}", e)))?;
+    let rlew_key = map_head.get(..2).map_or(parse_maps::RLEW_KEY, |key| u16::from_le_bytes([key[0], key[1]]));
+    // A MAPHEAD file's tile info is the rest of it, but in the exe it's followed by other things, so only the tile info's
+    // own length is kept.
+    let tile_info_data = match files.tables.map_head {
+        Some(_) => tile_info_data.to_vec(),
+        None => tile_info_data.get(..tile_info::len(graphics.tiles_16_unmasked.len(), graphics.tiles_16_masked.len())).unwrap_or_default().to_vec(),
+    };
+
+    // Parse the audio, if given:
+    let from_exe = |offset: usize, len: usize| -> &[u8] {
+        &files.exe.unwrap()[offset .. offset + len]
+    };
+    let tables = &files.tables;
+    let audio = match files.audio {
+        None => None,
+        Some(audio_data) => {
+            let audio_offsets = detection.as_ref().and_then(|d| d.offsets.audio);
+            let (audio_head, audio_dict) = match (tables.audio_head, tables.audio_dict, audio_offsets) {
+                (Some(audio_head), Some(audio_dict), _) => (audio_head, audio_dict),
+                (audio_head, audio_dict, Some(offsets)) => (
+                    audio_head.unwrap_or_else(|| from_exe(offsets.head_offset, offsets.head_len)),
+                    audio_dict.unwrap_or_else(|| from_exe(offsets.dict_offset, offsets.dict_len)),
+                ),
+                _ => return Err(Error::NoAudioTables),
+            };
+            Some(parse_audio::parse(audio_data, audio_head, audio_dict).map_err(|e| Error::Audio(format!("{:#}", e)))?)
+        },
+    };
+
+    Ok(Game { detection, graphics, maps, tile_info, rlew_key, tile_info_data, audio, problems })
+}
+
+// The graphics and map tables, from their own files if given, otherwise from the exe, which is recognised to find them.
+pub(crate) struct Tables<'a> {
+    pub detection: Option<versions::Detection>, // Which exe it was, or None if all the tables were given as files.
+    pub map_head: &'a [u8],
+    pub graph_head: &'a [u8],
+    pub graph_dict: &'a [u8],
+    pub tile_info: &'a [u8], // Whatever follows the map offsets, which has no length of its own.
+}
+
+// The graphics are only read from to recognise the exe, so they can be anything that reads a piece at a time.
+pub(crate) fn tables<'a>(exe: Option<&'a [u8]>, graph: &dyn ByteSource, maps_len: usize, audio: Option<&[u8]>, tables: &ExternalTables<'a>) -> Result<Tables<'a>, Error> {
+    // The exe is only needed if some of the tables weren't given as files:
+    let has_graphics_tables = tables.map_head.is_some() && tables.graph_head.is_some() && tables.graph_dict.is_some();
+    let has_audio_tables = audio.is_none() || (tables.audio_head.is_some() && tables.audio_dict.is_some());
+    let detection = if has_graphics_tables && has_audio_tables {
+        None
+    } else {
+        let Some(exe) = exe else { return Err(Error::NeedsExe) };
+        let given = versions::GivenGraphTables { head: tables.graph_head, dict: tables.graph_dict, map_head: tables.map_head };
+        Some(versions::determine(exe, graph, maps_len, audio, given).map_err(|e| Error::UnknownExe(format!("{:#}", e)))?)
+    };
+    let from_exe = |offset: usize, len: usize| -> &'a [u8] {
+        &exe.unwrap()[offset .. offset + len]
+    };
+
+    // Use the external tables if given, otherwise extract the necessary tables from the exe:
+    let (map_head, graph_head, graph_dict) = match &detection {
+        None => (tables.map_head.unwrap(), tables.graph_head.unwrap(), tables.graph_dict.unwrap()),
+        Some(detection) => {
+            let offsets = &detection.offsets;
+            (
+                tables.map_head.unwrap_or_else(|| from_exe(offsets.map_head_offset, offsets.map_head_len)),
+                tables.graph_head.unwrap_or_else(|| from_exe(offsets.graph_head_offset, offsets.graph_head_len)),
+                tables.graph_dict.unwrap_or_else(|| from_exe(offsets.graph_dict_offset, offsets.graph_dict_len)),
+            )
+        },
+    };
+    // The tile info follows the map offsets, in both MAPHEAD files and the exe:
+    let tile_info: &[u8] = match (tables.map_head, &detection) {
+        (Some(map_head), _) => map_head.get(parse_maps::MAP_HEAD_LEN..).unwrap_or_default(),
+        (None, Some(detection)) => exe.unwrap().get(detection.offsets.map_head_offset + parse_maps::MAP_HEAD_LEN..).unwrap_or_default(),
+        (None, None) => &[],
+    };
+    Ok(Tables { detection, map_head, graph_head, graph_dict, tile_info })
+}
 
-    // Export:
-    export::export(&graphics, &maps)?;
-    
-    Ok(())
+// Parses the tile info, if there is any. The exe doesn't say how long it is, so that needs the tile counts.
+pub(crate) fn tile_info(data: &[u8], background_count: usize, foreground_count: usize) -> Result<Option<tile_info::TileInfo>, Error> {
+    if data.len() < tile_info::len(background_count, foreground_count) { return Ok(None) }
+    Ok(Some(tile_info::parse(data, background_count, foreground_count).map_err(|e| Error::Maps(format!("{:#}", e)))?))
 }
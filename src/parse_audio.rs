@@ -0,0 +1,149 @@
+// This is responsible for parsing the AUDIO file into the PC speaker sounds, AdLib sounds and music.
+// https://moddingwiki.shikadi.net/wiki/AudioT_Format
+// The chunks go: every sound in PC speaker form, then every sound again in AdLib form, then again as digitised sound
+// (which Keen doesn't use so they're empty), then the music tracks. Each chunk is huffman compressed with a length header.
+
+use crate::huffman;
+use crate::versions;
+use anyhow::{Result, bail};
+
+pub struct Audio {
+    pub pc_sounds: Vec<Option<PcSound>>,
+    pub adlib_sounds: Vec<Option<AdlibSound>>,
+    pub music: Vec<Option<Music>>,
+}
+
+// https://moddingwiki.shikadi.net/wiki/Inverse_Frequency_Sound_format
+// Each byte is played for 1/140th of a second, and is the PC speaker timer's divisor / 60, or 0 for silence.
+pub struct PcSound {
+    pub priority: u16,
+    pub data: Vec<u8>,
+}
+
+// https://moddingwiki.shikadi.net/wiki/Adlib_sound_effect
+// Each byte is played for 1/140th of a second on one AdLib channel, and is the low 8 bits of the frequency number, or 0 for silence.
+pub struct AdlibSound {
+    pub priority: u16,
+    pub instrument: Instrument,
+    pub block: u8, // Octave.
+    pub data: Vec<u8>,
+}
+
+// The OPL2 register values for the two operators of the channel.
+pub struct Instrument {
+    pub modulator_char: u8, // 0x20: Tremolo, vibrato, sustain, key scale rate, multiplier.
+    pub carrier_char: u8,
+    pub modulator_scale: u8, // 0x40: Key scale level, output level.
+    pub carrier_scale: u8,
+    pub modulator_attack: u8, // 0x60: Attack, decay.
+    pub carrier_attack: u8,
+    pub modulator_sustain: u8, // 0x80: Sustain level, release.
+    pub carrier_sustain: u8,
+    pub modulator_wave: u8, // 0xE0: Waveform.
+    pub carrier_wave: u8,
+}
+
+// https://moddingwiki.shikadi.net/wiki/IMF_Format
+// Keen's music chunks are type-1 IMF files: A length, then register+value+delay commands played at 560Hz.
+pub struct Music {
+    pub imf: Vec<u8>,
+}
+
+impl Music {
+    // The register, value, and how many 560ths of a second to wait after writing it.
+    pub fn commands(&self) -> impl Iterator<Item = (u8, u8, u16)> + '_ {
+        let len = u16::from_le_bytes([self.imf[0], self.imf[1]]) as usize;
+        self.imf[2..].get(..len).unwrap_or(&self.imf[2..])
+            .chunks_exact(4)
+            .map(|c| (c[0], c[1], u16::from_le_bytes([c[2], c[3]])))
+    }
+}
+
+pub fn parse(audio_data: &[u8], audio_head: &[u8], audio_dict: &[u8]) -> Result<Audio> {
+    let offsets = versions::audio_head_offsets(audio_head);
+    if offsets.len() < 2 || offsets[0] != 0 { bail!("Audio head does not start with 0!") }
+    if *offsets.last().unwrap() != audio_data.len() { bail!("Audio head does not match the audio file size!") }
+    let dict = huffman::parse_dict(audio_dict)?;
+    let chunks: Vec<Vec<u8>> = offsets.windows(2).map(|w| {
+        // Empty chunks have the same offset as the next, or in Keen 4's case a later one.
+        let Some(chunk) = audio_data.get(w[0] .. w[1]) else { return vec![] };
+        if chunk.len() < 4 { return vec![] }
+        let len = u32::from_le_bytes(chunk[0..4].try_into().unwrap()) as usize;
+        huffman::decompress(&chunk[4..], &dict, len)
+    }).collect();
+
+    // The number of sounds differs per game, but there's 3 copies of them before the music starts.
+    let music_start = chunks.iter().position(|c| is_music(c)).unwrap_or(chunks.len());
+    let sound_count = music_start / 3;
+    Ok(Audio {
+        pc_sounds: chunks[.. sound_count].iter().map(|c| parse_pc_sound(c)).collect(),
+        adlib_sounds: chunks[sound_count .. sound_count * 2].iter().map(|c| parse_adlib_sound(c)).collect(),
+        music: chunks[sound_count * 3 ..].iter().map(|c| parse_music(c)).collect(),
+    })
+}
+
+// Music is its length then 4-byte commands, whereas sounds start with a 4-byte length then a priority.
+fn is_music(data: &[u8]) -> bool {
+    if data.len() < 2 { return false }
+    let len = u16::from_le_bytes([data[0], data[1]]) as usize;
+    len > 0 && len.is_multiple_of(4) && len + 2 <= data.len() && len + 2 + 4 > data.len()
+}
+
+// Sounds start with their length and priority.
+fn sound_header(data: &[u8]) -> Option<(usize, u16)> {
+    let len = u32::from_le_bytes(data.get(0..4)?.try_into().unwrap()) as usize;
+    let priority = u16::from_le_bytes(data.get(4..6)?.try_into().unwrap());
+    Some((len, priority))
+}
+
+fn parse_pc_sound(data: &[u8]) -> Option<PcSound> {
+    let (len, priority) = sound_header(data)?;
+    let data = data.get(6 .. 6 + len)?.to_vec();
+    Some(PcSound { priority, data })
+}
+
+fn parse_adlib_sound(data: &[u8]) -> Option<AdlibSound> {
+    let (len, priority) = sound_header(data)?;
+    let i = data.get(6 .. 6 + 16)?;
+    let instrument = Instrument {
+        modulator_char: i[0],
+        carrier_char: i[1],
+        modulator_scale: i[2],
+        carrier_scale: i[3],
+        modulator_attack: i[4],
+        carrier_attack: i[5],
+        modulator_sustain: i[6],
+        carrier_sustain: i[7],
+        modulator_wave: i[8],
+        carrier_wave: i[9],
+        // Then the connection, voice, mode, and 3 unused bytes, which sound effects don't use.
+    };
+    let block = *data.get(22)?;
+    let data = data.get(23 .. 23 + len)?.to_vec();
+    Some(AdlibSound { priority, instrument, block, data })
+}
+
+fn parse_music(data: &[u8]) -> Option<Music> {
+    if !is_music(data) { return None }
+    Some(Music { imf: data.to_vec() })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_keen4() {
+        let exe = std::fs::read("data/keen4/keen4.exe").unwrap();
+        let audio_data = std::fs::read("data/keen4/audio.ck4").unwrap();
+        let (head_offset, dict_offset) = (146416, 230134);
+        let audio = parse(&audio_data, &exe[head_offset .. head_offset + 652], &exe[dict_offset .. dict_offset + 1024]).unwrap();
+        assert_eq!(audio.pc_sounds.len(), 52);
+        assert_eq!(audio.adlib_sounds.len(), 52);
+        assert_eq!(audio.music.len(), 6);
+        assert!(audio.pc_sounds.iter().all(|s| s.is_some()));
+        let adlib = audio.adlib_sounds[0].as_ref().unwrap();
+        assert_eq!((adlib.block, adlib.data.as_slice()), (3, [0x2c; 4].as_slice()));
+        assert!(audio.music.iter().all(|m| m.as_ref().is_some_and(|m| m.commands().count() > 100)));
+    }
+}
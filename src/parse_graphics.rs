@@ -2,121 +2,235 @@
 
 use crate::images;
 use crate::egagraph;
-use anyhow::Result;
+use crate::parse_misc::{self, MiscChunk};
+use crate::source::ByteSource;
+use crate::versions::{ChunkLayout, Episode};
+use anyhow::{Result, bail};
+use std::iter;
 
-pub fn parse(graph_data: &[u8], graph_head: &[u8], graph_dict: &[u8]) -> Result<Graphics> {
-    println!("Parsing graphics...");
+// The bytes in each kind of tile, which is how long their chunks are as they have no length header.
+const TILE_8_LEN: usize = 32;
+const MASKED_TILE_8_LEN: usize = 40;
+pub(crate) const TILE_16_LEN: usize = 128;
+pub(crate) const MASKED_TILE_16_LEN: usize = 160;
 
-    let mut chunks = egagraph::ChunkIterator::new(&graph_data, &graph_head, &graph_dict)?;
+// In best effort mode (when problems is given), broken chunks are read as empty and what went wrong is added to problems,
+// rather than failing.
+// The episode says how many tiles there are, or it's worked out from how many chunks there are if it's not known.
+// The data can be anything that reads a piece at a time (see ByteSource), though every chunk is read, as they're all
+// decoded. To only decode what's needed, eg for a single map, see lazy::LazyGame instead.
+// The decompressed chunks are only kept in Graphics::chunks when keep_chunks is set, as only repacking needs them.
+pub fn parse<S: ByteSource + Sync>(graph_data: S, graph_head: &[u8], graph_dict: &[u8], episode: Option<Episode>, keep_chunks: bool, problems: Option<&mut Vec<String>>) -> Result<Graphics> {
+    let mut chunk_iterator = egagraph::ChunkIterator::new(graph_data, graph_head, graph_dict)?;
+    if keep_chunks {
+        chunk_iterator = chunk_iterator.keeping_chunks();
+    }
+    let mut chunks = Reader { chunks: chunk_iterator, problems };
     let mut graphics = Graphics::new();
     
     // Go through the chunks in order:
-    let unmasked_picture_table = parse_picture_table(&chunks.next());
-    let masked_picture_table = parse_picture_table(&chunks.next());
-    let sprite_table = parse_sprite_table(&chunks.next());
+    let unmasked_picture_table = chunks.next("the unmasked picture table")?;
+    let masked_picture_table = chunks.next("the masked picture table")?;
+    let sprite_table = chunks.next("the sprite table")?;
+    let tables = Tables::new(&unmasked_picture_table, &masked_picture_table, &sprite_table, episode, chunks.chunks.chunk_count())?;
+    let layout = tables.layout;
+
+    // Every chunk can be decoded on its own, so the rest are all decoded at once across the cores, then read in order.
+    let lengths: Vec<Option<usize>> = iter::repeat_n(None, ChunkLayout::FONTS + layout.pictures + layout.masked_pictures + layout.sprites)
+        .chain([Some(ChunkLayout::TILES_8 * TILE_8_LEN), Some(ChunkLayout::MASKED_TILES_8 * MASKED_TILE_8_LEN)])
+        .chain(iter::repeat_n(Some(TILE_16_LEN), layout.tiles_16))
+        .chain(iter::repeat_n(Some(MASKED_TILE_16_LEN), layout.masked_tiles_16))
+        .chain(iter::repeat_n(None, layout.externs()))
+        .collect();
+    chunks.chunks.decode_ahead(&lengths);
 
-    chunks.next(); // Font a.
-    chunks.next(); // Font b.
-    chunks.next(); // Font c.
+    // Fonts:
+    for index in 0..ChunkLayout::FONTS {
+        let data = chunks.next(&format!("font {}", index))?;
+        graphics.fonts.push(parse_font(&data));
+    }
 
     // Unmasked pictures:
-    for p in unmasked_picture_table.iter() {
-        let data = chunks.next();
-        let image: Option<images::Image> =
-            if p.width_div_8 == 0 || p.height == 0 || data.len() == 0 {
-                None
-            } else {
-                Some(images::parse_ega_rgbi(&data, p.width_div_8 as usize, p.height as usize))
-            };
+    for (index, entry) in tables.pictures.iter().enumerate() {
+        let what = format!("unmasked picture {}", index);
+        let data = chunks.next(&what)?;
+        let image = chunks.recover(&what, parse_picture(&data, entry, false))?;
         graphics.pictures_unmasked.push(image);
     }
 
     // Masked pictures:
-    for p in masked_picture_table.iter() {
-        let data = chunks.next();
-        let image: Option<images::Image> = 
-            if p.width_div_8 == 0 || p.height == 0 || data.len() == 0 {
-                None
-            } else {
-                Some(images::parse_ega_rgbim(&data, p.width_div_8 as usize, p.height as usize))
-            };
+    for (index, entry) in tables.masked_pictures.iter().enumerate() {
+        let what = format!("masked picture {}", index);
+        let data = chunks.next(&what)?;
+        let image = chunks.recover(&what, parse_picture(&data, entry, true))?;
         graphics.pictures_masked.push(image);
     }
 
     // Sprites:
-    for s in sprite_table.iter() {
-        let data = chunks.next();
-        let image: Option<images::Image> = 
-            if s.width_div_8 == 0 || s.height == 0 || data.len() == 0 {
-                None
-            } else {
-                Some(images::parse_ega_rgbim(&data, s.width_div_8 as usize, s.height as usize))
-            };
-        graphics.sprites.push(image);
+    for (index, entry) in tables.sprites.iter().enumerate() {
+        let what = format!("sprite {}", index);
+        let data = chunks.next(&what)?;
+        let sprite = chunks.recover(&what, parse_sprite(&data, entry))?;
+        graphics.sprites.push(sprite);
     }
 
     // Unmasked 8x8 tiles:
     // These are all stored in one chunk that has no length header.
     // These are not used in-game, should we bother?
-    let unmasked_tiles_8 = chunks.next_with_auto_length();
-    for t in unmasked_tiles_8.chunks_exact(32) {
-        let image = images::parse_ega_rgbi(&t, 1, 8);
+    let unmasked_tiles_8 = chunks.next_with_length("the unmasked 8x8 tiles", ChunkLayout::TILES_8 * TILE_8_LEN)?;
+    for t in unmasked_tiles_8.chunks_exact(TILE_8_LEN) {
+        let image = images::parse_ega_rgbi(t, 1, 8);
         graphics.tiles_8_unmasked.push(image);
     }
 
     // Masked 8x8 tiles:
     // These are stored as above, no length header.
-    let masked_tiles_8 = chunks.next_with_auto_length();
-    for t in masked_tiles_8.chunks_exact(40) {
-        let image = images::parse_ega_rgbim(&t, 1, 8);
+    let masked_tiles_8 = chunks.next_with_length("the masked 8x8 tiles", ChunkLayout::MASKED_TILES_8 * MASKED_TILE_8_LEN)?;
+    for t in masked_tiles_8.chunks_exact(MASKED_TILE_8_LEN) {
+        let image = images::parse_ega_rgbim(t, 1, 8);
         graphics.tiles_8_masked.push(image);
     }
 
     // Unmasked 16x16 tiles:
-    // These get a chunk each but the chunks have no header.
-    loop {
-        let chunk = chunks.next_with_auto_length();
-        let len = chunk.len();
-        if 128 <= len && len <= 159 { // Tests a range in case the auto length decoded some extra.
-            let image = images::parse_ega_rgbi(&chunk, 2, 16);
-            graphics.tiles_16_unmasked.push(Some(image));
-        } else if len == 0 { // Empties.
-            graphics.tiles_16_unmasked.push(None);
-        } else { // Found the first masked one.
-            chunks.rewind_once();
-            break
-        }
+    // These get a chunk each but the chunks have no header. Empty ones are left out of the game's data.
+    for index in 0..layout.tiles_16 {
+        let chunk = chunks.next_with_length(&format!("unmasked 16x16 tile {}", index), TILE_16_LEN)?;
+        graphics.tiles_16_unmasked.push(parse_tile_16(&chunk, false));
     }
 
     // Masked 16x16 tiles:
-    loop {
-        let chunk = chunks.next_with_auto_length();
-        let len = chunk.len();
-        if 160 <= len && len <= 160 + 16 { // 16 masked.
-            let image = images::parse_ega_rgbim(&chunk, 2, 16);
-            graphics.tiles_16_masked.push(Some(image));
-        } else if len == 0 { // Ignore empty chunks.
-            graphics.tiles_16_masked.push(None);
-        } else {
-            break // Finished reading tiles, hit the uninteresting chunks at the end now.
+    for index in 0..layout.masked_tiles_16 {
+        let chunk = chunks.next_with_length(&format!("masked 16x16 tile {}", index), MASKED_TILE_16_LEN)?;
+        graphics.tiles_16_masked.push(parse_tile_16(&chunk, true));
+    }
+
+    // Miscellaneous chunks (the externs), which have a length header:
+    for index in 0..layout.externs() {
+        let data = chunks.next(&format!("extern {}", index))?;
+        let chunk = match layout.extern_kind(index) {
+            Some(kind) => parse_misc::parse_kind(&data, kind),
+            None => parse_misc::parse(&data),
+        };
+        match chunk {
+            MiscChunk::Screen(screen) => graphics.text_screens.push(screen),
+            MiscChunk::Article(text) => graphics.articles.push(text),
+            MiscChunk::Terminator(image) => graphics.terminator_texts.push(image),
+            MiscChunk::Demo(demo) => graphics.demos.push(demo),
+            MiscChunk::Unknown => {}, // Eg empty chunks.
         }
     }
 
+    graphics.chunks = chunks.chunks.into_chunks();
     Ok(graphics)
 }
 
+// Reads the chunks, saying what each was meant to be if it's broken. In best effort mode, it notes that and carries on.
+struct Reader<'a, S> {
+    chunks: egagraph::ChunkIterator<S>,
+    problems: Option<&'a mut Vec<String>>,
+}
+
+impl<S: ByteSource> Reader<'_, S> {
+    fn next(&mut self, what: &str) -> Result<Vec<u8>> {
+        let result = self.chunks.next();
+        self.recover(what, result)
+    }
+
+    fn next_with_length(&mut self, what: &str, len: usize) -> Result<Vec<u8>> {
+        let result = self.chunks.next_with_length(len);
+        self.recover(what, result)
+    }
+
+    fn recover<T: Default>(&mut self, what: &str, result: Result<T>) -> Result<T> {
+        match (result, &mut self.problems) {
+            (Ok(data), _) => Ok(data),
+            (Err(error), Some(problems)) => {
+                problems.push(format!("Skipped {}: {:#}", what, error));
+                Ok(T::default())
+            },
+            (Err(error), None) => Err(error.context(format!("Couldn't read {}", what))),
+        }
+    }
+}
+
+// The picture and sprite tables from the first three chunks, and where every kind of chunk is from how long they are.
+pub(crate) struct Tables {
+    pub pictures: Vec<PictureTableEntry>,
+    pub masked_pictures: Vec<PictureTableEntry>,
+    pub sprites: Vec<SpriteTableEntry>,
+    pub layout: ChunkLayout,
+}
+
+impl Tables {
+    // The episode says how many tiles there are, or it's worked out from how many chunks there are if it's not known.
+    pub fn new(picture_table: &[u8], masked_picture_table: &[u8], sprite_table: &[u8], episode: Option<Episode>, chunk_count: usize) -> Result<Self> {
+        let pictures = parse_picture_table(picture_table);
+        let masked_pictures = parse_picture_table(masked_picture_table);
+        let sprites = parse_sprite_table(sprite_table);
+        let layout = match episode {
+            Some(episode) => ChunkLayout::new(episode, pictures.len(), masked_pictures.len(), sprites.len(), chunk_count)?,
+            None => ChunkLayout::guess(pictures.len(), masked_pictures.len(), sprites.len(), chunk_count)?.1,
+        };
+        Ok(Tables { pictures, masked_pictures, sprites, layout })
+    }
+}
+
+// A picture from its chunk, or None if it's empty. Masked ones have a fifth plane, for the mask.
+pub(crate) fn parse_picture(data: &[u8], entry: &PictureTableEntry, masked: bool) -> Result<Option<images::Image>> {
+    let (width_div_8, height) = (entry.width_div_8 as usize, entry.height as usize);
+    if width_div_8 == 0 || height == 0 || data.is_empty() { return Ok(None) }
+    check_room(data, width_div_8 * height * if masked { 5 } else { 4 })?;
+    Ok(Some(if masked { images::parse_ega_rgbim(data, width_div_8, height) } else { images::parse_ega_rgbi(data, width_div_8, height) }))
+}
+
+// A sprite from its chunk, or None if it's empty.
+pub(crate) fn parse_sprite(data: &[u8], entry: &SpriteTableEntry) -> Result<Option<Sprite>> {
+    let (width_div_8, height) = (entry.width_div_8 as usize, entry.height as usize);
+    if width_div_8 == 0 || height == 0 || data.is_empty() { return Ok(None) }
+    check_room(data, width_div_8 * height * 5)?;
+    Ok(Some(Sprite {
+        image: images::parse_ega_rgbim(data, width_div_8, height),
+        origin_x: entry.x_offset,
+        origin_y: entry.y_offset,
+        clip_left: entry.clip_left,
+        clip_top: entry.clip_top,
+        clip_right: entry.clip_right,
+        clip_bottom: entry.clip_bottom,
+        shifts: entry.shifts,
+    }))
+}
+
+// A 16x16 tile from its chunk, which is either empty or exactly the tile's size, as it was read with its length.
+pub(crate) fn parse_tile_16(data: &[u8], masked: bool) -> Option<images::Image> {
+    if data.is_empty() { return None }
+    Some(if masked { images::parse_ega_rgbim(data, 2, 16) } else { images::parse_ega_rgbi(data, 2, 16) })
+}
+
+// Checks the chunk has as many bytes as its table entry says it needs, as a corrupt table could ask for far more.
+fn check_room(data: &[u8], needed: usize) -> Result<()> {
+    if data.len() < needed { bail!("Its chunk is only {} bytes, but its size needs {}!", data.len(), needed) }
+    Ok(())
+}
+
 pub struct Graphics {
+    pub fonts: Vec<Option<Font>>,
     pub pictures_unmasked: Vec<Option<images::Image>>,
     pub pictures_masked: Vec<Option<images::Image>>,
-    pub sprites: Vec<Option<images::Image>>,
+    pub sprites: Vec<Option<Sprite>>,
     pub tiles_8_unmasked: Vec<images::Image>,
     pub tiles_8_masked: Vec<images::Image>,
     pub tiles_16_unmasked: Vec<Option<images::Image>>,
     pub tiles_16_masked: Vec<Option<images::Image>>,
+    pub text_screens: Vec<parse_misc::TextScreen>,
+    pub articles: Vec<String>,
+    pub terminator_texts: Vec<images::Image>,
+    pub demos: Vec<parse_misc::Demo>,
+    pub chunks: Vec<egagraph::Chunk>, // The decompressed chunks everything above came from, for repacking. Only kept if asked for.
 }
 impl Graphics {
-    fn new() -> Self {
+    pub(crate) fn new() -> Self {
         Graphics {
+            fonts: Vec::new(),
             pictures_unmasked: Vec::new(),
             pictures_masked: Vec::new(),
             sprites: Vec::new(),
@@ -124,13 +238,44 @@ impl Graphics {
             tiles_8_masked: Vec::new(),
             tiles_16_unmasked: Vec::new(),
             tiles_16_masked: Vec::new(),
+            text_screens: Vec::new(),
+            articles: Vec::new(),
+            terminator_texts: Vec::new(),
+            demos: Vec::new(),
+            chunks: Vec::new(),
         }
     }
 }
 
-struct PictureTableEntry {
+// A bitmap font, with an image per character (None for those with no width).
+pub struct Font {
+    pub height: usize,
+    pub widths: Vec<usize>,
+    pub glyphs: Vec<Option<images::Image>>,
+}
+
+// https://moddingwiki.shikadi.net/wiki/EGAGraph_Format#Fonts
+// Height, then 256 offsets to each character's data, then 256 widths, then the 1bpp rows.
+fn parse_font(data: &[u8]) -> Option<Font> {
+    const CHARACTERS: usize = 256;
+    const HEADER_LEN: usize = 2 + CHARACTERS * 2 + CHARACTERS;
+    if data.len() < HEADER_LEN { return None } // Empty font chunk.
+    let height = data[0] as usize + ((data[1] as usize) << 8);
+    let offsets = &data[2 .. 2 + CHARACTERS * 2];
+    let widths: Vec<usize> = data[2 + CHARACTERS * 2 .. HEADER_LEN].iter().map(|w| *w as usize).collect();
+    let glyphs: Vec<Option<images::Image>> = offsets.chunks_exact(2).zip(&widths).map(|(o, width)| {
+        let offset = o[0] as usize + ((o[1] as usize) << 8);
+        let len = width.div_ceil(8) * height;
+        if *width == 0 || height == 0 { return None }
+        let glyph_data = data.get(offset .. offset + len)?;
+        Some(images::parse_mono(glyph_data, *width, height))
+    }).collect();
+    Some(Font { height, widths, glyphs })
+}
+
+pub(crate) struct PictureTableEntry {
     width_div_8: u32,
-        height: u32,
+    height: u32,
 }
 fn parse_picture_table(data: &[u8]) -> Vec<PictureTableEntry> {
     // This uses chunks_exact instead of chunks, because the masked picture table isn't the right length.
@@ -140,29 +285,46 @@ fn parse_picture_table(data: &[u8]) -> Vec<PictureTableEntry> {
     }).collect()
 }
 
+// A sprite, with where it's drawn and its hit box. These are in pixels, relative to the object's position.
+pub struct Sprite {
+    pub image: images::Image,
+    pub origin_x: i32, // Where the image's top-left is drawn.
+    pub origin_y: i32,
+    pub clip_left: i32, // Hit box.
+    pub clip_top: i32,
+    pub clip_right: i32,
+    pub clip_bottom: i32,
+    pub shifts: u32, // How many horizontally pre-shifted copies the game makes, for smooth movement.
+}
+
 #[derive(Debug)]
-struct SpriteTableEntry {
+pub(crate) struct SpriteTableEntry {
     width_div_8: u32,
     height: u32,
-    _x_offset: u32,
-    _y_offset: u32,
-    _clip_left: u32,
-    _clip_top: u32,
-    _clip_right: u32,
-    _clip_bottom: u32,
-    _shifts: u32,
+    x_offset: i32,
+    y_offset: i32,
+    clip_left: i32,
+    clip_top: i32,
+    clip_right: i32,
+    clip_bottom: i32,
+    shifts: u32,
 }
 fn parse_sprite_table(data: &[u8]) -> Vec<SpriteTableEntry> {
+    // The offsets and clip box are signed, in the game's 1/16th pixel units, so convert them to pixels.
+    // The game shifts rather than divides, which rounds negative values down rather than towards zero.
+    fn pixels(le: u8, be: u8) -> i32 {
+        (i16::from_le_bytes([le, be]) as i32) >> 4
+    }
     // This uses chunks_exact instead of chunks, because the masked picture table isn't the right length.
     data.chunks_exact(18).map(|c| SpriteTableEntry {
         width_div_8: c[0] as u32 + ((c[1] as u32) << 8),
         height: c[2] as u32 + ((c[3] as u32) << 8),
-        _x_offset: c[4] as u32 + ((c[5] as u32) << 8),
-        _y_offset: c[6] as u32 + ((c[7] as u32) << 8),
-        _clip_left: c[8] as u32 + ((c[9] as u32) << 8),
-        _clip_top: c[10] as u32 + ((c[11] as u32) << 8),
-        _clip_right: c[12] as u32 + ((c[13] as u32) << 8),
-        _clip_bottom: c[14] as u32 + ((c[15] as u32) << 8),
-        _shifts: c[16] as u32 + ((c[17] as u32) << 8),
+        x_offset: pixels(c[4], c[5]),
+        y_offset: pixels(c[6], c[7]),
+        clip_left: pixels(c[8], c[9]),
+        clip_top: pixels(c[10], c[11]),
+        clip_right: pixels(c[12], c[13]),
+        clip_bottom: pixels(c[14], c[15]),
+        shifts: c[16] as u32 + ((c[17] as u32) << 8),
     }).collect()
 }
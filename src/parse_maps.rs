@@ -1,49 +1,142 @@
-// This is responsible for parsing all the maps.
+// This is responsible for parsing all the maps, and packing them back into a GAMEMAPS.
 
 use crate::carmackization;
 use crate::rlew;
-use anyhow::{Result, bail};
-
-pub fn parse(gamemaps: &[u8], map_head_data: &[u8]) -> Result<Vec<Map>> {
-    println!("Parsing maps...");
+use crate::source::ByteSource;
+use crate::threads;
+use anyhow::{Context, Result, bail};
 
+// In best effort mode (when problems is given), broken maps are left out and what went wrong is added to problems,
+// rather than failing. Each map keeps its number from the map head, so the ones after still have theirs.
+// Gamemaps can be anything that reads a piece at a time (see ByteSource), though every map is read.
+pub fn parse(gamemaps: &(impl ByteSource + Sync), map_head_data: &[u8], mut problems: Option<&mut Vec<String>>) -> Result<Vec<Map>> {
     // Parse the single map_head from the exe file:
-    let map_head = MapHead::parse(map_head_data);
+    let map_head = MapHead::parse(map_head_data, gamemaps.len())?;
 
-    // Parse the headers for each map from gamemaps.ck*:
-    let headers: Vec<Header> = map_head.offsets.iter().map(|offset| {
-        let data = &gamemaps[*offset .. *offset+38];
-        Header::parse(&data)
-    }).collect();
+    // Parse the header for each map from gamemaps.ck*:
+    // MapHead checked each offset leaves room for the header.
+    let headers: Vec<(usize, Header)> = map_head.offsets.iter()
+        .map(|(index, offset)| Ok((*index, read_header(gamemaps, *offset).with_context(|| format!("Couldn't read map {}", index))?)))
+        .collect::<Result<_>>()?;
 
-    // Decompress each map:
-    let maps: Vec<Map> = headers.into_iter().map(|header| {
-        Map::parse(&gamemaps, header, map_head.rlew_key)
-    }).collect();
+    // Every plane of every map is compressed on its own, so they're all expanded at once across the cores:
+    let planes: Vec<(&Header, u8)> = headers.iter().flat_map(|(_, header)| (0..3).map(move |plane| (header, plane))).collect();
+    let mut expanded = threads::map(&planes, |(header, plane)| parse_plane(gamemaps, header, *plane, map_head.rlew_key)).into_iter();
 
+    // Then put each map together:
+    let mut maps: Vec<Map> = Vec::with_capacity(headers.len());
+    for (index, header) in headers {
+        let name = header.name.clone();
+        let planes = [expanded.next().unwrap(), expanded.next().unwrap(), expanded.next().unwrap()];
+        match (Map::from_planes(index, header, planes), &mut problems) {
+            (Ok(map), _) => maps.push(map),
+            (Err(error), Some(problems)) => problems.push(format!("Skipped map {} ({}): {:#}", index, name, error)),
+            (Err(error), None) => return Err(error.context(format!("Couldn't read map {} ({})", index, name))),
+        }
+    }
     Ok(maps)
 }
 
+// Decodes just one map, given its number and offset from the map head, eg to show a single level without decoding
+// them all. Only its header and planes are read from gamemaps.
+pub(crate) fn parse_map(gamemaps: &impl ByteSource, rlew_key: u16, index: usize, offset: usize) -> Result<Map> {
+    let header = read_header(gamemaps, offset)?;
+    let planes = [0, 1, 2].map(|plane| parse_plane(gamemaps, &header, plane, rlew_key));
+    Map::from_planes(index, header, planes)
+}
+
+// Just a map's name, which is in its header, so nothing needs expanding.
+pub(crate) fn parse_map_name(gamemaps: &impl ByteSource, offset: usize) -> Result<String> {
+    Ok(read_header(gamemaps, offset)?.name)
+}
+
+fn read_header(gamemaps: &impl ByteSource, offset: usize) -> Result<Header> {
+    let data = gamemaps.read_at(offset, HEADER_LEN).with_context(|| format!("Couldn't read the map header at offset {}", offset))?;
+    Ok(Header::parse(&data))
+}
+
+// The games all use this, and it's what tools like TED5 write.
+pub const RLEW_KEY: u16 = 0xabcd;
+
+// A packed GAMEMAPS, and the MAPHEAD that points into it.
+pub struct Packed {
+    pub gamemaps: Vec<u8>,
+    pub map_head: Vec<u8>,
+}
+
+// The inverse of parse: Each map's planes are RLEW'd then carmackized, followed by its header, like TED5 lays them out.
+// Each map goes in its own slot in the map head, so they keep their numbers, and any gaps stay unused.
+// The tile info goes back after the map offsets as it was, as the game reads the tiles' properties and animations from it.
+pub fn pack(maps: &[Map], rlew_key: u16, tile_info: &[u8]) -> Result<Packed> {
+    const SIGNATURE: &[u8] = b"TED5v1.0";
+    const MAP_SEPARATOR: &[u8] = b"!ID!";
+    const NAME_LEN: usize = 16;
+    let mut gamemaps: Vec<u8> = SIGNATURE.to_vec();
+    let mut map_head: Vec<u8> = vec![0; MAP_HEAD_LEN]; // Unused maps have a zero offset.
+    map_head[..2].copy_from_slice(&rlew_key.to_le_bytes());
+    for map in maps {
+        if map.index >= MAX_MAPS { bail!("Map {} ({}) is past the last slot, there can only be {} maps!", map.index, map.name, MAX_MAPS) }
+        let slot = 2 + map.index * 4;
+        if map_head[slot..slot + 4] != [0; 4] { bail!("There are two map {}s!", map.index) }
+        let planes: [Vec<u16>; 3] = [
+            map.tiles.iter().flatten().map(|t| t.background).collect(),
+            map.tiles.iter().flatten().map(|t| t.foreground.unwrap_or(0)).collect(),
+            map.tiles.iter().flatten().map(|t| t.info.unwrap_or(0)).collect(),
+        ];
+        let mut offsets: Vec<u8> = Vec::new();
+        let mut lens: Vec<u8> = Vec::new();
+        for plane in planes {
+            let expanded: Vec<u8> = plane.iter().flat_map(|t| t.to_le_bytes()).collect();
+            let compressed = carmackization::compress_with_length_header(&rlew::compress_with_length_header(&expanded, rlew_key));
+            if compressed.len() > 0xffff { bail!("Map {} is too big to pack!", map.name) }
+            offsets.extend_from_slice(&(gamemaps.len() as u32).to_le_bytes());
+            lens.extend_from_slice(&(compressed.len() as u16).to_le_bytes());
+            gamemaps.extend(compressed);
+        }
+        map_head[slot..slot + 4].copy_from_slice(&(gamemaps.len() as u32).to_le_bytes());
+        gamemaps.extend(offsets);
+        gamemaps.extend(lens);
+        gamemaps.extend_from_slice(&(map.width as u16).to_le_bytes());
+        gamemaps.extend_from_slice(&(map.height as u16).to_le_bytes());
+        let mut name = map.name.as_bytes().to_vec();
+        name.resize(NAME_LEN - 1, 0); // Leave room for the terminator.
+        name.push(0);
+        gamemaps.extend(name);
+        gamemaps.extend_from_slice(MAP_SEPARATOR);
+    }
+    map_head.extend_from_slice(tile_info);
+    Ok(Packed { gamemaps, map_head })
+}
+
 // This represents the maphead which is embedded in the exe.
 // It points to the location of each map in the gamemaps file.
 // Apologies for the confusing naming vs 'map header', as 'map head' is what it's called in all
 // the reference docs, so i left it that way for consistency.
+const MAX_MAPS: usize = 100;
+pub const MAP_HEAD_LEN: usize = 2 + MAX_MAPS * 4; // The RLEW key then the offsets, and any tile info follows.
+const HEADER_LEN: usize = 38;
+
 #[derive(Debug)]
-struct MapHead {
-    rlew_key: u16,
-    offsets: Vec<usize>, // Raw file offsets to the start of each map in gamemaps.
+pub(crate) struct MapHead {
+    pub rlew_key: u16,
+    pub offsets: Vec<(usize, usize)>, // Each used map's number, and the raw file offset to its start in gamemaps.
 }
 impl MapHead {
-    fn parse(data: &[u8]) -> Self {
+    pub fn parse(data: &[u8], gamemaps_len: usize) -> Result<Self> {
+        if data.len() < 2 { bail!("Map head is too short!") }
         let rlew_key: u16 = (data[0] as u16) + ((data[1] as u16) << 8);
         let offsets_data = &data[2..];
-        let mut offsets: Vec<usize> = Vec::new();
-        for c in offsets_data.chunks_exact(4) {
-            let offset = u32::from_le_bytes(c.try_into().unwrap()) as usize;
-            if offset == 0 || offset > 9999999 { continue }
-            offsets.push(offset);
+        let mut offsets: Vec<(usize, usize)> = Vec::new();
+        // Only the first 100 are map offsets: the tile info comes after them.
+        for (index, c) in offsets_data.chunks_exact(4).take(MAX_MAPS).enumerate() {
+            let offset = u32::from_le_bytes(c.try_into().unwrap());
+            if offset == 0 || offset == u32::MAX { continue } // Unused, TED5 writes 0 but some tools write -1.
+            if offset as usize + HEADER_LEN > gamemaps_len {
+                bail!("Map {}'s offset {} is past the end of GAMEMAPS, which is {} bytes. Is the map head from another version?", index, offset, gamemaps_len);
+            }
+            offsets.push((index, offset as usize));
         }
-        MapHead { rlew_key, offsets }
+        Ok(MapHead { rlew_key, offsets })
     }
 }
 
@@ -81,34 +174,37 @@ fn string_from_asciiz(data: &[u8]) -> String {
 }
 
 // This represents a decompressed map:
+#[derive(Debug, PartialEq)]
 pub struct Map {
+    pub index: usize, // Its number in the map head, which is what the game (and eg demos) call it.
     pub name: String,
     pub width: usize,
     pub height: usize,
     pub tiles: Vec<Vec<MapTile>>, // Think of this as a vec of rows.
 }
+#[derive(Debug, PartialEq)]
 pub struct MapTile {
     pub background: u16,
-    pub foreground: Option<u16>,
-    pub _sprite: Option<u16>,
+    pub foreground: Option<u16>, // The masked tile, numbered as in the game. It's never 0, as that means none in the plane.
+    pub info: Option<u16>, // Sprite/info plane value, eg an actor to spawn here.
 }
 impl Map {
-    fn parse(gamemaps: &[u8], header: Header, rlew_key: u16) -> Self {
-        let plane_0= parse_plane(&gamemaps, &header, 0, rlew_key).unwrap();
-        let plane_1= parse_plane(&gamemaps, &header, 1, rlew_key).unwrap();
-        let plane_2= parse_plane(&gamemaps, &header, 2, rlew_key).unwrap();
-        let tiles = tiles_from_planes(&plane_0, &plane_1, &plane_2, header.width_tiles);
-        Map {
+    fn from_planes(index: usize, header: Header, planes: [Result<Vec<u16>>; 3]) -> Result<Self> {
+        if header.width_tiles == 0 || header.height_tiles == 0 { bail!("It's {}x{} tiles!", header.width_tiles, header.height_tiles) }
+        let [plane_0, plane_1, plane_2] = planes;
+        let tiles = tiles_from_planes(&plane_0?, &plane_1?, &plane_2?, header.width_tiles);
+        Ok(Map {
+            index,
             name: header.name,
             width: header.width_tiles,
             height: header.height_tiles,
             tiles,
-        }
+        })
     }
 }
 
 // De-carmacks then de-rlew's the compressed plane then parses to u16s.
-fn parse_plane(gamemaps: &[u8], header: &Header, plane: u8, key: u16) -> Result<Vec<u16>> {
+fn parse_plane(gamemaps: &impl ByteSource, header: &Header, plane: u8, key: u16) -> Result<Vec<u16>> {
     // Expand:
     let offset: usize = match plane {
         0 => header.offset_plane_0,
@@ -120,11 +216,17 @@ fn parse_plane(gamemaps: &[u8], header: &Header, plane: u8, key: u16) -> Result<
         1 => header.len_plane_1,
         _ => header.len_plane_2,
     };
-    let compressed = &gamemaps[offset .. (offset + length)];
-    let half_expanded = carmackization::expand_with_length_header(compressed)?;
-    let expanded = rlew::expand_with_length_header(&half_expanded, key)?;
+    if offset + length > gamemaps.len() {
+        bail!("Plane {} at offset {} ({} bytes) is past the end of GAMEMAPS, which is {} bytes!", plane, offset, length, gamemaps.len())
+    }
+    let compressed = gamemaps.read_at(offset, length).with_context(|| format!("Couldn't read plane {} at offset {}", plane, offset))?;
+    let context = || format!("Couldn't expand plane {} at offset {}", plane, offset);
+    let half_expanded = carmackization::expand_with_length_header(&compressed).with_context(context)?;
+    let expanded = rlew::expand_with_length_header(&half_expanded, key).with_context(context)?;
     let expected_length = header.width_tiles * header.height_tiles * 2;
-    if expanded.len() != expected_length { bail!("Expanded plane data isn't the expected number of tiles!") }
+    if expanded.len() != expected_length {
+        bail!("Plane {} at offset {} expanded to {} bytes, but {}x{} tiles needs {}!", plane, offset, expanded.len(), header.width_tiles, header.height_tiles, expected_length)
+    }
     
     // Convert:
     let parsed: Vec<u16> = expanded.chunks_exact(2).map(|c| {
@@ -143,9 +245,71 @@ fn tiles_from_planes(plane_0: &[u16], plane_1: &[u16], plane_2: &[u16], width: u
     // Split them into rows.
     zipped.chunks_exact(width).map(|row| {
         row.iter().map(|tile| {
-            let sprite: Option<u16> = if tile.2 == 0 { None } else { Some(tile.2 - 1) };
-            let foreground: Option<u16> = if tile.1 == 0 { None } else { Some(tile.1 - 1) };
-            MapTile { background: tile.0, foreground, _sprite: sprite }
+            let info: Option<u16> = if tile.2 == 0 { None } else { Some(tile.2) };
+            let foreground: Option<u16> = if tile.1 == 0 { None } else { Some(tile.1) };
+            MapTile { background: tile.0, foreground, info }
         }).collect()
     }).collect()
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_pack() {
+        let tile = |background, foreground, info| MapTile { background, foreground, info };
+        let map = |index, name: &str| Map {
+            index,
+            name: String::from(name),
+            width: 3,
+            height: 2,
+            tiles: vec![
+                vec![tile(1, None, None), tile(1, Some(1), Some(2)), tile(0xabcd, None, None)],
+                vec![tile(1, None, None), tile(1, Some(1), None), tile(0xa7a8, Some(5), Some(0xa701))],
+            ],
+        };
+        // Maps keep their numbers, with the slots between left unused.
+        let packed = pack(&[map(2, "Test"), map(5, "Other")], RLEW_KEY, &[]).unwrap();
+        assert!(packed.gamemaps.starts_with(b"TED5v1.0"));
+        assert_eq!(packed.map_head.len(), 402);
+        let maps = parse(&packed.gamemaps.as_slice(), &packed.map_head, None).unwrap();
+        assert_eq!(maps, [map(2, "Test"), map(5, "Other")]);
+        let reader = crate::source::SeekableReader::new(std::io::Cursor::new(&packed.gamemaps)).unwrap();
+        assert_eq!(parse(&reader, &packed.map_head, None).unwrap(), maps); // Or read a piece at a time.
+        assert_eq!(maps[0].tiles[1][2], tile(0xa7a8, Some(5), Some(0xa701)));
+        assert!(pack(&[map(2, "Test"), map(2, "Other")], RLEW_KEY, &[]).is_err());
+        assert!(pack(&[map(MAX_MAPS, "Test")], RLEW_KEY, &[]).is_err());
+
+        // Breaking the background plane's length header only loses that map in best effort mode, and the next keeps
+        // its number.
+        let mut broken = packed.gamemaps.clone();
+        broken[8] ^= 0xff;
+        let error = parse(&broken.as_slice(), &packed.map_head, None).unwrap_err();
+        assert!(format!("{:#}", error).starts_with("Couldn't read map 2 (Test): Couldn't expand plane 0 at offset 8"));
+        let mut problems: Vec<String> = Vec::new();
+        assert_eq!(parse(&broken.as_slice(), &packed.map_head, Some(&mut problems)).unwrap(), [map(5, "Other")]);
+        assert_eq!(problems.len(), 1);
+    }
+
+    #[test]
+    fn test_pack_keeps_tile_info() {
+        use crate::parse::{ExternalTables, Files};
+        let read = |name: &str| std::fs::read(format!("data/keen4/{}", name)).unwrap();
+        let (exe, graph, maps) = (read("keen4.exe"), read("egagraph.ck4"), read("gamemaps.ck4"));
+        let files = Files { exe: Some(&exe), graph: &graph, maps: &maps, audio: None, tables: ExternalTables::default(), best_effort: false, keep_chunks: false };
+        let game = crate::parse::parse(&files).unwrap();
+        assert!(!game.tile_info_data.is_empty());
+
+        // A MAPHEAD file with tile info after the offsets comes back byte for byte after being loaded and packed again.
+        let packed = pack(&game.maps, game.rlew_key, &game.tile_info_data).unwrap();
+        assert_eq!(packed.map_head.len(), MAP_HEAD_LEN + game.tile_info_data.len());
+        let tables = ExternalTables { map_head: Some(&packed.map_head), ..ExternalTables::default() };
+        let files = Files { maps: &packed.gamemaps, tables, ..files };
+        let repacked_game = crate::parse::parse(&files).unwrap();
+        assert_eq!(repacked_game.tile_info_data, game.tile_info_data);
+        assert!(repacked_game.tile_info.is_some());
+        let repacked = pack(&repacked_game.maps, repacked_game.rlew_key, &repacked_game.tile_info_data).unwrap();
+        assert!(repacked.map_head == packed.map_head && repacked.gamemaps == packed.gamemaps);
+    }
+}
@@ -0,0 +1,210 @@
+// This is responsible for parsing the miscellaneous chunks at the end of the EGAGRAPH, after the tiles:
+// The text mode screens shown on exit, the help/story/ending articles, the big text for the terminator intro, and the demos.
+// Which of these each game has, and their order, differs between the games and versions, so they come from the
+// episode's versions::ChunkLayout. Versions it doesn't know fall back to recognising them by their contents.
+
+use crate::images;
+use crate::versions::ExternKind;
+
+pub enum MiscChunk {
+    Screen(TextScreen),
+    Article(String),
+    Terminator(images::Image),
+    Demo(Demo),
+    Unknown,
+}
+
+// Parses an extern that's known to be the given kind, or Unknown if it isn't one after all.
+pub fn parse_kind(data: &[u8], kind: ExternKind) -> MiscChunk {
+    let parsed = match kind {
+        ExternKind::Screen => parse_screen(data).map(MiscChunk::Screen),
+        ExternKind::Article => parse_article(data).map(MiscChunk::Article),
+        ExternKind::Terminator => parse_terminator(data).map(MiscChunk::Terminator),
+        ExternKind::Demo => parse_demo(data).map(MiscChunk::Demo),
+    };
+    parsed.unwrap_or(MiscChunk::Unknown)
+}
+
+// For versions whose externs aren't known: Works out what it is from its contents.
+pub fn parse(data: &[u8]) -> MiscChunk {
+    if let Some(screen) = parse_screen(data) {
+        MiscChunk::Screen(screen)
+    } else if let Some(article) = parse_article(data) {
+        MiscChunk::Article(article)
+    } else if let Some(image) = parse_terminator(data) {
+        MiscChunk::Terminator(image)
+    } else if let Some(demo) = parse_demo(data) {
+        MiscChunk::Demo(demo)
+    } else {
+        MiscChunk::Unknown
+    }
+}
+
+fn u16_at(data: &[u8], offset: usize) -> Option<usize> {
+    let bytes = data.get(offset .. offset + 2)?;
+    Some(bytes[0] as usize + ((bytes[1] as usize) << 8))
+}
+
+// Articles are text with ^ commands, starting with a page break.
+fn parse_article(data: &[u8]) -> Option<String> {
+    if !data.starts_with(b"^P") && !data.starts_with(b"^p") { return None }
+    Some(data.iter().map(|b| cp437(*b)).collect())
+}
+
+pub const SCREEN_WIDTH: usize = 80;
+pub const SCREEN_HEIGHT: usize = 25;
+
+// An 80x25 text mode screen, with a character and a colour attribute per cell.
+pub struct TextScreen {
+    pub characters: Vec<u8>,
+    pub attributes: Vec<u8>, // Low nibble is the foreground colour, then 3 bits of background, then blink.
+}
+
+// https://moddingwiki.shikadi.net/wiki/B800_Text
+// These are BSAVE files: A 0xFD byte, then the segment (0xB800 for text mode), offset and length, then the screen memory.
+fn parse_screen(data: &[u8]) -> Option<TextScreen> {
+    const HEADER_LEN: usize = 7;
+    const LEN: usize = SCREEN_WIDTH * SCREEN_HEIGHT * 2;
+    if data.first() != Some(&0xfd) || u16_at(data, 1) != Some(0xb800) { return None }
+    let cells = data.get(HEADER_LEN .. HEADER_LEN + LEN)?;
+    Some(TextScreen {
+        characters: cells.iter().step_by(2).copied().collect(),
+        attributes: cells.iter().skip(1).step_by(2).copied().collect(),
+    })
+}
+
+impl TextScreen {
+    // Converts to text with ANSI colour codes, so it can be viewed in a terminal with `cat`.
+    pub fn ansi(&self) -> String {
+        // The EGA palette has blue and red the other way around to ANSI.
+        const ANSI_FROM_EGA: [u8; 8] = [0, 4, 2, 6, 1, 5, 3, 7];
+        let mut text = String::new();
+        for (characters, attributes) in self.characters.chunks_exact(SCREEN_WIDTH).zip(self.attributes.chunks_exact(SCREEN_WIDTH)) {
+            let mut previous: Option<u8> = None;
+            for (character, attribute) in characters.iter().zip(attributes) {
+                if previous != Some(*attribute) {
+                    let foreground = attribute & 0xf;
+                    let foreground_code = if foreground < 8 { 30 } else { 90 } + ANSI_FROM_EGA[(foreground & 7) as usize];
+                    let background_code = 40 + ANSI_FROM_EGA[((attribute >> 4) & 7) as usize];
+                    let blink = if attribute & 0x80 != 0 { ";5" } else { "" };
+                    text += &format!("\x1b[0;{};{}{}m", foreground_code, background_code, blink);
+                    previous = Some(*attribute);
+                }
+                text.push(cp437_screen(*character));
+            }
+            text += "\x1b[0m\n";
+        }
+        text
+    }
+}
+
+// The PC's character set, as shown in text mode. Control characters are drawn as symbols too.
+const CP437_LOW: &str = " ☺☻♥♦♣♠•◘○◙♂♀♪♫☼►◄↕‼¶§▬↨↑↓→←∟↔▲▼";
+const CP437_HIGH: &str = "ÇüéâäàåçêëèïîìÄÅÉæÆôöòûùÿÖÜ¢£¥₧ƒáíóúñÑªº¿⌐¬½¼¡«»░▒▓│┤╡╢╖╕╣║╗╝╜╛┐└┴┬├─┼╞╟╚╔╩╦╠═╬╧╨╤╥╙╘╒╓╫╪┘┌█▄▌▐▀αßΓπΣσµτΦΘΩδ∞φε∩≡±≥≤⌠⌡÷≈°∙·√ⁿ²■ ";
+
+// Articles keep their line breaks, so only text screens show the low control characters as symbols.
+fn cp437(byte: u8) -> char {
+    match byte {
+        0x80.. => CP437_HIGH.chars().nth((byte - 0x80) as usize).unwrap(),
+        0x7f => '⌂',
+        _ => byte as char,
+    }
+}
+
+fn cp437_screen(byte: u8) -> char {
+    match byte {
+        ..0x20 => CP437_LOW.chars().nth(byte as usize).unwrap(),
+        _ => cp437(byte),
+    }
+}
+
+// The terminator intro's huge 'COMMANDER' and 'KEEN', which it scales down.
+// Height, width, then offsets to each row. Each row is alternating clear and set run lengths, ending with 0xFFFF.
+fn parse_terminator(data: &[u8]) -> Option<images::Image> {
+    let height = u16_at(data, 0)?;
+    let width = u16_at(data, 2)?;
+    if height == 0 || width == 0 || u16_at(data, 4)? != 4 + height * 2 { return None }
+    let mut image = images::Image::empty(width, height);
+    for y in 0..height {
+        let mut offset = u16_at(data, 4 + y * 2)?;
+        let mut x = 0;
+        let mut is_set = false;
+        loop {
+            let run = u16_at(data, offset)?;
+            if run == 0xffff { break }
+            if x + run > width { return None }
+            if is_set {
+                image.data[y * width + x .. y * width + x + run].fill(0); // Black.
+            }
+            x += run;
+            is_set = !is_set;
+            offset += 2;
+        }
+    }
+    Some(image)
+}
+
+// A recorded demo: Which map, and the controls held down for how long.
+pub struct Demo {
+    pub map: u16,
+    pub inputs: Vec<DemoInput>,
+}
+
+pub struct DemoInput {
+    pub tics: u8, // How long this is held for, in 70ths of a second.
+    pub input: u8, // Bits 0-1 are the y direction plus 1, 2-3 are x plus 1, and 4-5 are the buttons.
+}
+
+impl DemoInput {
+    pub fn x(&self) -> i8 { ((self.input >> 2) & 3) as i8 - 1 }
+    pub fn y(&self) -> i8 { (self.input & 3) as i8 - 1 }
+    pub fn buttons(&self) -> u8 { (self.input >> 4) & 3 }
+}
+
+// Map number, then the length of the inputs in bytes, then the inputs as tics+input pairs.
+// The chunk can be longer than the inputs, so when guessing, only the map number and inputs can be checked.
+fn parse_demo(data: &[u8]) -> Option<Demo> {
+    const MAPS: usize = 100;
+    let map = u16_at(data, 0)?;
+    let len = u16_at(data, 2)?;
+    if map >= MAPS || len == 0 || !len.is_multiple_of(2) { return None }
+    let inputs = data.get(4 .. 4 + len)?;
+    Some(Demo {
+        map: map as u16,
+        inputs: inputs.chunks_exact(2).map(|c| DemoInput { tics: c[0], input: c[1] }).collect(),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_code_page_has_every_character() {
+        assert_eq!(CP437_LOW.chars().count(), 0x20);
+        assert_eq!(CP437_HIGH.chars().count(), 0x80);
+        assert_eq!(cp437_screen(1), '☺');
+        assert_eq!(cp437(0xff), ' ');
+    }
+
+    #[test]
+    fn test_parse() {
+        let MiscChunk::Demo(demo) = parse(&[3, 0, 4, 0, 10, 0x15, 2, 0x05]) else { panic!("Not a demo") };
+        assert_eq!(demo.map, 3);
+        assert_eq!(demo.inputs.len(), 2);
+        assert_eq!((demo.inputs[0].tics, demo.inputs[0].x(), demo.inputs[0].y(), demo.inputs[0].buttons()), (10, 0, 0, 1));
+
+        // A 3x1 image, with a row of clear, set, clear.
+        let MiscChunk::Terminator(image) = parse(&[1, 0, 3, 0, 6, 0, 1, 0, 1, 0, 1, 0, 0xff, 0xff]) else { panic!("Not terminator text") };
+        assert_eq!(image.data, vec![crate::palette::CLEAR_INDEX, 0, crate::palette::CLEAR_INDEX]);
+
+        assert!(matches!(parse(b"^P\r\nHello\r\n^E"), MiscChunk::Article(text) if text.ends_with("Hello\r\n^E")));
+    }
+
+    #[test]
+    fn test_parse_kind() {
+        assert!(matches!(parse_kind(&[3, 0, 4, 0, 10, 0x15, 2, 0x05], ExternKind::Demo), MiscChunk::Demo(_)));
+        assert!(matches!(parse_kind(&[3, 0, 4, 0, 10, 0x15, 2, 0x05], ExternKind::Article), MiscChunk::Unknown));
+        assert!(matches!(parse(&[0x34, 0x12, 4, 0, 10, 0x15, 2, 0x05]), MiscChunk::Unknown)); // Map 0x1234 can't be a demo.
+    }
+}
@@ -1,6 +1,36 @@
-// This is responsible for writing naively-uncompressed PNG files.
+// This is responsible for writing PNG files, with its own deflate compressor, and reading them back.
 // https://www.splinter.com.au/2022/07/12/rust-png-writer/
 
+use anyhow::{Result, bail};
+
+// How hard to try compressing, like zlib's levels: 0 stores uncompressed, 1 is fastest, 9 is smallest.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Level(pub u8);
+
+impl Default for Level {
+    fn default() -> Self {
+        Level(6)
+    }
+}
+
+impl Level {
+    // How many earlier matches to check per position, and a match length that's good enough to stop looking.
+    fn search_limits(&self) -> (usize, usize) {
+        match self.0 {
+            0 => (0, 0),
+            1 => (4, 8),
+            2 => (8, 16),
+            3 => (16, 32),
+            4 => (16, 64),
+            5 => (32, 128),
+            6 => (64, 128),
+            7 => (128, 258),
+            8 => (512, 258),
+            _ => (4096, 258),
+        }
+    }
+}
+
 // Converts into an RFC1951 'raw deflate stream' format in a simple non-compressing way.
 // An uncompressed deflate stream looks like N blocks, each being:
 // [is_final, 2 bytes length, 2 bytes length 1's complement, data].
@@ -8,7 +38,7 @@
 // See: https://datatracker.ietf.org/doc/html/rfc1951
 // Test output with: ruby -rzlib -e 'print Zlib::Inflate.new(-15).inflate(STDIN.read)' < foo.deflateStream
 // https://yob.id.au/2020/06/16/zlib-gzip-and-deflate-in-ruby.html
-fn to_deflate_stream(input: &[u8]) -> Vec<u8> {
+fn to_stored_deflate_stream(input: &[u8]) -> Vec<u8> {
     if input.is_empty() {
         return vec![1, 0, 0, 0xff, 0xff]; // 1 block with no content.
     }
@@ -30,56 +60,444 @@ fn to_deflate_stream(input: &[u8]) -> Vec<u8> {
     output
 }
 
-// Converts to an uncompressed RFC1950 zlib stream.
+// Deflate proper: LZ77 to find repeats, then each block is Huffman coded with
+// whichever of the fixed codes, its own dynamic codes, or storing is smallest.
+// See: https://datatracker.ietf.org/doc/html/rfc1951
+fn to_deflate_stream(input: &[u8], level: Level) -> Vec<u8> {
+    const TOKENS_PER_BLOCK: usize = 1 << 15;
+    if level.0 == 0 { return to_stored_deflate_stream(input) }
+    let tokens = lz77(input, level);
+    let mut writer = BitWriter::new();
+    let mut block_start: usize = 0; // In the input, for stored blocks.
+    let blocks: Vec<&[Token]> = if tokens.is_empty() { vec![&[]] } else { tokens.chunks(TOKENS_PER_BLOCK).collect() };
+    for (index, block) in blocks.iter().enumerate() {
+        let block_len: usize = block.iter().map(|t| t.input_len()).sum();
+        write_block(&mut writer, block, &input[block_start .. block_start + block_len], index == blocks.len() - 1);
+        block_start += block_len;
+    }
+    writer.finish()
+}
+
+// A literal byte, or a copy of 3-258 bytes from 1-32768 bytes back.
+#[derive(Clone, Copy)]
+struct Token {
+    len: u16, // 0 for a literal.
+    value: u16, // The literal, or the distance.
+}
+
+impl Token {
+    fn input_len(&self) -> usize {
+        if self.len == 0 { 1 } else { self.len as usize }
+    }
+}
+
+// Finds repeats with hash chains of the positions where each 3 bytes were last seen.
+fn lz77(input: &[u8], level: Level) -> Vec<Token> {
+    const WINDOW: usize = 1 << 15;
+    const HASH_BITS: usize = 15;
+    const MIN_MATCH: usize = 3;
+    const MAX_MATCH: usize = 258;
+    const NONE: u32 = u32::MAX;
+    let (max_chain, nice_len) = level.search_limits();
+    let hash = |i: usize| -> usize {
+        ((input[i] as usize) << 10 ^ (input[i + 1] as usize) << 5 ^ input[i + 2] as usize) & ((1 << HASH_BITS) - 1)
+    };
+    let mut head: Vec<u32> = vec![NONE; 1 << HASH_BITS];
+    let mut previous: Vec<u32> = vec![NONE; WINDOW];
+    let insert = |i: usize, head: &mut [u32], previous: &mut [u32]| {
+        let h = hash(i);
+        previous[i % WINDOW] = head[h];
+        head[h] = i as u32;
+    };
+
+    let mut tokens: Vec<Token> = Vec::with_capacity(input.len() / 4);
+    let mut i: usize = 0;
+    while i < input.len() {
+        if i + MIN_MATCH > input.len() {
+            tokens.push(Token { len: 0, value: input[i] as u16 });
+            i += 1;
+            continue
+        }
+        // Walk the chain looking for the longest match.
+        let max_len = MAX_MATCH.min(input.len() - i);
+        let mut best_len: usize = 0;
+        let mut best_distance: usize = 0;
+        let mut candidate = head[hash(i)];
+        let mut chain = max_chain;
+        while candidate != NONE && chain > 0 {
+            let c = candidate as usize;
+            let distance = i - c;
+            if distance > WINDOW - MAX_MATCH { break } // Leave room so the chain entries haven't been overwritten.
+            // Quick reject: it must beat the best so far at its last byte.
+            if input[c + best_len.min(max_len - 1)] == input[i + best_len.min(max_len - 1)] {
+                let len = input[c..].iter().zip(&input[i .. i + max_len]).take_while(|(a, b)| a == b).count();
+                if len > best_len {
+                    best_len = len;
+                    best_distance = distance;
+                    if len >= nice_len || len == max_len { break }
+                }
+            }
+            candidate = previous[c % WINDOW];
+            chain -= 1;
+        }
+        if best_len >= MIN_MATCH {
+            tokens.push(Token { len: best_len as u16, value: best_distance as u16 });
+            for j in i .. (i + best_len).min(input.len() - MIN_MATCH + 1) {
+                insert(j, &mut head, &mut previous);
+            }
+            i += best_len;
+        } else {
+            tokens.push(Token { len: 0, value: input[i] as u16 });
+            insert(i, &mut head, &mut previous);
+            i += 1;
+        }
+    }
+    tokens
+}
+
+// Length codes 257-285: base length and extra bits.
+const LENGTH_BASES: [u16; 29] = [3, 4, 5, 6, 7, 8, 9, 10, 11, 13, 15, 17, 19, 23, 27, 31, 35, 43, 51, 59, 67, 83, 99, 115, 131, 163, 195, 227, 258];
+const LENGTH_EXTRA_BITS: [u8; 29] = [0, 0, 0, 0, 0, 0, 0, 0, 1, 1, 1, 1, 2, 2, 2, 2, 3, 3, 3, 3, 4, 4, 4, 4, 5, 5, 5, 5, 0];
+// Distance codes 0-29.
+const DISTANCE_BASES: [u16; 30] = [1, 2, 3, 4, 5, 7, 9, 13, 17, 25, 33, 49, 65, 97, 129, 193, 257, 385, 513, 769, 1025, 1537, 2049, 3073, 4097, 6145, 8193, 12289, 16385, 24577];
+const DISTANCE_EXTRA_BITS: [u8; 30] = [0, 0, 0, 0, 1, 1, 2, 2, 3, 3, 4, 4, 5, 5, 6, 6, 7, 7, 8, 8, 9, 9, 10, 10, 11, 11, 12, 12, 13, 13];
+// The order code length code lengths are written in.
+const CODE_LENGTH_ORDER: [usize; 19] = [16, 17, 18, 0, 8, 7, 9, 6, 10, 5, 11, 4, 12, 3, 13, 2, 14, 1, 15];
+const END_OF_BLOCK: usize = 256;
+
+fn length_code(len: u16) -> usize {
+    LENGTH_BASES.partition_point(|base| *base <= len) - 1
+}
+
+fn distance_code(distance: u16) -> usize {
+    DISTANCE_BASES.partition_point(|base| *base <= distance) - 1
+}
+
+fn fixed_lengths() -> (Vec<u8>, Vec<u8>) {
+    let mut literal_lengths = vec![8u8; 288];
+    literal_lengths[144..256].fill(9);
+    literal_lengths[256..280].fill(7);
+    (literal_lengths, vec![5u8; 30])
+}
+
+fn write_block(writer: &mut BitWriter, tokens: &[Token], input: &[u8], is_final: bool) {
+    // Count how often each symbol is used.
+    let mut literal_counts = vec![0u32; 286];
+    let mut distance_counts = vec![0u32; 30];
+    literal_counts[END_OF_BLOCK] = 1;
+    for token in tokens {
+        if token.len == 0 {
+            literal_counts[token.value as usize] += 1;
+        } else {
+            literal_counts[257 + length_code(token.len)] += 1;
+            distance_counts[distance_code(token.value)] += 1;
+        }
+    }
+    let data_bits = |literal_lengths: &[u8], distance_lengths: &[u8]| -> usize {
+        let literals: usize = literal_counts.iter().zip(literal_lengths).enumerate()
+            .map(|(symbol, (count, len))| *count as usize * (*len as usize + if symbol > 256 { LENGTH_EXTRA_BITS[symbol - 257] as usize } else { 0 }))
+            .sum();
+        let distances: usize = distance_counts.iter().zip(distance_lengths).enumerate()
+            .map(|(symbol, (count, len))| *count as usize * (*len as usize + DISTANCE_EXTRA_BITS[symbol] as usize))
+            .sum();
+        literals + distances
+    };
+
+    let (fixed_literal_lengths, fixed_distance_lengths) = fixed_lengths();
+    let fixed_bits = 3 + data_bits(&fixed_literal_lengths, &fixed_distance_lengths);
+
+    let literal_lengths = huffman_lengths(&literal_counts, 15);
+    let mut distance_lengths = huffman_lengths(&distance_counts, 15);
+    if distance_lengths.iter().all(|l| *l == 0) {
+        distance_lengths[0] = 1; // Decoders expect at least one distance code.
+    }
+    let header = DynamicHeader::new(&literal_lengths, &distance_lengths);
+    let dynamic_bits = 3 + header.bits() + data_bits(&literal_lengths, &distance_lengths);
+
+    let stored_bits = (input.len() + input.len().div_ceil(0xffff).max(1) * 5) * 8 + 7; // Plus up to 7 bits to align.
+
+    if stored_bits < fixed_bits.min(dynamic_bits) {
+        let chunks: Vec<&[u8]> = if input.is_empty() { vec![&[]] } else { input.chunks(0xffff).collect() };
+        for (index, chunk) in chunks.iter().enumerate() {
+            writer.write((is_final && index == chunks.len() - 1) as u32, 1);
+            writer.write(0, 2); // Stored.
+            writer.align();
+            let len = chunk.len() as u16;
+            writer.bytes(&len.to_le_bytes());
+            writer.bytes(&(!len).to_le_bytes());
+            writer.bytes(chunk);
+        }
+    } else if fixed_bits <= dynamic_bits {
+        writer.write(is_final as u32, 1);
+        writer.write(1, 2); // Fixed.
+        write_tokens(writer, tokens, &fixed_literal_lengths, &fixed_distance_lengths);
+    } else {
+        writer.write(is_final as u32, 1);
+        writer.write(2, 2); // Dynamic.
+        header.write(writer);
+        write_tokens(writer, tokens, &literal_lengths, &distance_lengths);
+    }
+}
+
+fn write_tokens(writer: &mut BitWriter, tokens: &[Token], literal_lengths: &[u8], distance_lengths: &[u8]) {
+    let literal_codes = canonical_codes(literal_lengths);
+    let distance_codes = canonical_codes(distance_lengths);
+    for token in tokens {
+        if token.len == 0 {
+            let symbol = token.value as usize;
+            writer.write(literal_codes[symbol], literal_lengths[symbol]);
+        } else {
+            let code = length_code(token.len);
+            writer.write(literal_codes[257 + code], literal_lengths[257 + code]);
+            writer.write((token.len - LENGTH_BASES[code]) as u32, LENGTH_EXTRA_BITS[code]);
+            let code = distance_code(token.value);
+            writer.write(distance_codes[code], distance_lengths[code]);
+            writer.write((token.value - DISTANCE_BASES[code]) as u32, DISTANCE_EXTRA_BITS[code]);
+        }
+    }
+    writer.write(literal_codes[END_OF_BLOCK], literal_lengths[END_OF_BLOCK]);
+}
+
+// A dynamic block's header: The code lengths, themselves run length encoded then huffman coded.
+struct DynamicHeader {
+    literal_count: usize,
+    distance_count: usize,
+    code_length_count: usize,
+    code_length_lengths: Vec<u8>,
+    runs: Vec<(u8, u8)>, // Code length symbol 0-18, and its extra bits value.
+}
+
+impl DynamicHeader {
+    fn new(literal_lengths: &[u8], distance_lengths: &[u8]) -> Self {
+        let literal_count = 257.max(literal_lengths.iter().rposition(|l| *l != 0).map_or(0, |p| p + 1));
+        let distance_count = 1.max(distance_lengths.iter().rposition(|l| *l != 0).map_or(0, |p| p + 1));
+        let lengths: Vec<u8> = literal_lengths[..literal_count].iter().chain(&distance_lengths[..distance_count]).copied().collect();
+
+        // 16 repeats the previous length 3-6 times, 17 is 3-10 zeroes, 18 is 11-138 zeroes.
+        let mut runs: Vec<(u8, u8)> = Vec::new();
+        let mut i = 0;
+        while i < lengths.len() {
+            let len = lengths[i];
+            let run = lengths[i..].iter().take_while(|l| **l == len).count();
+            if len == 0 && run >= 11 {
+                let n = run.min(138);
+                runs.push((18, (n - 11) as u8));
+                i += n;
+            } else if len == 0 && run >= 3 {
+                runs.push((17, (run - 3) as u8));
+                i += run;
+            } else if len != 0 && run >= 4 {
+                runs.push((len, 0));
+                let n = (run - 1).min(6);
+                runs.push((16, (n - 3) as u8));
+                i += 1 + n;
+            } else {
+                runs.push((len, 0));
+                i += 1;
+            }
+        }
+        let mut counts = [0u32; 19];
+        for (symbol, _) in runs.iter() {
+            counts[*symbol as usize] += 1;
+        }
+        let code_length_lengths = huffman_lengths(&counts, 7);
+        let code_length_count = 4.max(CODE_LENGTH_ORDER.iter().rposition(|s| code_length_lengths[*s] != 0).map_or(0, |p| p + 1));
+        DynamicHeader { literal_count, distance_count, code_length_count, code_length_lengths, runs }
+    }
+
+    fn bits(&self) -> usize {
+        let runs: usize = self.runs.iter().map(|(symbol, _)| self.code_length_lengths[*symbol as usize] as usize + run_extra_bits(*symbol) as usize).sum();
+        5 + 5 + 4 + self.code_length_count * 3 + runs
+    }
+
+    fn write(&self, writer: &mut BitWriter) {
+        writer.write((self.literal_count - 257) as u32, 5);
+        writer.write((self.distance_count - 1) as u32, 5);
+        writer.write((self.code_length_count - 4) as u32, 4);
+        for symbol in CODE_LENGTH_ORDER.iter().take(self.code_length_count) {
+            writer.write(self.code_length_lengths[*symbol] as u32, 3);
+        }
+        let codes = canonical_codes(&self.code_length_lengths);
+        for (symbol, extra) in self.runs.iter() {
+            writer.write(codes[*symbol as usize], self.code_length_lengths[*symbol as usize]);
+            writer.write(*extra as u32, run_extra_bits(*symbol));
+        }
+    }
+}
+
+fn run_extra_bits(symbol: u8) -> u8 {
+    match symbol {
+        16 => 2,
+        17 => 3,
+        18 => 7,
+        _ => 0,
+    }
+}
+
+// Works out the optimal code length for each symbol, from how often they're used.
+// If it's longer than the limit, the counts are flattened and it tries again, which is slightly suboptimal but simple.
+fn huffman_lengths(counts: &[u32], limit: u8) -> Vec<u8> {
+    let mut counts = counts.to_vec();
+    loop {
+        let lengths = unlimited_huffman_lengths(&counts);
+        if lengths.iter().all(|l| *l <= limit) { return lengths }
+        for count in counts.iter_mut().filter(|c| **c > 0) {
+            *count = (*count >> 1) | 1;
+        }
+    }
+}
+
+fn unlimited_huffman_lengths(counts: &[u32]) -> Vec<u8> {
+    use std::cmp::Reverse;
+    use std::collections::BinaryHeap;
+    let mut lengths = vec![0u8; counts.len()];
+    let used: Vec<usize> = (0..counts.len()).filter(|s| counts[*s] > 0).collect();
+    if used.len() <= 1 {
+        if let Some(symbol) = used.first() {
+            lengths[*symbol] = 1; // A code needs at least 1 bit.
+        }
+        return lengths
+    }
+    // Nodes 0..n are the symbols, then the merged ones. Parents are tracked to work out the depths at the end.
+    let mut parents: Vec<usize> = vec![usize::MAX; used.len()];
+    let mut heap: BinaryHeap<Reverse<(u64, usize)>> = used.iter().enumerate()
+        .map(|(node, symbol)| Reverse((counts[*symbol] as u64, node)))
+        .collect();
+    while heap.len() > 1 {
+        let Reverse((a_count, a)) = heap.pop().unwrap();
+        let Reverse((b_count, b)) = heap.pop().unwrap();
+        let parent = parents.len();
+        parents.push(usize::MAX);
+        parents[a] = parent;
+        parents[b] = parent;
+        heap.push(Reverse((a_count + b_count, parent)));
+    }
+    // Parents always come after their children, so work backwards to find the depths.
+    let mut depths: Vec<u8> = vec![0; parents.len()];
+    for node in (0 .. parents.len() - 1).rev() {
+        depths[node] = depths[parents[node]] + 1;
+    }
+    for (node, symbol) in used.iter().enumerate() {
+        lengths[*symbol] = depths[node];
+    }
+    lengths
+}
+
+// Assigns the codes from the lengths as deflate specifies, then reverses them as they're written lsb-first.
+fn canonical_codes(lengths: &[u8]) -> Vec<u32> {
+    let mut length_counts = [0u32; 16];
+    for len in lengths.iter().filter(|l| **l > 0) {
+        length_counts[*len as usize] += 1;
+    }
+    let mut next_code = [0u32; 16];
+    let mut code = 0;
+    for bits in 1..16 {
+        code = (code + length_counts[bits - 1]) << 1;
+        next_code[bits] = code;
+    }
+    lengths.iter().map(|len| {
+        if *len == 0 { return 0 }
+        let code = next_code[*len as usize];
+        next_code[*len as usize] += 1;
+        code.reverse_bits() >> (32 - *len as u32)
+    }).collect()
+}
+
+// Writes bits lsb-first, as deflate wants.
+struct BitWriter {
+    output: Vec<u8>,
+    buffer: u64,
+    count: u32,
+}
+
+impl BitWriter {
+    fn new() -> Self {
+        BitWriter { output: Vec::new(), buffer: 0, count: 0 }
+    }
+
+    fn write(&mut self, value: u32, bits: u8) {
+        self.buffer |= (value as u64) << self.count;
+        self.count += bits as u32;
+        while self.count >= 8 {
+            self.output.push(self.buffer as u8);
+            self.buffer >>= 8;
+            self.count -= 8;
+        }
+    }
+
+    fn align(&mut self) {
+        if self.count > 0 {
+            self.write(0, (8 - self.count) as u8);
+        }
+    }
+
+    fn bytes(&mut self, bytes: &[u8]) {
+        self.output.extend_from_slice(bytes);
+    }
+
+    fn finish(mut self) -> Vec<u8> {
+        self.align();
+        self.output
+    }
+}
+
+// Converts to an RFC1950 zlib stream.
 // See: https://datatracker.ietf.org/doc/html/rfc1950
-// In the simple uncompressed case, this boils down to adding a header and checksum.
+// This boils down to adding a header and checksum.
 // Test output with: ruby -rzlib -e 'print Zlib::Inflate.new.inflate(STDIN.read)' < foo.zlib
-fn to_zlib_stream(input: &[u8]) -> Vec<u8> {
+fn to_zlib_stream(input: &[u8], level: Level) -> Vec<u8> {
     // Header.
     let mut output = Vec::<u8>::new();
     output.push(0x78); // CMF byte. Bits 0-3=method, 4-7=info/window size. Method=8, Window size=7.
-    output.push(1); // FLG byte. Bits 0-4=fcheck, 5=fdict which we dont want so 0, 6-7=flevel where 0 means fastest.
+    // FLG byte. Bits 0-4=fcheck, 5=fdict which we dont want so 0, 6-7=flevel as a hint of how hard it tried.
+    output.push(match level.0 {
+        0..=1 => 0x01,
+        2..=5 => 0x5e,
+        6 => 0x9c,
+        _ => 0xda,
+    });
 
     // Body.
-    let deflated = to_deflate_stream(input);
+    let deflated = to_deflate_stream(input, level);
     output.extend(deflated);
 
     // Checksum.
-    // See: https://en.wikipedia.org/wiki/Adler-32#Example_implementation
+    output.extend_from_slice(&adler32(input).to_be_bytes());
+
+    output
+}
+
+// See: https://en.wikipedia.org/wiki/Adler-32#Example_implementation
+fn adler32(input: &[u8]) -> u32 {
     let mut a: u32 = 1;
     let mut b: u32 = 0;
     for data in input {
         a = (a + (*data as u32)) % 65521;
         b = (b + a) % 65521;
     }
-    output.push((b >> 8) as u8);
-    output.push((b & 0xff) as u8);
-    output.push((a >> 8) as u8);
-    output.push((a & 0xff) as u8);
-
-    output
+    (b << 16) | a
 }
 
 // http://libpng.org/pub/png/spec/1.0/PNG-CRCAppendix.html
 fn crc(data: &[u8]) -> u32 {
     // Make the CRC table first.
     let mut crc_table: [u32; 256] = [0; 256];
-    for n in 0..256 {
+    for (n, entry) in crc_table.iter_mut().enumerate() {
         let mut c: u32 = n as u32;
         for _k in 0..8 {
             if c & 1 == 1 {
                 c = 0xedb88320u32 ^ (c >> 1);
             } else {
-                c = c >> 1;
+                c >>= 1;
             }
         }
-        crc_table[n] = c;
+        *entry = c;
     }
 
     // Calculate the CRC.
     let mut crc: u32 = 0xffffffff;
     for b in data {
-        crc = crc_table[((crc ^ (*b as u32)) & 0xff) as usize] ^ (crc >> 8);        
+        crc = crc_table[((crc ^ (*b as u32)) & 0xff) as usize] ^ (crc >> 8);
     }
     !crc
 }
@@ -92,78 +510,550 @@ fn append_msb(vec: &mut Vec<u8>, value: u32) {
     vec.push((value & 0xff) as u8);
 }
 
-// The rgba input is L->R then top to bottom. R is MSB, A is LSB.
+// Each row is prefixed with the filter that makes it most compressible, which predicts each byte from its neighbours.
+// This uses the usual heuristic of choosing the filter whose output is smallest as signed bytes.
+// Given a filter, every row uses that one instead, eg to test them all.
+// http://www.libpng.org/pub/png/spec/1.2/PNG-Filters.html
+fn filter_rows(raw: &[u8], row_len: usize, bytes_per_pixel: usize, level: Level, forced: Option<u8>) -> Vec<u8> {
+    let mut output = Vec::<u8>::with_capacity(raw.len() + raw.len() / row_len.max(1));
+    let empty_row = vec![0u8; row_len];
+    let mut candidate = vec![0u8; row_len];
+    let mut best = vec![0u8; row_len];
+    let filters: Vec<u8> = match forced {
+        Some(filter) => vec![filter],
+        None if level.0 == 0 => vec![0], // None, as it won't be compressed anyway.
+        None => (0..5).collect(),
+    };
+    for (index, row) in raw.chunks_exact(row_len).enumerate() {
+        let above = if index == 0 { &empty_row[..] } else { &raw[(index - 1) * row_len .. index * row_len] };
+        let mut best_filter: u8 = 0;
+        let mut best_score = usize::MAX;
+        for &filter in &filters {
+            for x in 0..row_len {
+                let left = if x >= bytes_per_pixel { row[x - bytes_per_pixel] } else { 0 };
+                let up = above[x];
+                let up_left = if x >= bytes_per_pixel { above[x - bytes_per_pixel] } else { 0 };
+                let prediction = match filter {
+                    0 => 0,
+                    1 => left,
+                    2 => up,
+                    3 => ((left as u16 + up as u16) / 2) as u8,
+                    _ => paeth(left, up, up_left),
+                };
+                candidate[x] = row[x].wrapping_sub(prediction);
+            }
+            let score: usize = candidate.iter().map(|b| (*b as i8).unsigned_abs() as usize).sum();
+            if score < best_score {
+                best_score = score;
+                best_filter = filter;
+                std::mem::swap(&mut best, &mut candidate);
+            }
+        }
+        output.push(best_filter);
+        output.extend_from_slice(&best);
+    }
+    output
+}
+
+fn paeth(a: u8, b: u8, c: u8) -> u8 {
+    let p = a as i16 + b as i16 - c as i16;
+    let pa = (p - a as i16).abs();
+    let pb = (p - b as i16).abs();
+    let pc = (p - c as i16).abs();
+    if pa <= pb && pa <= pc { a } else if pb <= pc { b } else { c }
+}
+
+// The pixels are palette indices, L->R then top to bottom. The palette's entries are 0xrrggbbaa.
+// Palettes of up to 16 colours are packed at 4bpp, bigger ones at 8bpp.
 // https://en.wikipedia.org/wiki/Portable_Network_Graphics#File_format
-pub fn encode(width: u32, height: u32, rgba: &[u32]) -> Vec<u8> {
-    let mut output = Vec::<u8>::new();
+pub fn encode(width: u32, height: u32, pixels: &[u8], palette: &[u32], level: Level) -> Vec<u8> {
+    encode_filtered(width, height, pixels, palette, level, None)
+}
 
-    // Header.
-    output.push(0x89);
-    output.push(b'P');
-    output.push(b'N');
-    output.push(b'G');
-    output.push(0x0d); // Cr
-    output.push(0x0a); // Lf
-    output.push(0x1a); // Eof
-    output.push(0x0a); // Lf
-
-    // Build IHDR.
-    let mut ihdr_type_and_data = Vec::<u8>::new();
-    ihdr_type_and_data.push(b'I');
-    ihdr_type_and_data.push(b'H');
-    ihdr_type_and_data.push(b'D');
-    ihdr_type_and_data.push(b'R');
-    append_msb(&mut ihdr_type_and_data, width);
-    append_msb(&mut ihdr_type_and_data, height);
-    ihdr_type_and_data.push(8); // 8bpp.
-    ihdr_type_and_data.push(6); // RGBA.
-    ihdr_type_and_data.push(0); // Compression method: zlib.
-    ihdr_type_and_data.push(0); // Filter method.
-    ihdr_type_and_data.push(0); // No interlace.
-    let ihdr_len = ihdr_type_and_data.len() - 4; // Minus the type.
-    let ihdr_crc = crc(&ihdr_type_and_data);
-    // Append IHDR to output.
-    append_msb(&mut output, ihdr_len as u32);
-    output.extend_from_slice(&ihdr_type_and_data);
-    append_msb(&mut output, ihdr_crc);
-
-    // Build image data.
-    let mut idat_data = Vec::<u8>::new();
-    let mut rgba_iter = rgba.iter();
-    for _y in 0..height {
-        idat_data.push(0); // Each line is prepended a filter type byte (0).
-        for _x in 0..width {
-            let rgba = rgba_iter.next().unwrap_or(&0);
-            idat_data.push((rgba >> 24) as u8);
-            idat_data.push((rgba >> 16) as u8); // 'as u8' truncates upper bits for us.
-            idat_data.push((rgba >> 8) as u8);
-            idat_data.push((*rgba) as u8);
-        }
-    }
-    let compressed_idat_data = to_zlib_stream(&idat_data);
-
-    // Build IDAT.
-    let mut idat_type_and_data = Vec::<u8>::new();
-    idat_type_and_data.push(b'I');
-    idat_type_and_data.push(b'D');
-    idat_type_and_data.push(b'A');
-    idat_type_and_data.push(b'T');
-    idat_type_and_data.extend_from_slice(&compressed_idat_data);
-    let idat_len = idat_type_and_data.len() - 4; // Minus the type.
-    let idat_crc = crc(&idat_type_and_data);
-    // Append IDAT to output.
-    append_msb(&mut output, idat_len as u32);
-    output.extend_from_slice(&idat_type_and_data);
-    append_msb(&mut output, idat_crc);
-
-    // IEND (no data).
-    append_msb(&mut output, 0); // Length.
-    output.push(b'I'); // Type.
-    output.push(b'E');
-    output.push(b'N');
-    output.push(b'D');
-    let iend_crc = crc(b"IEND");
-    append_msb(&mut output, iend_crc);
+// As encode, but with every row using the given filter if there is one.
+fn encode_filtered(width: u32, height: u32, pixels: &[u8], palette: &[u32], level: Level, filter: Option<u8>) -> Vec<u8> {
+    let mut output = header(width, height, palette);
+    append_chunk(&mut output, b"IDAT", &image_data(width, height, pixels, palette, level, filter));
+    append_chunk(&mut output, b"IEND", &[]); // No data.
+    output
+}
+
+// A frame of an animated png: The rectangle it replaces, and how long it shows for, in seconds as a fraction.
+pub struct Frame<'a> {
+    pub x: u32,
+    pub y: u32,
+    pub width: u32,
+    pub height: u32,
+    pub pixels: &'a [u8],
+    pub delay_numerator: u16,
+    pub delay_denominator: u16,
+    pub blend_over: bool, // Draw over the previous frame, so its clear pixels leave what was there. Otherwise it replaces it.
+}
+
+// https://wiki.mozilla.org/APNG_Specification
+// Writes an animated png that loops forever. The first frame must cover the whole image, and is also what viewers
+// without APNG support show. Later frames only need to cover what changed.
+pub fn encode_animated(width: u32, height: u32, frames: &[Frame], palette: &[u32], level: Level) -> Vec<u8> {
+    let mut output = header(width, height, palette);
+    let mut actl: Vec<u8> = Vec::new();
+    append_msb(&mut actl, frames.len() as u32);
+    append_msb(&mut actl, 0); // Loop forever.
+    append_chunk(&mut output, b"acTL", &actl);
 
+    // fcTL and fdAT chunks share a sequence number.
+    let mut sequence: u32 = 0;
+    for (index, frame) in frames.iter().enumerate() {
+        let mut fctl: Vec<u8> = Vec::new();
+        append_msb(&mut fctl, sequence);
+        append_msb(&mut fctl, frame.width);
+        append_msb(&mut fctl, frame.height);
+        append_msb(&mut fctl, frame.x);
+        append_msb(&mut fctl, frame.y);
+        fctl.extend_from_slice(&frame.delay_numerator.to_be_bytes());
+        fctl.extend_from_slice(&frame.delay_denominator.to_be_bytes());
+        fctl.push(0); // Dispose: Leave it there for the next frame to draw over.
+        fctl.push(frame.blend_over as u8);
+        append_chunk(&mut output, b"fcTL", &fctl);
+        sequence += 1;
+
+        let data = image_data(frame.width, frame.height, frame.pixels, palette, level, None);
+        if index == 0 {
+            append_chunk(&mut output, b"IDAT", &data);
+        } else {
+            let mut fdat: Vec<u8> = Vec::new();
+            append_msb(&mut fdat, sequence);
+            fdat.extend(data);
+            append_chunk(&mut output, b"fdAT", &fdat);
+            sequence += 1;
+        }
+    }
+    append_chunk(&mut output, b"IEND", &[]);
+    output
+}
+
+// The signature, IHDR, PLTE and tRNS.
+fn header(width: u32, height: u32, palette: &[u32]) -> Vec<u8> {
+    let mut output: Vec<u8> = vec![
+        0x89,
+        b'P',
+        b'N',
+        b'G',
+        0x0d, // Cr
+        0x0a, // Lf
+        0x1a, // Eof
+        0x0a, // Lf
+    ];
+
+    // IHDR.
+    let mut ihdr: Vec<u8> = Vec::new();
+    append_msb(&mut ihdr, width);
+    append_msb(&mut ihdr, height);
+    ihdr.push(bit_depth(palette));
+    ihdr.push(3); // Indexed colour.
+    ihdr.push(0); // Compression method: zlib.
+    ihdr.push(0); // Filter method.
+    ihdr.push(0); // No interlace.
+    append_chunk(&mut output, b"IHDR", &ihdr);
+
+    // PLTE, then tRNS for the alphas up to the last non-opaque one (the rest default to opaque).
+    let plte: Vec<u8> = palette.iter().flat_map(|rgba| rgba.to_be_bytes()[..3].to_vec()).collect();
+    append_chunk(&mut output, b"PLTE", &plte);
+    let alphas: Vec<u8> = palette.iter().map(|rgba| (rgba & 0xff) as u8).collect();
+    if let Some(last_clear) = alphas.iter().rposition(|a| *a != 0xff) {
+        append_chunk(&mut output, b"tRNS", &alphas[..= last_clear]);
+    }
     output
 }
+
+fn bit_depth(palette: &[u32]) -> u8 {
+    if palette.len() <= 16 { 4 } else { 8 }
+}
+
+// The packed, filtered and compressed pixels, for an IDAT or fdAT.
+fn image_data(width: u32, height: u32, pixels: &[u8], palette: &[u32], level: Level, filter: Option<u8>) -> Vec<u8> {
+    let bit_depth = bit_depth(palette);
+    let width = width as usize;
+    let row_len = if bit_depth == 4 { width.div_ceil(2) } else { width };
+    let mut raw = Vec::<u8>::with_capacity(row_len * height as usize);
+    for y in 0 .. height as usize {
+        let pixel = |x: usize| *pixels.get(y * width + x).unwrap_or(&0);
+        if bit_depth == 4 {
+            raw.extend((0..row_len).map(|i| pixel(i * 2) << 4 | if i * 2 + 1 < width { pixel(i * 2 + 1) & 0xf } else { 0 }));
+        } else {
+            raw.extend((0..width).map(pixel));
+        }
+    }
+    let filtered = filter_rows(&raw, row_len, 1, level, filter);
+    to_zlib_stream(&filtered, level)
+}
+
+// Length, type, data, then the CRC of the type and data.
+fn append_chunk(output: &mut Vec<u8>, chunk_type: &[u8; 4], data: &[u8]) {
+    let mut type_and_data: Vec<u8> = chunk_type.to_vec();
+    type_and_data.extend_from_slice(data);
+    append_msb(output, data.len() as u32);
+    output.extend_from_slice(&type_and_data);
+    append_msb(output, crc(&type_and_data));
+}
+
+// Reading:
+
+// Reads bits LSB first, the way deflate packs them.
+struct BitReader<'a> {
+    data: &'a [u8],
+    position: usize, // In bits.
+}
+
+impl<'a> BitReader<'a> {
+    fn new(data: &'a [u8]) -> Self {
+        BitReader { data, position: 0 }
+    }
+
+    fn bits(&mut self, count: u8) -> Result<u32> {
+        let mut value: u32 = 0;
+        for i in 0..count {
+            let Some(byte) = self.data.get(self.position / 8) else { bail!("The deflate stream ended early!") };
+            value |= (((byte >> (self.position % 8)) & 1) as u32) << i;
+            self.position += 1;
+        }
+        Ok(value)
+    }
+
+    // Stored blocks start on a byte boundary.
+    fn bytes(&mut self, len: usize) -> Result<&'a [u8]> {
+        let start = self.position.div_ceil(8);
+        let Some(bytes) = self.data.get(start .. start + len) else { bail!("The deflate stream ended early!") };
+        self.position = (start + len) * 8;
+        Ok(bytes)
+    }
+}
+
+// Decodes canonical Huffman codes a bit at a time, using how many codes there are of each length.
+// Based on zlib's puff.c.
+struct HuffmanDecoder {
+    counts: [u16; 16], // How many symbols have each code length.
+    symbols: Vec<u16>, // Ordered by code length, then by value.
+}
+
+impl HuffmanDecoder {
+    fn new(lengths: &[u8]) -> Self {
+        let mut counts = [0u16; 16];
+        for length in lengths {
+            counts[*length as usize] += 1;
+        }
+        counts[0] = 0;
+        let mut symbols: Vec<u16> = (0..lengths.len() as u16).filter(|s| lengths[*s as usize] != 0).collect();
+        symbols.sort_by_key(|s| lengths[*s as usize]);
+        HuffmanDecoder { counts, symbols }
+    }
+
+    fn decode(&self, reader: &mut BitReader) -> Result<u16> {
+        let mut code: usize = 0; // The bits so far.
+        let mut first: usize = 0; // The first code of this length.
+        let mut index: usize = 0; // Where this length's symbols start.
+        for count in self.counts.iter().skip(1) {
+            code |= reader.bits(1)? as usize;
+            let count = *count as usize;
+            if code < first + count {
+                return Ok(self.symbols[index + code - first])
+            }
+            index += count;
+            first = (first + count) << 1;
+            code <<= 1;
+        }
+        bail!("The deflate stream has an invalid code!")
+    }
+}
+
+// Decompresses an RFC1951 raw deflate stream.
+fn from_deflate_stream(input: &[u8]) -> Result<Vec<u8>> {
+    let mut reader = BitReader::new(input);
+    let mut output = Vec::<u8>::new();
+    loop {
+        let is_final = reader.bits(1)? == 1;
+        match reader.bits(2)? {
+            0 => {
+                let header = reader.bytes(4)?;
+                let len = u16::from_le_bytes([header[0], header[1]]);
+                let len_complement = u16::from_le_bytes([header[2], header[3]]);
+                if len != !len_complement { bail!("The deflate stream has a corrupt stored block!") }
+                output.extend_from_slice(reader.bytes(len as usize)?);
+            },
+            1 => {
+                let (literal_lengths, distance_lengths) = fixed_lengths();
+                inflate_block(&mut reader, &mut output, &HuffmanDecoder::new(&literal_lengths), &HuffmanDecoder::new(&distance_lengths))?;
+            },
+            2 => {
+                let (literal_lengths, distance_lengths) = read_dynamic_lengths(&mut reader)?;
+                inflate_block(&mut reader, &mut output, &HuffmanDecoder::new(&literal_lengths), &HuffmanDecoder::new(&distance_lengths))?;
+            },
+            _ => bail!("The deflate stream has an invalid block type!"),
+        }
+        if is_final { return Ok(output) }
+    }
+}
+
+// The code lengths for a dynamic block, which are themselves Huffman coded with run lengths.
+fn read_dynamic_lengths(reader: &mut BitReader) -> Result<(Vec<u8>, Vec<u8>)> {
+    let literal_count = reader.bits(5)? as usize + 257;
+    let distance_count = reader.bits(5)? as usize + 1;
+    let code_length_count = reader.bits(4)? as usize + 4;
+    let mut code_length_lengths = [0u8; 19];
+    for symbol in CODE_LENGTH_ORDER.iter().take(code_length_count) {
+        code_length_lengths[*symbol] = reader.bits(3)? as u8;
+    }
+    let code_length_decoder = HuffmanDecoder::new(&code_length_lengths);
+    let mut lengths = Vec::<u8>::with_capacity(literal_count + distance_count);
+    while lengths.len() < literal_count + distance_count {
+        let (length, repeat) = match code_length_decoder.decode(reader)? {
+            symbol @ 0..=15 => (symbol as u8, 1),
+            16 => {
+                let Some(previous) = lengths.last() else { bail!("The deflate stream repeats a length before the first!") };
+                (*previous, 3 + reader.bits(2)?)
+            },
+            17 => (0, 3 + reader.bits(3)?),
+            _ => (0, 11 + reader.bits(7)?),
+        };
+        lengths.extend(std::iter::repeat_n(length, repeat as usize));
+    }
+    if lengths.len() > literal_count + distance_count { bail!("The deflate stream has too many code lengths!") }
+    let distance_lengths = lengths.split_off(literal_count);
+    Ok((lengths, distance_lengths))
+}
+
+fn inflate_block(reader: &mut BitReader, output: &mut Vec<u8>, literals: &HuffmanDecoder, distances: &HuffmanDecoder) -> Result<()> {
+    loop {
+        let symbol = literals.decode(reader)? as usize;
+        if symbol < END_OF_BLOCK {
+            output.push(symbol as u8);
+            continue
+        }
+        if symbol == END_OF_BLOCK { return Ok(()) }
+        let length_code = symbol - 257;
+        if length_code >= LENGTH_BASES.len() { bail!("The deflate stream has an invalid length!") }
+        let len = LENGTH_BASES[length_code] as usize + reader.bits(LENGTH_EXTRA_BITS[length_code])? as usize;
+        let distance_code = distances.decode(reader)? as usize;
+        if distance_code >= DISTANCE_BASES.len() { bail!("The deflate stream has an invalid distance!") }
+        let distance = DISTANCE_BASES[distance_code] as usize + reader.bits(DISTANCE_EXTRA_BITS[distance_code])? as usize;
+        if distance > output.len() { bail!("The deflate stream copies from before the start!") }
+        let start = output.len() - distance;
+        for i in 0..len { // Byte by byte, as the copy can overlap what it's writing.
+            output.push(output[start + i]);
+        }
+    }
+}
+
+fn from_zlib_stream(input: &[u8]) -> Result<Vec<u8>> {
+    if input.len() < 6 || input[0] & 0xf != 8 || !u16::from_be_bytes([input[0], input[1]]).is_multiple_of(31) {
+        bail!("The png's image data isn't a zlib stream!")
+    }
+    if input[1] & 0x20 != 0 { bail!("The png's image data needs a preset dictionary, which isn't supported!") }
+    let output = from_deflate_stream(&input[2..])?;
+    let checksum = u32::from_be_bytes(input[input.len() - 4 ..].try_into().unwrap());
+    if adler32(&output) != checksum { bail!("The png's image data fails its checksum!") }
+    Ok(output)
+}
+
+// Reverses filter_rows, in place. Row lengths exclude the filter type byte.
+fn unfilter_rows(data: &mut [u8], row_len: usize, bytes_per_pixel: usize) -> Result<()> {
+    for y in 0 .. data.len() / (row_len + 1) {
+        let row_start = y * (row_len + 1) + 1;
+        let filter = data[row_start - 1];
+        for x in 0..row_len {
+            let left = if x >= bytes_per_pixel { data[row_start + x - bytes_per_pixel] } else { 0 };
+            let up = if y > 0 { data[row_start + x - row_len - 1] } else { 0 };
+            let up_left = if y > 0 && x >= bytes_per_pixel { data[row_start + x - bytes_per_pixel - row_len - 1] } else { 0 };
+            let prediction = match filter {
+                0 => 0,
+                1 => left,
+                2 => up,
+                3 => ((left as u16 + up as u16) / 2) as u8,
+                4 => paeth(left, up, up_left),
+                _ => bail!("The png has an invalid filter type!"),
+            };
+            data[row_start + x] = data[row_start + x].wrapping_add(prediction);
+        }
+    }
+    Ok(())
+}
+
+pub struct Decoded {
+    pub width: usize,
+    pub height: usize,
+    pub rgba: Vec<u32>, // 0xrrggbbaa, L->R then top to bottom.
+}
+
+// Reads any standard png (greyscale, RGB, indexed, with or without alpha, any bit depth, interlaced or not) into RGBA.
+// 16 bit samples are reduced to 8, and tRNS chunks are applied.
+pub fn decode(data: &[u8]) -> Result<Decoded> {
+    const SIGNATURE: [u8; 8] = [0x89, b'P', b'N', b'G', 0x0d, 0x0a, 0x1a, 0x0a];
+    if !data.starts_with(&SIGNATURE) { bail!("Not a png!") }
+
+    // Gather the chunks.
+    let mut header: Option<&[u8]> = None;
+    let mut palette: &[u8] = &[];
+    let mut transparency: &[u8] = &[];
+    let mut compressed = Vec::<u8>::new();
+    let mut position = SIGNATURE.len();
+    loop {
+        let Some(len_bytes) = data.get(position .. position + 4) else { bail!("The png ends without an IEND chunk!") };
+        let len = u32::from_be_bytes(len_bytes.try_into().unwrap()) as usize;
+        let Some(type_and_data) = data.get(position + 4 .. position + 8 + len) else { bail!("The png is truncated!") };
+        let Some(crc_bytes) = data.get(position + 8 + len .. position + 12 + len) else { bail!("The png is truncated!") };
+        let chunk_type = &type_and_data[..4];
+        if crc(type_and_data) != u32::from_be_bytes(crc_bytes.try_into().unwrap()) {
+            bail!("The png's {} chunk is corrupt!", String::from_utf8_lossy(chunk_type))
+        }
+        let chunk_data = &type_and_data[4..];
+        match chunk_type {
+            b"IHDR" => header = Some(chunk_data),
+            b"PLTE" => palette = chunk_data,
+            b"tRNS" => transparency = chunk_data,
+            b"IDAT" => compressed.extend_from_slice(chunk_data),
+            b"IEND" => break,
+            _ => {}, // Ancillary chunks, eg text or gamma, don't matter here.
+        }
+        position += 12 + len;
+    }
+
+    // Interpret the header.
+    let Some(header) = header.filter(|h| h.len() == 13) else { bail!("The png has no valid IHDR chunk!") };
+    let width = u32::from_be_bytes(header[0..4].try_into().unwrap()) as usize;
+    let height = u32::from_be_bytes(header[4..8].try_into().unwrap()) as usize;
+    let bit_depth = header[8] as usize;
+    let colour_type = header[9];
+    let is_interlaced = header[12] == 1;
+    let channels: usize = match (colour_type, bit_depth) {
+        (0, 1 | 2 | 4 | 8 | 16) => 1, // Greyscale.
+        (2, 8 | 16) => 3, // RGB.
+        (3, 1 | 2 | 4 | 8) => 1, // Indexed.
+        (4, 8 | 16) => 2, // Greyscale and alpha.
+        (6, 8 | 16) => 4, // RGBA.
+        _ => bail!("The png has an invalid colour type {} with bit depth {}!", colour_type, bit_depth),
+    };
+    if header[10] != 0 || header[11] != 0 || header[12] > 1 { bail!("The png has an unknown compression, filter, or interlace method!") }
+    if colour_type == 3 && palette.is_empty() { bail!("The png is indexed but has no palette!") }
+    let bits_per_pixel = channels * bit_depth;
+
+    // Converts a pixel's samples to RGBA.
+    let max = (1u32 << bit_depth) - 1;
+    let to_8_bits = |sample: u32| if bit_depth == 16 { sample >> 8 } else { sample * 255 / max };
+    let key = |index: usize| transparency.get(index * 2 .. index * 2 + 2).map(|b| u16::from_be_bytes([b[0], b[1]]) as u32);
+    let to_rgba = |samples: &[u32]| -> Result<u32> {
+        let (r, g, b, a) = match colour_type {
+            0 => {
+                let grey = to_8_bits(samples[0]);
+                let is_clear = key(0) == Some(samples[0]);
+                (grey, grey, grey, if is_clear { 0 } else { 255 })
+            },
+            2 => {
+                let is_clear = (0..3).all(|i| key(i) == Some(samples[i]));
+                (to_8_bits(samples[0]), to_8_bits(samples[1]), to_8_bits(samples[2]), if is_clear { 0 } else { 255 })
+            },
+            3 => {
+                let index = samples[0] as usize;
+                let Some(colour) = palette.get(index * 3 .. index * 3 + 3) else { bail!("The png uses colour {} which isn't in its palette!", index) };
+                let alpha = transparency.get(index).copied().unwrap_or(255);
+                (colour[0] as u32, colour[1] as u32, colour[2] as u32, alpha as u32)
+            },
+            4 => {
+                let grey = to_8_bits(samples[0]);
+                (grey, grey, grey, to_8_bits(samples[1]))
+            },
+            _ => (to_8_bits(samples[0]), to_8_bits(samples[1]), to_8_bits(samples[2]), to_8_bits(samples[3])),
+        };
+        Ok((r << 24) | (g << 16) | (b << 8) | a)
+    };
+
+    // Interlaced images come in 7 passes of every nth pixel, otherwise it's all in 1 pass.
+    // Each pass is (x start, y start, x step, y step).
+    const ADAM7: [(usize, usize, usize, usize); 7] = [(0, 0, 8, 8), (4, 0, 8, 8), (0, 4, 4, 8), (2, 0, 4, 4), (0, 2, 2, 4), (1, 0, 2, 2), (0, 1, 1, 2)];
+    let passes: &[(usize, usize, usize, usize)] = if is_interlaced { &ADAM7 } else { &[(0, 0, 1, 1)] };
+    let mut raw = from_zlib_stream(&compressed)?;
+    // Every pixel takes up its bits in the image data, so a size that couldn't fit is rejected before allocating for it.
+    let Some(pixel_count) = width.checked_mul(height) else { bail!("The png's size {}x{} is too big!", width, height) };
+    if pixel_count.checked_mul(bits_per_pixel).is_none_or(|bits| bits > raw.len() * 8) {
+        bail!("The png's image data is too short for {}x{} pixels!", width, height)
+    }
+    let mut rgba: Vec<u32> = vec![0; pixel_count];
+    let mut samples: Vec<u32> = vec![0; channels];
+    let mut pass_start: usize = 0;
+    for (x_start, y_start, x_step, y_step) in passes {
+        let pass_width = width.saturating_sub(*x_start).div_ceil(*x_step);
+        let pass_height = height.saturating_sub(*y_start).div_ceil(*y_step);
+        if pass_width == 0 || pass_height == 0 { continue } // Empty passes have no filter bytes either.
+        let row_len = (pass_width * bits_per_pixel).div_ceil(8);
+        let pass_len = (row_len + 1) * pass_height;
+        let Some(pass) = raw.get_mut(pass_start .. pass_start + pass_len) else { bail!("The png's image data is too short!") };
+        unfilter_rows(pass, row_len, bits_per_pixel.div_ceil(8))?;
+        for (y, row) in pass.chunks_exact(row_len + 1).enumerate() {
+            let row = &row[1..];
+            for x in 0..pass_width {
+                for (channel, sample) in samples.iter_mut().enumerate() {
+                    let bit = (x * channels + channel) * bit_depth;
+                    *sample = match bit_depth {
+                        16 => u16::from_be_bytes([row[bit / 8], row[bit / 8 + 1]]) as u32,
+                        _ => ((row[bit / 8] >> (8 - bit_depth - bit % 8)) as u32) & max,
+                    };
+                }
+                rgba[(y_start + y * y_step) * width + x_start + x * x_step] = to_rgba(&samples)?;
+            }
+        }
+        pass_start += pass_len;
+    }
+    Ok(Decoded { width, height, rgba })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_deflate() {
+        // What zlib makes, using fixed codes.
+        assert_eq!(to_deflate_stream(b"a", Level::default()), [0x4b, 0x04, 0x00]);
+        // A literal then a 9 byte copy from 1 back, which zlib decodes (it makes 2 literals and an 8 byte copy itself).
+        assert_eq!(to_deflate_stream(b"aaaaaaaaaa", Level::default()), [0x4b, 0x84, 0x03, 0x00]);
+    }
+
+    #[test]
+    fn test_decode() {
+        // What zlib makes for "aaaaaaaaaa".
+        assert_eq!(from_deflate_stream(&[0x4b, 0x4c, 0x84, 0x01, 0x00]).unwrap(), b"aaaaaaaaaa");
+
+        // A 3x2 round trip, with a clear pixel.
+        let palette = [0x000000ff, 0xff5555ff, 0];
+        let png = encode(3, 2, &[0, 1, 2, 2, 1, 0], &palette, Level::default());
+        let decoded = decode(&png).unwrap();
+        assert_eq!((decoded.width, decoded.height), (3, 2));
+        assert_eq!(decoded.rgba, [0x000000ff, 0xff5555ff, 0, 0, 0xff5555ff, 0x000000ff]);
+
+        // A header claiming far more pixels than the data holds is rejected before allocating them.
+        let mut huge = png.clone();
+        huge[16..24].copy_from_slice(&[0x7f, 0xff, 0xff, 0xff, 0x7f, 0xff, 0xff, 0xff]);
+        let crc_at = 8 + 4 + 4 + 13;
+        let header_crc = crc(&huge[12 .. crc_at]);
+        huge[crc_at .. crc_at + 4].copy_from_slice(&header_crc.to_be_bytes());
+        assert!(decode(&huge).err().is_some_and(|e| e.to_string().contains("too short")));
+    }
+
+    #[test]
+    fn test_round_trip_map() {
+        // Part of a real map, which compresses with long matches and dynamic codes, with every row filter.
+        let game = crate::Game::from_paths(&crate::read::Paths::discover("data/keen4").unwrap()).unwrap();
+        let region = crate::map_renderer::Region { x: 0, y: 30, width: 20, height: 15 };
+        let options = crate::map_renderer::RenderOptions { region: Some(region), ..Default::default() };
+        let image = crate::map_renderer::render(&game.maps[1], &game.graphics, &options);
+        let mut palette = crate::palette::PALETTE.to_vec();
+        if image.data.contains(&crate::palette::CLEAR_INDEX) { palette.push(0) }
+        let expected: Vec<u32> = image.data.iter().map(|p| palette[*p as usize]).collect();
+        for level in [0, 1, 6, 9] {
+            for filter in 0..5 {
+                let png = encode_filtered(image.width as u32, image.height as u32, &image.data, &palette, Level(level), Some(filter));
+                let decoded = decode(&png).unwrap();
+                assert_eq!((decoded.width, decoded.height), (image.width, image.height));
+                assert!(decoded.rgba == expected, "Level {} with filter {} didn't round trip", level, filter);
+            }
+        }
+    }
+}
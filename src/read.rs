@@ -1,22 +1,159 @@
 // This file's responsible for reading the game from disk, then passing onto the next step.
 
-use std::fs;
-use anyhow::Result;
+use std::fs::{self, File};
+use std::path::Path;
+use crate::Error;
+use crate::Game;
+use crate::lazy::LazyGame;
+use crate::lzexe;
 use crate::parse;
+use crate::source::SeekableReader;
 
-pub fn read(exe: &str, graph: &str, maps: &str) -> Result<()> {
-    println!("Reading...");
-    
-    println!("Executable: {}", exe);
-    let exe_buf = fs::read(exe)?;
-
-    println!("Graphics: {}", graph);
-    let graph_buf = fs::read(graph)?;
-
-    println!("Maps: {}", maps);
-    let maps_buf = fs::read(maps)?;
-    
-    parse::parse(&exe_buf, &graph_buf, &maps_buf)?;
-    
-    Ok(())
+// Where the game's files are.
+#[derive(Default)]
+pub struct Paths {
+    pub exe: Option<String>, // Only needed for the tables that aren't in `tables`.
+    pub graph: String,
+    pub maps: String,
+    pub audio: Option<String>,
+    pub tables: TablePaths,
+    pub best_effort: bool, // Skip broken maps and graphics chunks, listing them in Game::problems, rather than failing.
+    pub keep_chunks: bool, // Keep the decompressed graphics chunks in Graphics::chunks, to repack them.
+}
+
+// Paths to tables given as their own files, eg EGAHEAD.CK4, rather than embedded in the exe.
+#[derive(Default)]
+pub struct TablePaths {
+    pub map_head: Option<String>,
+    pub graph_head: Option<String>,
+    pub graph_dict: Option<String>,
+    pub audio_head: Option<String>,
+    pub audio_dict: Option<String>,
+}
+
+impl Paths {
+    // Finds the game's files in a folder by their names, eg KEEN4E.EXE, EGAGRAPH.CK4 and GAMEMAPS.CK4, ignoring case.
+    // The tables and audio are picked up too if they're there.
+    pub fn discover(dir: &str) -> Result<Paths, Error> {
+        let entries = fs::read_dir(dir).map_err(|error| Error::Io { path: dir.to_string(), error })?;
+        let mut names: Vec<String> = entries
+            .filter_map(|e| e.ok())
+            .filter(|e| e.path().is_file())
+            .filter_map(|e| e.file_name().into_string().ok())
+            .collect();
+        names.sort();
+        let full = |name: &String| Path::new(dir).join(name).to_string_lossy().into_owned();
+
+        // The episode comes from EGAGRAPH's extension, and the rest must match it, in case several episodes share a folder.
+        let Some(graph) = names.iter().find(|n| has_stem(n, "EGAGRAPH")) else {
+            return Err(Error::NotFound(format!("Couldn't find an EGAGRAPH.CK? in {}", dir)));
+        };
+        let extension = graph[graph.len() - 3..].to_ascii_uppercase();
+        let find = |stem: &str| names.iter().find(|n| n.eq_ignore_ascii_case(&format!("{}.{}", stem, extension))).map(full);
+        let Some(maps) = find("GAMEMAPS") else {
+            return Err(Error::NotFound(format!("Couldn't find GAMEMAPS.{} in {}", extension, dir)));
+        };
+
+        // Prefer an exe named after the episode, eg KEEN4E.EXE, over eg an installer's.
+        let exes: Vec<&String> = names.iter().filter(|n| n.to_ascii_uppercase().ends_with(".EXE")).collect();
+        let episode_number = &extension[2..];
+        let exe = exes.iter().find(|n| n.to_ascii_uppercase().starts_with(&format!("KEEN{}", episode_number)))
+            .or(exes.iter().find(|n| n.to_ascii_uppercase().starts_with("KEEN")))
+            .or(if exes.len() == 1 { exes.first() } else { None })
+            .map(|n| full(n));
+
+        Ok(Paths {
+            exe,
+            graph: full(graph),
+            maps,
+            audio: find("AUDIO"),
+            tables: TablePaths {
+                map_head: find("MAPHEAD"),
+                graph_head: find("EGAHEAD"),
+                graph_dict: find("EGADICT"),
+                audio_head: find("AUDIOHED"),
+                audio_dict: find("AUDIODCT"),
+            },
+            best_effort: false,
+            keep_chunks: false,
+        })
+    }
+}
+
+// Whether the name is eg EGAGRAPH.CK4, with any episode number.
+fn has_stem(name: &str, stem: &str) -> bool {
+    let upper = name.to_ascii_uppercase();
+    upper.len() == stem.len() + 4 && upper.starts_with(stem) && upper[stem.len()..].starts_with(".CK")
+}
+
+// Reads every file into memory, as the whole game is decoded.
+pub fn read(paths: &Paths) -> Result<Game, Error> {
+    let exe_buf = read_exe(&paths.exe)?;
+    let graph_buf = read_file(&paths.graph)?;
+    let maps_buf = read_file(&paths.maps)?;
+    let audio_buf = read_optional(&paths.audio)?;
+
+    let tables = &paths.tables;
+    let map_head_buf = read_optional(&tables.map_head)?;
+    let graph_head_buf = read_optional(&tables.graph_head)?;
+    let graph_dict_buf = read_optional(&tables.graph_dict)?;
+    let audio_head_buf = read_optional(&tables.audio_head)?;
+    let audio_dict_buf = read_optional(&tables.audio_dict)?;
+
+    parse::parse(&parse::Files {
+        exe: exe_buf.as_deref(),
+        graph: &graph_buf,
+        maps: &maps_buf,
+        audio: audio_buf.as_deref(),
+        tables: parse::ExternalTables {
+            map_head: map_head_buf.as_deref(),
+            graph_head: graph_head_buf.as_deref(),
+            graph_dict: graph_dict_buf.as_deref(),
+            audio_head: audio_head_buf.as_deref(),
+            audio_dict: audio_dict_buf.as_deref(),
+        },
+        best_effort: paths.best_effort,
+        keep_chunks: paths.keep_chunks,
+    })
+}
+
+// Like read, but EGAGRAPH and GAMEMAPS are only opened, to be read from as the LazyGame needs them.
+// The audio isn't read, as LazyGame doesn't decode it.
+pub fn open_lazy(paths: &Paths) -> Result<LazyGame<SeekableReader<File>>, Error> {
+    let exe_buf = read_exe(&paths.exe)?;
+    let graph = open_file(&paths.graph)?;
+    let maps = open_file(&paths.maps)?;
+
+    let tables = &paths.tables;
+    let map_head_buf = read_optional(&tables.map_head)?;
+    let graph_head_buf = read_optional(&tables.graph_head)?;
+    let graph_dict_buf = read_optional(&tables.graph_dict)?;
+
+    LazyGame::from_sources(exe_buf.as_deref(), graph, maps, &parse::ExternalTables {
+        map_head: map_head_buf.as_deref(),
+        graph_head: graph_head_buf.as_deref(),
+        graph_dict: graph_dict_buf.as_deref(),
+        ..Default::default()
+    })
+}
+
+// Unpacked, if it was LZEXE'd.
+fn read_exe(path: &Option<String>) -> Result<Option<Vec<u8>>, Error> {
+    let Some(mut exe_buf) = read_optional(path)? else { return Ok(None) };
+    if lzexe::is_packed(&exe_buf) {
+        exe_buf = lzexe::unpack(&exe_buf).map_err(|e| Error::Exe(format!("{:#}", e)))?;
+    }
+    Ok(Some(exe_buf))
+}
+
+fn open_file(path: &str) -> Result<SeekableReader<File>, Error> {
+    File::open(path).and_then(SeekableReader::new).map_err(|error| Error::Io { path: path.to_string(), error })
+}
+
+fn read_file(path: &str) -> Result<Vec<u8>, Error> {
+    fs::read(path).map_err(|error| Error::Io { path: path.to_string(), error })
+}
+
+fn read_optional(path: &Option<String>) -> Result<Option<Vec<u8>>, Error> {
+    path.as_deref().map(read_file).transpose()
 }
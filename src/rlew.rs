@@ -1,4 +1,4 @@
-// This file is responsible for expanding RLEW-compressed data eg maps.
+// This file is responsible for expanding RLEW-compressed data eg maps, and compressing it again.
 // https://moddingwiki.shikadi.net/wiki/Id_Software_RLEW_compression
 // https://github.com/camoto-project/gamecompjs/blob/master/formats/cmp-rlew-id.js
 // https://github.com/gerstrong/Commander-Genius/blob/master/src/fileio/compression/CRLE.cpp
@@ -6,20 +6,20 @@
 use anyhow::{Result, bail};
 
 pub fn expand_with_length_header(compressed: &[u8], key: u16) -> Result<Vec<u8>> {
-    let length = (compressed[0] as usize) + ((compressed[1] as usize) << 8);
-    let expanded = expand(&compressed[2..], key)?;
-    if expanded.len() != length { bail!("RLEW expansion resulted in an unexpected length!") }
+    let Some(header) = compressed.get(0..2) else { bail!("RLEW data is too short for its length header!") };
+    let length = (header[0] as usize) + ((header[1] as usize) << 8);
+    let expanded = expand(&compressed[2..], key, length)?;
+    if expanded.len() != length { bail!("RLEW expansion resulted in {} bytes, but the header says {}!", expanded.len(), length) }
     Ok(expanded)
 }
 
-fn expand(compressed: &[u8], key: u16) -> Result<Vec<u8>> {
-    let mut out: Vec<u8> = Vec::new();
+// Stops with an error as soon as it's longer than the given length, so a broken count can't use up all the memory.
+fn expand(compressed: &[u8], key: u16, max_len: usize) -> Result<Vec<u8>> {
+    let mut out: Vec<u8> = Vec::with_capacity(max_len);
     let mut bytes = compressed.iter();
-    loop {
+    // Reading stops when reaching EOF with an even number of bytes.
+    while let Some(le) = bytes.next() {
         // Read the word.
-        let Some(le) = bytes.next() else {
-            break // Reached EOF with an even number of bytes.
-        };
         let Some(be) = bytes.next() else {
             out.push(*le); // Reached EOF with an odd number of bytes.
             break
@@ -27,11 +27,15 @@ fn expand(compressed: &[u8], key: u16) -> Result<Vec<u8>> {
         let word = (*le as u16) + ((*be as u16) << 8);
 
         if word == key { // Repeater.
-            let Some(count_le) = bytes.next() else { bail!("Count le byte missing after RLEW key!") };
-            let Some(count_be) = bytes.next() else { bail!("Count be byte missing after RLEW key!") };
-            let Some(value_le) = bytes.next() else { bail!("Value le byte missing after RLEW key!") };
-            let Some(value_be) = bytes.next() else { bail!("Value be byte missing after RLEW key!") };
+            let offset = compressed.len() - bytes.len() - 2;
+            let Some(count_le) = bytes.next() else { bail!("Count le byte missing after RLEW key at byte {}!", offset) };
+            let Some(count_be) = bytes.next() else { bail!("Count be byte missing after RLEW key at byte {}!", offset) };
+            let Some(value_le) = bytes.next() else { bail!("Value le byte missing after RLEW key at byte {}!", offset) };
+            let Some(value_be) = bytes.next() else { bail!("Value be byte missing after RLEW key at byte {}!", offset) };
             let count = (*count_le as u16) + ((*count_be as u16) << 8);
+            if out.len() + count as usize * 2 > max_len {
+                bail!("RLEW run at byte {} of {} words goes past the {} bytes the header says!", offset, count, max_len)
+            }
             for _ in 0..count {
                 out.push(*value_le);
                 out.push(*value_be);
@@ -39,18 +43,53 @@ fn expand(compressed: &[u8], key: u16) -> Result<Vec<u8>> {
         } else {
             out.push(*le);
             out.push(*be);
+            if out.len() > max_len { bail!("RLEW data at byte {} goes past the {} bytes the header says!", compressed.len() - bytes.len() - 2, max_len) }
         }
     }
     Ok(out)
 }
 
+// The inverse of expand_with_length_header, for whole words.
+pub fn compress_with_length_header(data: &[u8], key: u16) -> Vec<u8> {
+    let mut out: Vec<u8> = Vec::with_capacity(data.len() + 2);
+    out.extend_from_slice(&(data.len() as u16).to_le_bytes());
+    out.extend(compress(data, key));
+    out
+}
+
+// Runs become the key, a count, then the word. Runs of 3 or fewer are cheaper as they are,
+// except for the key itself, which has to be written as a run to not be mistaken for one.
+fn compress(data: &[u8], key: u16) -> Vec<u8> {
+    let words: Vec<u16> = data.chunks_exact(2).map(|c| u16::from_le_bytes([c[0], c[1]])).collect();
+    let mut out: Vec<u8> = Vec::with_capacity(data.len());
+    let mut i = 0;
+    while i < words.len() {
+        let word = words[i];
+        let run = words[i..].iter().take(0xffff).take_while(|w| **w == word).count();
+        if run > 3 || word == key {
+            out.extend_from_slice(&key.to_le_bytes());
+            out.extend_from_slice(&(run as u16).to_le_bytes());
+            out.extend_from_slice(&word.to_le_bytes());
+        } else {
+            for _ in 0..run {
+                out.extend_from_slice(&word.to_le_bytes());
+            }
+        }
+        i += run;
+    }
+    if data.len() % 2 == 1 {
+        out.push(data[data.len() - 1]); // An odd byte on the end is left as is.
+    }
+    out
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
     #[test]
     fn test_no_repeat_odd_length() {
         let input: Vec<u8> = vec![12, 34, 56, 78, 90];
-        let output = expand(&input, 4321).unwrap();
+        let output = expand(&input, 4321, input.len()).unwrap();
         assert_eq!(output, input);
     }
 
@@ -58,7 +97,16 @@ mod tests {
     fn test_with_repeat() {
         let input: Vec<u8>    = vec![0x12, 0x34,  0x56, 0x78,  0x11, 0x22,  0x03, 0x00,  0x33, 0x44,  0x9a, 0xbc];
         let expected: Vec<u8> = vec![0x12, 0x34,  0x56, 0x78,  0x33, 0x44,  0x33, 0x44,  0x33, 0x44,  0x9a, 0xbc];
-        let output = expand(&input, 0x2211).unwrap();
+        let output = expand(&input, 0x2211, expected.len()).unwrap();
         assert_eq!(output, expected);
+        assert!(expand(&input, 0x2211, expected.len() - 2).is_err()); // The run is longer than the header says.
+    }
+
+    #[test]
+    fn test_compress() {
+        let input: Vec<u8> = vec![0x12, 0x34,  0x33, 0x44,  0x33, 0x44,  0x33, 0x44,  0x33, 0x44,  0x11, 0x22,  0x9a];
+        let expected: Vec<u8> = vec![0x12, 0x34,  0x11, 0x22,  0x04, 0x00,  0x33, 0x44,  0x11, 0x22,  0x01, 0x00,  0x11, 0x22,  0x9a];
+        assert_eq!(compress(&input, 0x2211), expected);
+        assert_eq!(expand(&expected, 0x2211, input.len()).unwrap(), input);
     }
 }
@@ -0,0 +1,79 @@
+// This is responsible for rendering the sounds and music into samples.
+
+use crate::opl::{self, Opl};
+use crate::parse_audio::{AdlibSound, Music, PcSound};
+
+const SOUND_RATE: usize = 140; // Sound effects play one byte per 140th of a second.
+const MUSIC_RATE: usize = 560; // Music delays are in 560ths of a second.
+const SAMPLES_PER_SOUND_TICK: usize = opl::SAMPLE_RATE as usize / SOUND_RATE;
+
+// The PC speaker is a square wave, at the timer's 1.193182MHz divided by the divisor.
+pub fn render_pc_sound(sound: &PcSound) -> Vec<i16> {
+    const TIMER_RATE: f64 = 1193182.0;
+    const VOLUME: i16 = 8000;
+    let mut output: Vec<i16> = Vec::with_capacity(sound.data.len() * SAMPLES_PER_SOUND_TICK);
+    let mut phase: f64 = 0.0; // Carried between bytes so the wave doesn't click.
+    for value in sound.data.iter() {
+        if *value == 0 {
+            output.extend(std::iter::repeat_n(0, SAMPLES_PER_SOUND_TICK));
+            continue
+        }
+        let frequency = TIMER_RATE / (*value as f64 * 60.0);
+        for _ in 0..SAMPLES_PER_SOUND_TICK {
+            output.push(if phase < 0.5 { VOLUME } else { -VOLUME });
+            phase = (phase + frequency / opl::SAMPLE_RATE as f64).fract();
+        }
+    }
+    output
+}
+
+// Sets up channel 0 with the instrument the way the game does, then plays the notes.
+pub fn render_adlib_sound(sound: &AdlibSound) -> Vec<i16> {
+    const MODULATOR: u8 = 0;
+    const CARRIER: u8 = 3;
+    const RELEASE_TICKS: usize = SOUND_RATE / 2; // Let the last note fade out.
+    let instrument = &sound.instrument;
+    let mut opl = Opl::new();
+    opl.write(0x01, 0x20); // Enable waveform selection.
+    opl.write(0x20 + MODULATOR, instrument.modulator_char);
+    opl.write(0x40 + MODULATOR, instrument.modulator_scale);
+    opl.write(0x60 + MODULATOR, instrument.modulator_attack);
+    opl.write(0x80 + MODULATOR, instrument.modulator_sustain);
+    opl.write(0xe0 + MODULATOR, instrument.modulator_wave);
+    opl.write(0x20 + CARRIER, instrument.carrier_char);
+    opl.write(0x40 + CARRIER, instrument.carrier_scale);
+    opl.write(0x60 + CARRIER, instrument.carrier_attack);
+    opl.write(0x80 + CARRIER, instrument.carrier_sustain);
+    opl.write(0xe0 + CARRIER, instrument.carrier_wave);
+    opl.write(0xc0, 0); // Sound effects use no feedback.
+
+    let key_on_block = ((sound.block & 7) << 2) | 0x20;
+    let mut output: Vec<i16> = Vec::new();
+    for value in sound.data.iter() {
+        if *value == 0 {
+            opl.write(0xb0, 0);
+        } else {
+            opl.write(0xa0, *value);
+            opl.write(0xb0, key_on_block);
+        }
+        opl.generate(SAMPLES_PER_SOUND_TICK, &mut output);
+    }
+    opl.write(0xb0, 0);
+    opl.generate(SAMPLES_PER_SOUND_TICK * RELEASE_TICKS, &mut output);
+    output
+}
+
+pub fn render_music(music: &Music) -> Vec<i16> {
+    let mut opl = Opl::new();
+    opl.write(0x01, 0x20); // Enable waveform selection, as the game does.
+    let mut output: Vec<i16> = Vec::new();
+    let mut ticks: usize = 0;
+    for (register, value, delay) in music.commands() {
+        opl.write(register, value);
+        ticks += delay as usize;
+        // Work out the sample count from the total so far, as there isn't a whole number of samples per tick.
+        let sample_count = ticks * opl::SAMPLE_RATE as usize / MUSIC_RATE;
+        opl.generate(sample_count - output.len(), &mut output);
+    }
+    output
+}
@@ -0,0 +1,86 @@
+// This is responsible for reading the big files (EGAGRAPH and GAMEMAPS) a piece at a time, so only the chunks and maps
+// that are wanted need be read. Files in memory work as they are, including memory-mapped ones (pass the mapped slice),
+// as does anything seekable, eg an open File.
+
+use std::borrow::Cow;
+use std::io::{self, Read, Seek, SeekFrom};
+use std::sync::{Mutex, PoisonError};
+
+pub trait ByteSource {
+    fn len(&self) -> usize;
+
+    // The bytes from offset, or an error if they go past the end or couldn't be read.
+    fn read_at(&self, offset: usize, len: usize) -> io::Result<Cow<'_, [u8]>>;
+
+    fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+impl ByteSource for &[u8] {
+    fn len(&self) -> usize {
+        <[u8]>::len(self)
+    }
+
+    fn read_at(&self, offset: usize, len: usize) -> io::Result<Cow<'_, [u8]>> {
+        check_range(offset, len, self.len())?;
+        Ok(Cow::Borrowed(&self[offset .. offset + len]))
+    }
+}
+
+// Reads from anything seekable, seeking to each piece as it's asked for. The reader is behind a lock so chunks can be
+// decoded on several threads at once, though their reads take turns.
+pub struct SeekableReader<R> {
+    reader: Mutex<R>,
+    len: usize,
+}
+
+impl<R: Read + Seek> SeekableReader<R> {
+    pub fn new(mut reader: R) -> io::Result<Self> {
+        let len = reader.seek(SeekFrom::End(0))? as usize;
+        Ok(SeekableReader { reader: Mutex::new(reader), len })
+    }
+}
+
+impl<R: Read + Seek> ByteSource for SeekableReader<R> {
+    fn len(&self) -> usize {
+        self.len
+    }
+
+    fn read_at(&self, offset: usize, len: usize) -> io::Result<Cow<'_, [u8]>> {
+        check_range(offset, len, self.len)?; // Before allocating, in case a broken offset asks for gigabytes.
+        let mut reader = self.reader.lock().unwrap_or_else(PoisonError::into_inner);
+        reader.seek(SeekFrom::Start(offset as u64))?;
+        let mut data = vec![0; len];
+        reader.read_exact(&mut data)?;
+        Ok(Cow::Owned(data))
+    }
+}
+
+fn check_range(offset: usize, len: usize, source_len: usize) -> io::Result<()> {
+    if offset.checked_add(len).is_none_or(|end| end > source_len) {
+        let message = format!("{} bytes at offset {} is past the end, which is {} bytes", len, offset, source_len);
+        return Err(io::Error::new(io::ErrorKind::UnexpectedEof, message))
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    #[test]
+    fn test_sources_agree() {
+        let data: Vec<u8> = (0..=255).collect();
+        let reader = SeekableReader::new(Cursor::new(data.clone())).unwrap();
+        let sources: [&dyn ByteSource; 2] = [&data.as_slice(), &reader];
+        for source in sources {
+            assert_eq!(source.len(), 256);
+            assert_eq!(&*source.read_at(10, 3).unwrap(), &[10, 11, 12]);
+            assert_eq!(source.read_at(256, 0).unwrap().len(), 0);
+            assert!(source.read_at(250, 7).is_err());
+            assert!(source.read_at(usize::MAX, 2).is_err());
+        }
+    }
+}
@@ -0,0 +1,109 @@
+// This is responsible for knowing which sprite chunks are the frames of the same animation, for each game.
+// Like info_plane.rs, these are indexes into Graphics::sprites.
+
+use crate::versions::Episode;
+use std::ops::RangeInclusive;
+
+pub struct SpriteGroup {
+    pub name: &'static str,
+    pub sprites: RangeInclusive<usize>, // The frames, in order.
+    pub tics: u32, // How long each frame shows for, in 70ths of a second.
+}
+
+pub fn groups(episode: Episode) -> &'static [SpriteGroup] {
+    match episode {
+        Episode::Keen4 => &KEEN4,
+        Episode::Keen5 => &KEEN5,
+        Episode::Keen6 => &KEEN6,
+    }
+}
+
+const fn group(name: &'static str, sprites: RangeInclusive<usize>, tics: u32) -> SpriteGroup {
+    SpriteGroup { name, sprites, tics }
+}
+
+// Keen 4's were picked out by eye from the shareware data's sprites.
+// The world map directions go anticlockwise from right.
+const KEEN4: [SpriteGroup; 55] = [
+    group("keen_run_right", 7..=10, 8),
+    group("keen_jump_right", 11..=13, 10),
+    group("keen_run_left", 15..=18, 8),
+    group("keen_jump_left", 19..=21, 10),
+    group("keen_reading", 28..=34, 20),
+    group("keen_stunned_stars", 40..=42, 10),
+    group("stunner_shot", 50..=53, 6),
+    group("stunner_shot_hit", 54..=55, 10),
+    group("keen_entering_door", 72..=76, 10),
+    group("item_sparkle", 91..=93, 10),
+    group("shikadi_soda", 103..=104, 20),
+    group("three_tooth_gum", 105..=106, 20),
+    group("candy_bar", 107..=108, 20),
+    group("jawbreaker", 109..=110, 20),
+    group("doughnut", 111..=112, 20),
+    group("ice_cream_cone", 113..=114, 20),
+    group("lifewater_flask", 115..=116, 20),
+    group("red_gem", 118..=119, 20),
+    group("yellow_gem", 120..=121, 20),
+    group("blue_gem", 122..=123, 20),
+    group("green_gem", 124..=125, 20),
+    group("neural_stunner", 127..=128, 20),
+    group("world_keen_walk_right", 130..=132, 10),
+    group("world_keen_walk_up_right", 133..=135, 10),
+    group("world_keen_walk_up", 136..=138, 10),
+    group("world_keen_walk_up_left", 139..=141, 10),
+    group("world_keen_walk_left", 142..=144, 10),
+    group("world_keen_walk_down_left", 145..=147, 10),
+    group("world_keen_walk_down", 148..=150, 10),
+    group("world_keen_walk_down_right", 151..=153, 10),
+    group("world_keen_swim_right", 156..=157, 15),
+    group("world_keen_swim_up_right", 158..=159, 15),
+    group("world_keen_swim_up", 160..=161, 15),
+    group("world_keen_swim_up_left", 162..=163, 15),
+    group("world_keen_swim_left", 164..=165, 15),
+    group("world_keen_swim_down_left", 166..=167, 15),
+    group("world_keen_swim_down", 168..=169, 15),
+    group("world_keen_swim_down_right", 170..=171, 15),
+    group("world_keen_riding_foot", 172..=173, 15),
+    group("flag_thrown", 174..=180, 8),
+    group("flag_waving", 181..=184, 10),
+    group("poison_slug", 191..=192, 12),
+    group("mad_mushroom", 201..=204, 10),
+    group("princess_lindsey", 205..=208, 12),
+    group("smoke_puff", 226..=230, 8),
+    group("eggbird_flying", 251..=254, 8),
+    group("dopefish_swim_right", 280..=281, 20),
+    group("dopefish_swim_left", 294..=295, 20),
+    group("arachnut_walking", 301..=304, 8),
+    group("lick_flames_right", 353..=355, 8),
+    group("lick_flames_left", 356..=358, 8),
+    group("thundercloud_lightning", 377..=378, 6),
+    group("berkeloid_moving", 379..=382, 10),
+    group("berkeloid_fireball", 391..=392, 8),
+    group("fire", 393..=394, 10),
+];
+// As with the info plane, Keen 5 and 6 aren't in data/ to check against yet.
+const KEEN5: [SpriteGroup; 0] = [];
+const KEEN6: [SpriteGroup; 0] = [];
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_groups() {
+        for episode in [Episode::Keen4, Episode::Keen5, Episode::Keen6] {
+            for group in groups(episode) {
+                assert!(!group.sprites.is_empty() && group.tics > 0, "{} has no frames", group.name);
+            }
+        }
+        // Only Keen 4's data is in data/ to check the frames are there.
+        let paths = crate::read::Paths::discover("data/keen4").unwrap();
+        let game = crate::Game::from_paths(&paths).unwrap();
+        for group in groups(Episode::Keen4) {
+            for sprite in group.sprites.clone() {
+                let image = game.graphics.sprites.get(sprite).and_then(|s| s.as_ref()).map(|s| &s.image);
+                assert!(image.is_some_and(|i| i.width > 0 && i.height > 0), "{} has no sprite {}", group.name, sprite);
+            }
+        }
+    }
+}
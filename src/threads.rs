@@ -0,0 +1,46 @@
+// This is responsible for spreading independent work, eg decoding chunks or encoding pngs, across all the cores.
+
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::thread;
+
+// Like items.iter().map(f).collect(), but with a thread per core, each taking the next item as it finishes its last.
+// That keeps them all busy even when the items take very different times, eg a big picture vs a tile.
+// The results are in the same order as the items.
+pub fn map<T: Sync, R: Send>(items: &[T], f: impl Fn(&T) -> R + Sync) -> Vec<R> {
+    map_on(thread::available_parallelism().map_or(1, |n| n.get()), items, f)
+}
+
+fn map_on<T: Sync, R: Send>(thread_count: usize, items: &[T], f: impl Fn(&T) -> R + Sync) -> Vec<R> {
+    let thread_count = thread_count.min(items.len());
+    if thread_count <= 1 { return items.iter().map(f).collect() }
+    let next = AtomicUsize::new(0);
+    let mut results: Vec<(usize, R)> = thread::scope(|scope| {
+        let workers: Vec<_> = (0..thread_count).map(|_| scope.spawn(|| {
+            let mut results: Vec<(usize, R)> = Vec::new();
+            loop {
+                let index = next.fetch_add(1, Ordering::Relaxed);
+                let Some(item) = items.get(index) else { return results };
+                results.push((index, f(item)));
+            }
+        })).collect();
+        // A panic in a worker carries on in the caller, as it would have without threads.
+        workers.into_iter().flat_map(|worker| worker.join().unwrap_or_else(|panic| std::panic::resume_unwind(panic))).collect()
+    });
+    results.sort_unstable_by_key(|(index, _)| *index);
+    results.into_iter().map(|(_, result)| result).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_map() {
+        let items: Vec<usize> = (0..1000).collect();
+        let doubled: Vec<usize> = items.iter().map(|i| i * 2).collect();
+        for thread_count in [1, 2, 7] {
+            assert_eq!(map_on(thread_count, &items, |i| i * 2), doubled);
+            assert!(map_on(thread_count, &[] as &[usize], |i| *i).is_empty());
+        }
+    }
+}
@@ -0,0 +1,172 @@
+// This is responsible for the tile info: Per-tile properties that follow the map offsets in MAPHEAD.
+// https://moddingwiki.shikadi.net/wiki/Tileinfo_Format
+// The background (unmasked) tiles have an animation table each, then the foreground (masked) ones have a table each for
+// which sides block Keen, their animation, and what they do when touched, eg kill him or let him climb.
+
+use anyhow::{Result, bail};
+
+const BACKGROUND_TABLES: usize = 2;
+const FOREGROUND_TABLES: usize = 7;
+
+pub struct TileInfo {
+    pub background: Vec<BackgroundTile>,
+    pub foreground: Vec<ForegroundTile>,
+}
+
+pub struct BackgroundTile {
+    pub animation_time: u8, // How many 70ths of a second to show this tile before switching to the next.
+    pub animation_offset: i8, // The next tile's number, relative to this.
+}
+
+pub struct ForegroundTile {
+    pub top: u8, // 1 is solid, values above that are slopes.
+    pub right: u8,
+    pub bottom: u8, // Also slopes, for ceilings.
+    pub left: u8,
+    pub animation_offset: i8, // Also used without a time, for the tile a switch or item turns into.
+    pub misc: u8, // The top bit draws the tile in front of sprites, the rest is its behaviour: 1 is a pole, 2 a door, 3 deadly etc.
+    pub animation_time: u8,
+}
+
+impl BackgroundTile {
+    pub fn next_tile(&self, tile: usize) -> Option<usize> {
+        next_tile(tile, self.animation_offset)
+    }
+}
+
+impl ForegroundTile {
+    pub fn next_tile(&self, tile: usize) -> Option<usize> {
+        next_tile(tile, self.animation_offset)
+    }
+
+    pub fn in_front(&self) -> bool {
+        self.misc & 0x80 != 0
+    }
+
+    pub fn behaviour(&self) -> u8 {
+        self.misc & 0x7f
+    }
+
+    pub fn is_pole(&self) -> bool {
+        self.behaviour() == 1
+    }
+
+    pub fn is_door(&self) -> bool {
+        self.behaviour() == 2
+    }
+
+    pub fn is_deadly(&self) -> bool {
+        self.behaviour() == 3
+    }
+
+    // Whether any side blocks Keen.
+    pub fn is_solid(&self) -> bool {
+        self.top != 0 || self.right != 0 || self.bottom != 0 || self.left != 0
+    }
+}
+
+fn next_tile(tile: usize, offset: i8) -> Option<usize> {
+    if offset == 0 { return None }
+    tile.checked_add_signed(offset as isize)
+}
+
+// How many bytes of tile info there are for these tile counts.
+pub fn len(background_count: usize, foreground_count: usize) -> usize {
+    background_count * BACKGROUND_TABLES + foreground_count * FOREGROUND_TABLES
+}
+
+// Data is everything after the map offsets, which can go on past the tile info, eg when it's in the exe.
+pub fn parse(data: &[u8], background_count: usize, foreground_count: usize) -> Result<TileInfo> {
+    if data.len() < len(background_count, foreground_count) {
+        bail!("Tile info is {} bytes, but {} background and {} foreground tiles need {}!", data.len(), background_count, foreground_count, len(background_count, foreground_count));
+    }
+    let (background_data, foreground_data) = data.split_at(background_count * BACKGROUND_TABLES);
+    let background_table = |table: usize| &background_data[table * background_count .. (table + 1) * background_count];
+    let foreground_table = |table: usize| &foreground_data[table * foreground_count .. (table + 1) * foreground_count];
+    let background = (0..background_count).map(|i| BackgroundTile {
+        animation_time: background_table(0)[i],
+        animation_offset: background_table(1)[i] as i8,
+    }).collect();
+    let foreground = (0..foreground_count).map(|i| ForegroundTile {
+        top: foreground_table(0)[i],
+        right: foreground_table(1)[i],
+        bottom: foreground_table(2)[i],
+        left: foreground_table(3)[i],
+        animation_offset: foreground_table(4)[i] as i8,
+        misc: foreground_table(5)[i],
+        animation_time: foreground_table(6)[i],
+    }).collect();
+    Ok(TileInfo { background, foreground })
+}
+
+impl TileInfo {
+    pub fn background_csv(&self) -> String {
+        let mut csv = String::from("tile,animation_time,next_tile\n");
+        for (index, tile) in self.background.iter().enumerate() {
+            csv += &format!("{},{},{}\n", index, tile.animation_time, optional(tile.next_tile(index)));
+        }
+        csv
+    }
+
+    pub fn foreground_csv(&self) -> String {
+        let mut csv = String::from("tile,top,right,bottom,left,behaviour,in_front,solid,deadly,pole,door,animation_time,next_tile\n");
+        for (index, tile) in self.foreground.iter().enumerate() {
+            csv += &format!("{},{},{},{},{},{},{},{},{},{},{},{},{}\n",
+                index, tile.top, tile.right, tile.bottom, tile.left, tile.behaviour(), tile.in_front(),
+                tile.is_solid(), tile.is_deadly(), tile.is_pole(), tile.is_door(), tile.animation_time, optional(tile.next_tile(index)));
+        }
+        csv
+    }
+
+    // One object per tile, in two arrays.
+    pub fn json(&self) -> String {
+        let background: Vec<String> = self.background.iter().enumerate().map(|(index, tile)| {
+            format!("    {{\"tile\": {}, \"animation_time\": {}, \"next_tile\": {}}}", index, tile.animation_time, json_optional(tile.next_tile(index)))
+        }).collect();
+        let foreground: Vec<String> = self.foreground.iter().enumerate().map(|(index, tile)| {
+            format!("    {{\"tile\": {}, \"top\": {}, \"right\": {}, \"bottom\": {}, \"left\": {}, \"behaviour\": {}, \"in_front\": {}, \"solid\": {}, \"deadly\": {}, \"pole\": {}, \"door\": {}, \"animation_time\": {}, \"next_tile\": {}}}",
+                index, tile.top, tile.right, tile.bottom, tile.left, tile.behaviour(), tile.in_front(),
+                tile.is_solid(), tile.is_deadly(), tile.is_pole(), tile.is_door(), tile.animation_time, json_optional(tile.next_tile(index)))
+        }).collect();
+        format!("{{\n  \"background\": [\n{}\n  ],\n  \"foreground\": [\n{}\n  ]\n}}\n", background.join(",\n"), foreground.join(",\n"))
+    }
+}
+
+fn optional(value: Option<usize>) -> String {
+    value.map(|v| v.to_string()).unwrap_or_default()
+}
+
+fn json_optional(value: Option<usize>) -> String {
+    value.map(|v| v.to_string()).unwrap_or("null".to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse() {
+        // 2 background tiles, then 3 foreground.
+        let data: Vec<u8> = vec![
+            0, 8, // Animation time.
+            0, 0xff, // Offset: Tile 1 goes back to 0.
+            1, 0, 0, // Top.
+            1, 0, 0, // Right.
+            0, 0, 0, // Bottom.
+            1, 0, 0, // Left.
+            0, 1, 0, // Offset.
+            0, 0x83, 1, // Misc.
+            0, 10, 0, // Animation time.
+            0xaa, // Extra, eg the rest of the exe.
+        ];
+        let info = parse(&data, 2, 3).unwrap();
+        assert_eq!(info.background[1].next_tile(1), Some(0));
+        assert_eq!(info.background[0].next_tile(0), None);
+        assert!(info.foreground[0].is_solid());
+        assert!(!info.foreground[1].is_solid());
+        assert!(info.foreground[1].is_deadly() && info.foreground[1].in_front());
+        assert_eq!(info.foreground[1].next_tile(1), Some(2));
+        assert!(info.foreground[2].is_pole());
+        assert!(parse(&data[..10], 2, 3).is_err());
+    }
+}
@@ -0,0 +1,266 @@
+// This is responsible for Tiled's tilesets (.tsx) and maps (.tmx), so the levels can be edited there and read back.
+// https://doc.mapeditor.org/en/stable/reference/tmx-map-format/
+// Each map gets background, foreground and info layers. Like the planes in GAMEMAPS, 0 means nothing's there, except in
+// the background where every cell has a tile. Info values too big for the labelled info tileset (eg switches, which hold
+// the coordinates of what they toggle) go in an object layer instead, named by their value.
+
+use crate::images::Image;
+use crate::map_renderer;
+use crate::parse_graphics::Graphics;
+use crate::parse_maps::{Map, MapTile};
+use crate::tile_info::TileInfo;
+use anyhow::{Result, bail};
+
+const TILE_SIZE: usize = 16;
+const COLUMNS: usize = 18; // How TED5 lays out Keen's tiles.
+const MAX_INFO_TILE: u16 = 255; // Bigger info values go in the object layer.
+const TICS_PER_SECOND: u32 = 70;
+
+pub const BACKGROUND_TILESET: &str = "OutputTilesetBackground";
+pub const FOREGROUND_TILESET: &str = "OutputTilesetForeground";
+pub const INFO_TILESET: &str = "OutputTilesetInfo";
+
+// A tileset's atlas, to be written as name.png, and its .tsx pointing at it.
+pub struct Tileset {
+    pub name: &'static str,
+    pub image: Image,
+    pub tsx: String,
+}
+
+// The background and foreground tiles, with their tile info as properties and animations if it's known, then the info values.
+pub fn tilesets(graphics: &Graphics, tile_info: Option<&TileInfo>) -> Vec<Tileset> {
+    let blank = Image::empty(TILE_SIZE, TILE_SIZE);
+    let backgrounds: Vec<&Image> = graphics.tiles_16_unmasked.iter().map(|t| t.as_ref().unwrap_or(&blank)).collect();
+    let foregrounds: Vec<&Image> = graphics.tiles_16_masked.iter().map(|t| t.as_ref().unwrap_or(&blank)).collect();
+    let labels: Vec<Image> = (1..=MAX_INFO_TILE).map(|value| {
+        let mut label = Image::empty(TILE_SIZE, TILE_SIZE);
+        map_renderer::draw_label(value, &mut label, 0, 0);
+        label
+    }).collect();
+
+    let background_extras = |id: usize| tile_info.map(|info| tile_extras(id, |t| info.background.get(t).map(|i| (i.animation_time, i.next_tile(t))), Vec::new())).unwrap_or_default();
+    let foreground_extras = |id: usize| {
+        let Some(info) = tile_info else { return String::new() };
+        let properties = match info.foreground.get(id) {
+            Some(tile) => [("top", tile.top), ("right", tile.right), ("bottom", tile.bottom), ("left", tile.left), ("behaviour", tile.behaviour())]
+                .into_iter().filter(|(_, value)| *value != 0).map(|(name, value)| (name, "int", value.to_string()))
+                .chain(tile.in_front().then(|| ("in_front", "bool", "true".to_string())))
+                .collect(),
+            None => Vec::new(),
+        };
+        tile_extras(id, |t| info.foreground.get(t).map(|i| (i.animation_time, i.next_tile(t))), properties)
+    };
+    vec![
+        tileset(BACKGROUND_TILESET, "Background", &backgrounds, background_extras),
+        tileset(FOREGROUND_TILESET, "Foreground", &foregrounds, foreground_extras),
+        tileset(INFO_TILESET, "Info", &labels.iter().collect::<Vec<_>>(), |_| String::new()),
+    ]
+}
+
+fn tileset(name: &'static str, title: &str, tiles: &[&Image], extras: impl Fn(usize) -> String) -> Tileset {
+    let rows = tiles.len().div_ceil(COLUMNS);
+    let mut image = Image::empty(COLUMNS * TILE_SIZE, rows * TILE_SIZE);
+    for (index, tile) in tiles.iter().enumerate() {
+        map_renderer::draw(tile, &mut image, (index % COLUMNS) * TILE_SIZE, (index / COLUMNS) * TILE_SIZE);
+    }
+    let mut tsx = format!("<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n<tileset version=\"1.10\" name=\"{}\" tilewidth=\"{}\" tileheight=\"{}\" tilecount=\"{}\" columns=\"{}\">\n",
+        title, TILE_SIZE, TILE_SIZE, tiles.len(), COLUMNS);
+    tsx += &format!(" <image source=\"{}.png\" width=\"{}\" height=\"{}\"/>\n", name, image.width, image.height);
+    for id in 0..tiles.len() {
+        tsx += &extras(id);
+    }
+    tsx += "</tileset>\n";
+    Tileset { name, image, tsx }
+}
+
+// A tile's properties, and its animation if it loops back to itself.
+fn tile_extras(id: usize, animation_of: impl Fn(usize) -> Option<(u8, Option<usize>)>, properties: Vec<(&str, &str, String)>) -> String {
+    const MAX_FRAMES: usize = 64;
+    let mut frames: Vec<(usize, u8)> = Vec::new();
+    let mut tile = id;
+    while let Some((time, Some(next))) = animation_of(tile) && time != 0 && frames.len() < MAX_FRAMES {
+        frames.push((tile, time));
+        tile = next;
+        if tile == id { break }
+    }
+    let animates = tile == id && !frames.is_empty();
+    if properties.is_empty() && !animates { return String::new() }
+    let mut xml = format!(" <tile id=\"{}\">\n", id);
+    if !properties.is_empty() {
+        xml += "  <properties>\n";
+        for (name, kind, value) in properties {
+            xml += &format!("   <property name=\"{}\" type=\"{}\" value=\"{}\"/>\n", name, kind, value);
+        }
+        xml += "  </properties>\n";
+    }
+    if animates {
+        xml += "  <animation>\n";
+        for (tile, time) in frames {
+            xml += &format!("   <frame tileid=\"{}\" duration=\"{}\"/>\n", tile, time as u32 * 1000 / TICS_PER_SECOND);
+        }
+        xml += "  </animation>\n";
+    }
+    xml + " </tile>\n"
+}
+
+// The map as a .tmx using the tilesets above, remembering which map it was so it can go back in the same place.
+pub fn tmx(map: &Map, graphics: &Graphics) -> String {
+    let background_first = 1;
+    let foreground_first = background_first + graphics.tiles_16_unmasked.len();
+    let info_first = foreground_first + graphics.tiles_16_masked.len();
+    let mut xml = format!("<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n<map version=\"1.10\" orientation=\"orthogonal\" renderorder=\"right-down\" width=\"{}\" height=\"{}\" tilewidth=\"{}\" tileheight=\"{}\" infinite=\"0\" nextlayerid=\"5\" nextobjectid=\"1\">\n",
+        map.width, map.height, TILE_SIZE, TILE_SIZE);
+    xml += &format!(" <properties>\n  <property name=\"name\" value=\"{}\"/>\n  <property name=\"index\" type=\"int\" value=\"{}\"/>\n </properties>\n", escape(&map.name), map.index);
+    for (first, name) in [(background_first, BACKGROUND_TILESET), (foreground_first, FOREGROUND_TILESET), (info_first, INFO_TILESET)] {
+        xml += &format!(" <tileset firstgid=\"{}\" source=\"{}.tsx\"/>\n", first, name);
+    }
+    let layer = |id: usize, name: &str, gid: &dyn Fn(&MapTile) -> usize| {
+        let rows: Vec<String> = map.tiles.iter().map(|row| row.iter().map(|t| gid(t).to_string()).collect::<Vec<_>>().join(",")).collect();
+        format!(" <layer id=\"{}\" name=\"{}\" width=\"{}\" height=\"{}\">\n  <data encoding=\"csv\">\n{}\n</data>\n </layer>\n", id, name, map.width, map.height, rows.join(",\n"))
+    };
+    xml += &layer(1, "Background", &|t| background_first + t.background as usize);
+    xml += &layer(2, "Foreground", &|t| t.foreground.map(|f| foreground_first + f as usize).unwrap_or(0));
+    xml += &layer(3, "Info", &|t| t.info.filter(|v| *v <= MAX_INFO_TILE).map(|v| info_first + v as usize - 1).unwrap_or(0));
+    xml += " <objectgroup id=\"4\" name=\"Info values\">\n";
+    let mut object_id = 1;
+    for (y, row) in map.tiles.iter().enumerate() {
+        for (x, tile) in row.iter().enumerate() {
+            let Some(info) = tile.info.filter(|v| *v > MAX_INFO_TILE) else { continue };
+            xml += &format!("  <object id=\"{}\" name=\"{}\" x=\"{}\" y=\"{}\" width=\"{}\" height=\"{}\"/>\n", object_id, info, x * TILE_SIZE, y * TILE_SIZE, TILE_SIZE, TILE_SIZE);
+            object_id += 1;
+        }
+    }
+    xml += " </objectgroup>\n</map>\n";
+    xml.replacen("nextobjectid=\"1\"", &format!("nextobjectid=\"{}\"", object_id), 1)
+}
+
+// Reads a .tmx written by `tmx`, after it's been edited in Tiled. The layers need to stay in CSV, and its index
+// property says which map it was.
+pub fn parse_tmx(text: &str) -> Result<Map> {
+    let Some(map_tag) = tags(text, "map").next() else { bail!("This isn't a Tiled map!") };
+    let number = |tag: &str, name: &str| -> Result<usize> {
+        let Some(value) = attribute(tag, name) else { bail!("The {} is missing!", name) };
+        // Tiled can write object positions with fractions.
+        value.parse::<f64>().map(|v| v as usize).map_err(|_| anyhow::anyhow!("The {} isn't a number: {}", name, value))
+    };
+    let width = number(map_tag, "width")?;
+    let height = number(map_tag, "height")?;
+
+    // Only the map's own properties, which come before its tilesets and layers, not any a layer or object has.
+    let children = ["<tileset", "<layer", "<objectgroup", "<imagelayer", "<group"];
+    let map_head = &map_tag[..children.iter().filter_map(|c| map_tag.find(c)).min().unwrap_or(map_tag.len())];
+    let property = |name: &str| tags(map_head, "property").find(|tag| attribute(tag, "name").as_deref() == Some(name)).and_then(|tag| attribute(tag, "value"));
+    let name = property("name").unwrap_or_default();
+    let Some(index) = property("index").and_then(|i| i.parse().ok()) else { bail!("It doesn't say which map it is, it needs its index property!") };
+
+    // Each layer's numbers are relative to its tileset's first id.
+    let first_gid = |tileset: &str| -> Result<usize> {
+        let Some(tag) = tags(text, "tileset").find(|tag| attribute(tag, "source").is_some_and(|s| s.contains(tileset))) else { bail!("The {} tileset is missing!", tileset) };
+        number(tag, "firstgid")
+    };
+    let layer = |name: &str| -> Result<Vec<usize>> {
+        let Some(tag) = tags(text, "layer").find(|tag| attribute(tag, "name").as_deref() == Some(name)) else { bail!("The {} layer is missing!", name) };
+        let Some(data_tag) = tags(tag, "data").next() else { bail!("The {} layer has no data!", name) };
+        if attribute(data_tag, "encoding").as_deref() != Some("csv") { bail!("The {} layer needs to be saved as CSV!", name) }
+        let csv = &data_tag[data_tag.find('>').unwrap_or(0) + 1 .. data_tag.find("</data>").unwrap_or(data_tag.len())];
+        let gids: Vec<usize> = csv.split(',').map(|g| g.trim().parse::<u32>().map(|g| (g & 0x1fffffff) as usize)) // Without the flip bits.
+            .collect::<Result<_, _>>().map_err(|_| anyhow::anyhow!("The {} layer has something that isn't a tile number!", name))?;
+        if gids.len() != width * height { bail!("The {} layer has {} tiles, but the map is {}x{}!", name, gids.len(), width, height) }
+        Ok(gids)
+    };
+    let (background_first, foreground_first, info_first) = (first_gid(BACKGROUND_TILESET)?, first_gid(FOREGROUND_TILESET)?, first_gid(INFO_TILESET)?);
+    // Each tileset goes up to the next one's first id. The info tileset has a tile for each value up to MAX_INFO_TILE.
+    let firsts = [background_first, foreground_first, info_first];
+    let end = |first: usize| firsts.iter().copied().filter(|f| *f > first).min().unwrap_or(first + MAX_INFO_TILE as usize);
+    let relative = |gid: usize, first: usize| -> Result<u16> {
+        (first..end(first)).contains(&gid).then(|| u16::try_from(gid - first).ok()).flatten()
+            .ok_or_else(|| anyhow::anyhow!("Tile {} is from the wrong tileset!", gid))
+    };
+    let backgrounds = layer("Background")?;
+    let foregrounds = layer("Foreground")?;
+    let infos = layer("Info")?;
+
+    let mut tiles: Vec<Vec<MapTile>> = Vec::with_capacity(height);
+    for y in 0..height {
+        let mut row: Vec<MapTile> = Vec::with_capacity(width);
+        for x in 0..width {
+            let i = y * width + x;
+            if backgrounds[i] == 0 { bail!("The background at {},{} is empty, but every cell needs one!", x, y) }
+            row.push(MapTile {
+                background: relative(backgrounds[i], background_first)?,
+                foreground: if foregrounds[i] == 0 { None } else { Some(relative(foregrounds[i], foreground_first)?).filter(|f| *f != 0) }, // Masked tile 0 is blank.
+                info: if infos[i] == 0 { None } else { Some(relative(infos[i], info_first)? + 1) },
+            });
+        }
+        tiles.push(row);
+    }
+    for tag in tags(text, "object") {
+        let Some(value) = attribute(tag, "name").and_then(|n| n.parse::<u16>().ok()) else { bail!("Info objects need their value as their name!") };
+        let (x, y) = (number(tag, "x")? / TILE_SIZE, number(tag, "y")? / TILE_SIZE);
+        let Some(tile) = tiles.get_mut(y).and_then(|row| row.get_mut(x)) else { bail!("The info object {} at {},{} is off the map!", value, x, y) };
+        tile.info = Some(value);
+    }
+    Ok(Map { index, name, width, height, tiles })
+}
+
+// Everything from after each `<name` onwards.
+fn tags<'a>(text: &'a str, name: &str) -> impl Iterator<Item = &'a str> {
+    let open = format!("<{}", name);
+    let starts: Vec<usize> = text.match_indices(&open).map(|(position, _)| position + open.len()).collect();
+    starts.into_iter()
+        .map(move |start| &text[start..])
+        .filter(|rest| rest.starts_with(|c: char| c.is_whitespace() || c == '>' || c == '/'))
+}
+
+// An attribute of the tag at the start of the text.
+fn attribute(tag: &str, name: &str) -> Option<String> {
+    let tag = &tag[..tag.find('>').unwrap_or(tag.len())];
+    let pattern = format!(" {}=\"", name);
+    let start = tag.find(&pattern)? + pattern.len();
+    let end = start + tag[start..].find('"')?;
+    Some(unescape(&tag[start..end]))
+}
+
+fn escape(text: &str) -> String {
+    text.replace('&', "&amp;").replace('<', "&lt;").replace('>', "&gt;").replace('"', "&quot;")
+}
+
+fn unescape(text: &str) -> String {
+    text.replace("&quot;", "\"").replace("&lt;", "<").replace("&gt;", ">").replace("&apos;", "'").replace("&amp;", "&")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_round_trip() {
+        let mut graphics = crate::parse_graphics::Graphics::new();
+        graphics.tiles_16_unmasked = (0..4).map(|_| None).collect();
+        graphics.tiles_16_masked = (0..3).map(|_| None).collect();
+        let tile = |background, foreground, info| MapTile { background, foreground, info };
+        let map = Map {
+            index: 7,
+            name: String::from("A & \"B\""),
+            width: 3,
+            height: 2,
+            tiles: vec![
+                vec![tile(0, None, None), tile(3, Some(1), Some(1)), tile(2, Some(2), Some(255))],
+                vec![tile(1, None, Some(256)), tile(3, Some(2), None), tile(0, None, Some(61457))],
+            ],
+        };
+        let xml = tmx(&map, &graphics);
+        assert_eq!(parse_tmx(&xml).unwrap(), map);
+        assert!(parse_tmx(&xml.replace("name=\"index\"", "name=\"number\"")).is_err()); // It needs to say which map.
+
+        // A layer's properties aren't the map's.
+        let layer_name = xml.replace("<layer id=\"1\" name=\"Background\" width=\"3\" height=\"2\">\n", "<layer id=\"1\" name=\"Background\" width=\"3\" height=\"2\">\n  <properties>\n   <property name=\"name\" value=\"Layer\"/>\n  </properties>\n");
+        assert_ne!(layer_name, xml);
+        assert_eq!(parse_tmx(&layer_name).unwrap().name, map.name);
+
+        // Tiles past the end of their layer's tileset are in the next one. The background's 4 tiles are 1-4.
+        let foreground_in_background = xml.replacen("\n1,4,3,\n", "\n1,5,3,\n", 1);
+        assert_ne!(foreground_in_background, xml);
+        assert!(parse_tmx(&foreground_in_background).is_err());
+    }
+}
@@ -3,16 +3,85 @@
 // Data from: https://moddingwiki.shikadi.net/wiki/Commander_Keen_4-6
 
 use anyhow::{Result, bail};
+use crate::huffman;
+use crate::source::ByteSource;
 
-pub fn determine(exe_size: usize) -> Result<ExeOffsets> {
-    println!("Determining version...");
-    let version = determine_exe_version(exe_size)?;
-    println!("Detected version: {:?}", version);
-    Ok(version.offsets())
+// Only the first few graphics chunks are read from graph_data, to check the dictionary decodes them.
+// Tables given as files are checked against the graphics and maps instead of the exe's own, which may not match a modded
+// EGAGRAPH or GAMEMAPS.
+pub fn determine(exe: &[u8], graph_data: &dyn ByteSource, maps_len: usize, audio_data: Option<&[u8]>, given: GivenGraphTables) -> Result<Detection> {
+    let mut detection = determine_detection(exe, graph_data, maps_len, given)?;
+    if let Some(audio_data) = audio_data {
+        determine_audio(exe, audio_data, &mut detection.offsets);
+    }
+    Ok(detection)
+}
+
+// The audio tables are only known for some builds, so they're searched for otherwise.
+fn determine_audio(exe: &[u8], audio_data: &[u8], offsets: &mut ExeOffsets) {
+    if offsets.audio.is_some_and(|audio| audio.are_valid(exe, audio_data)) { return }
+    offsets.audio = search_audio_offsets(exe, audio_data);
+}
+
+// The graph head and dictionary, and the map head, if they were given as their own files.
+#[derive(Default, Clone, Copy)]
+pub struct GivenGraphTables<'a> {
+    pub head: Option<&'a [u8]>,
+    pub dict: Option<&'a [u8]>,
+    pub map_head: Option<&'a [u8]>, // A whole MAPHEAD file, so any tile info follows the map offsets.
+}
+
+impl GivenGraphTables<'_> {
+    // Just the given map head's key and offsets, without the tile info.
+    fn map_head(&self) -> Option<&[u8]> {
+        self.map_head.map(|data| data.get(..MAP_HEAD_LEN).unwrap_or(data))
+    }
 }
 
-#[derive(Debug)]
-enum ExeVersion {
+// The result of figuring out the exe: which build it is (if known), how sure we are, and where the tables are.
+pub struct Detection {
+    pub version: Option<ExeVersion>,
+    pub confidence: Confidence,
+    pub offsets: ExeOffsets,
+}
+
+#[derive(Debug, PartialEq)]
+pub enum Confidence {
+    Fingerprint, // The whole exe hashes to a known build.
+    Size, // The exe size matches a known build, and the tables at its offsets look right.
+    Search, // Unknown build (eg patched or modded), so the tables were found by searching the exe.
+}
+
+impl Confidence {
+    pub fn description(&self) -> &'static str {
+        match self {
+            Confidence::Fingerprint => "exact match on the exe's fingerprint",
+            Confidence::Size => "matched on exe size, tables verified",
+            Confidence::Search => "unrecognised exe, tables found by searching",
+        }
+    }
+}
+
+// Tries the most certain method first, falling back to progressively looser ones.
+fn determine_detection(exe: &[u8], graph_data: &dyn ByteSource, maps_len: usize, given: GivenGraphTables) -> Result<Detection> {
+    if let Some(version) = version_from_fingerprint(fingerprint(exe)) {
+        let offsets = version.offsets();
+        return Ok(Detection { version: Some(version), confidence: Confidence::Fingerprint, offsets })
+    }
+    if let Some(version) = version_from_size(exe.len()) {
+        let offsets = version.offsets();
+        if offsets.are_valid(exe, graph_data, maps_len, given) {
+            return Ok(Detection { version: Some(version), confidence: Confidence::Size, offsets })
+        }
+    }
+    let Some(offsets) = search_offsets(exe, graph_data, maps_len, given) else {
+        bail!("Unknown exe: size {} doesn't match a known version, and its tables couldn't be found", exe.len())
+    };
+    Ok(Detection { version: None, confidence: Confidence::Search, offsets })
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ExeVersion {
     Keen4_1_0Demo,
     Keen4_1_0,
     Keen4_1_1,
@@ -29,49 +98,202 @@ enum ExeVersion {
     Keen6_1_5,
 }
 
-fn determine_exe_version(exe_size: usize) -> Result<ExeVersion> {
+// FNV-1a 64 hashes of the whole (unpacked) exe, for builds that have been checked byte-for-byte.
+// Builds not listed here still get matched by size below, so add them as they're verified.
+const FINGERPRINTS: [(u64, ExeVersion); 1] = [
+    (0x9262e8fd6c5d71d2, ExeVersion::Keen4_1_4), // Shareware, as included in data/keen4.
+];
+
+fn fingerprint(data: &[u8]) -> u64 {
+    // https://en.wikipedia.org/wiki/Fowler%E2%80%93Noll%E2%80%93Vo_hash_function
+    let mut hash: u64 = 0xcbf29ce484222325;
+    for b in data {
+        hash ^= *b as u64;
+        hash = hash.wrapping_mul(0x100000001b3);
+    }
+    hash
+}
+
+fn version_from_fingerprint(fingerprint: u64) -> Option<ExeVersion> {
+    FINGERPRINTS.iter()
+        .find(|(known, _)| *known == fingerprint)
+        .map(|(_, version)| *version)
+}
+
+fn version_from_size(exe_size: usize) -> Option<ExeVersion> {
     // Values from:
     // https://moddingwiki.shikadi.net/wiki/Commander_Keen_4-6
     match exe_size {
-        262240 => Ok(ExeVersion::Keen4_1_0Demo),
-        258064 => Ok(ExeVersion::Keen4_1_0),
-        259232 => Ok(ExeVersion::Keen4_1_1),
-        259920 => Ok(ExeVersion::Keen4_1_2),
-        263488 => Ok(ExeVersion::Keen4_1_4),
-        264864 => Ok(ExeVersion::Keen4_1_4g),
-        262176 => Ok(ExeVersion::Keen5_1_0),
-        266096 => Ok(ExeVersion::Keen5_1_4),
-        267616 => Ok(ExeVersion::Keen5_1_4g),
-        236112 => Ok(ExeVersion::Keen6_1_0Demo),
-        238368 => Ok(ExeVersion::Keen6_1_0Promo),
-        266032 => Ok(ExeVersion::Keen6_1_0),
-        271696 => Ok(ExeVersion::Keen6_1_4),
-        270896 => Ok(ExeVersion::Keen6_1_5),
-        _ => bail!("Unknown exe size: {}", exe_size),
-    }    
+        262240 => Some(ExeVersion::Keen4_1_0Demo),
+        258064 => Some(ExeVersion::Keen4_1_0),
+        259232 => Some(ExeVersion::Keen4_1_1),
+        259920 => Some(ExeVersion::Keen4_1_2),
+        263488 => Some(ExeVersion::Keen4_1_4),
+        264864 => Some(ExeVersion::Keen4_1_4g),
+        262176 => Some(ExeVersion::Keen5_1_0),
+        266096 => Some(ExeVersion::Keen5_1_4),
+        267616 => Some(ExeVersion::Keen5_1_4g),
+        236112 => Some(ExeVersion::Keen6_1_0Demo),
+        238368 => Some(ExeVersion::Keen6_1_0Promo),
+        266032 => Some(ExeVersion::Keen6_1_0),
+        271696 => Some(ExeVersion::Keen6_1_4),
+        270896 => Some(ExeVersion::Keen6_1_5),
+        _ => None,
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Episode {
+    Keen4,
+    Keen5,
+    Keen6,
+}
+
+impl Episode {
+    // The data files' extension, eg EGAGRAPH.CK4.
+    pub fn extension(&self) -> &'static str {
+        match self {
+            Episode::Keen4 => "CK4",
+            Episode::Keen5 => "CK5",
+            Episode::Keen6 => "CK6",
+        }
+    }
+
+    // How many background (unmasked) and foreground (masked) 16x16 tiles there are, ie NUMTILE16 and NUMTILE16M.
+    pub fn tile_16_counts(&self) -> (usize, usize) {
+        match self {
+            Episode::Keen4 => (1296, 2916),
+            Episode::Keen5 => (1512, 2952),
+            Episode::Keen6 => (2376, 2736),
+        }
+    }
+
+    // What each extern chunk is, in order, as in the GFXE header from ORDERSCREEN on.
+    // Keen 4's are checked against the shareware 1.4 data. Keen 5 and 6's haven't been checked against real data yet,
+    // so like other versions with a different number of externs, they're only used if the count matches.
+    fn extern_kinds(&self) -> &'static [ExternKind] {
+        use ExternKind::*;
+        match self {
+            Episode::Keen4 => &[
+                Screen, // ORDERSCREEN, shown on quitting.
+                Terminator, Terminator, // BIGCOMMANDER and BIGKEEN, for the terminator intro.
+                Screen, // OUTOFMEM.
+                Article, Article, Article, Article, Article, // T_HELPART, T_CONTRART, T_STORYART, T_IDART and T_ENDART.
+                Article, Article, // The shareware's level-not-in-the-demo note, and T_ORDERART.
+                Demo, Demo, Demo, Demo, Demo, // T_DEMO0 to T_DEMO4.
+            ],
+            Episode::Keen5 | Episode::Keen6 => &[
+                Screen, Terminator, Terminator, Screen,
+                Article, Article, Article, Article, Article,
+                Demo, Demo, Demo, Demo, Demo,
+            ],
+        }
+    }
+
+    pub const ALL: [Episode; 3] = [Episode::Keen4, Episode::Keen5, Episode::Keen6];
+}
+
+// The kinds of extern chunks at the end of EGAGRAPH, see parse_misc.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ExternKind {
+    Screen,
+    Terminator,
+    Article,
+    Demo,
+}
+
+// Where each kind of chunk is in EGAGRAPH, as the games' GFXE_CK?.H defines with STARTPICS, STARTTILE16 etc:
+// The picture, masked picture and sprite tables, the fonts, pictures, masked pictures and sprites, all the 8x8 tiles in
+// one chunk and the masked ones in another, a chunk per 16x16 tile, then the externs (text screens, articles, demos etc).
+// The picture and sprite counts differ between versions, and come from their tables, which have an entry for each.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ChunkLayout {
+    pub pictures: usize, // NUMPICS.
+    pub masked_pictures: usize, // NUMPICM.
+    pub sprites: usize, // NUMSPRITES.
+    pub tiles_16: usize, // NUMTILE16.
+    pub masked_tiles_16: usize, // NUMTILE16M, including the empty masked tile 0, as 0 in the foreground plane means none.
+    pub chunk_count: usize, // NUMCHUNKS.
+    pub extern_kinds: Option<&'static [ExternKind]>, // What each extern is, if known for this many externs.
+}
+
+impl ChunkLayout {
+    pub const TABLES: usize = 3;
+    pub const FONTS: usize = 3;
+    pub const TILES_8: usize = 104; // NUMTILE8, the same in all three games.
+    pub const MASKED_TILES_8: usize = 20; // NUMTILE8M.
+
+    // The episode's layout, checking its tiles fit in the chunks.
+    pub fn new(episode: Episode, pictures: usize, masked_pictures: usize, sprites: usize, chunk_count: usize) -> Result<Self> {
+        let (tiles_16, masked_tiles_16) = episode.tile_16_counts();
+        let mut layout = ChunkLayout { pictures, masked_pictures, sprites, tiles_16, masked_tiles_16, chunk_count, extern_kinds: None };
+        if layout.start_externs() > chunk_count {
+            bail!("{:?}'s tiles would end at chunk {}, but there are only {} chunks!", episode, layout.start_externs(), chunk_count)
+        }
+        let extern_kinds = episode.extern_kinds();
+        if extern_kinds.len() == layout.externs() {
+            layout.extern_kinds = Some(extern_kinds);
+        }
+        Ok(layout)
+    }
+
+    // For when the version isn't known: The episode whose tiles fit with the fewest chunks left over.
+    pub fn guess(pictures: usize, masked_pictures: usize, sprites: usize, chunk_count: usize) -> Result<(Episode, Self)> {
+        Episode::ALL.iter()
+            .filter_map(|e| ChunkLayout::new(*e, pictures, masked_pictures, sprites, chunk_count).ok().map(|l| (*e, l)))
+            .min_by_key(|(_, layout)| layout.externs())
+            .ok_or_else(|| anyhow::anyhow!("None of the games' tiles fit in {} chunks!", chunk_count))
+    }
+
+    pub fn start_fonts(&self) -> usize { Self::TABLES }
+    pub fn start_pictures(&self) -> usize { self.start_fonts() + Self::FONTS }
+    pub fn start_masked_pictures(&self) -> usize { self.start_pictures() + self.pictures }
+    pub fn start_sprites(&self) -> usize { self.start_masked_pictures() + self.masked_pictures }
+    pub fn start_tile_8(&self) -> usize { self.start_sprites() + self.sprites }
+    pub fn start_masked_tile_8(&self) -> usize { self.start_tile_8() + 1 }
+    pub fn start_tile_16(&self) -> usize { self.start_masked_tile_8() + 1 }
+    pub fn start_masked_tile_16(&self) -> usize { self.start_tile_16() + self.tiles_16 }
+    pub fn start_externs(&self) -> usize { self.start_masked_tile_16() + self.masked_tiles_16 }
+    pub fn externs(&self) -> usize { self.chunk_count - self.start_externs() }
+
+    // What the given extern (counting from the first) is, or None if this version's externs aren't known.
+    pub fn extern_kind(&self, index: usize) -> Option<ExternKind> {
+        self.extern_kinds.and_then(|kinds| kinds.get(index).copied())
+    }
 }
 
 impl ExeVersion {
+    pub fn episode(&self) -> Episode {
+        match self {
+            ExeVersion::Keen4_1_0Demo | ExeVersion::Keen4_1_0 | ExeVersion::Keen4_1_1 | ExeVersion::Keen4_1_2 |
+                ExeVersion::Keen4_1_4 | ExeVersion::Keen4_1_4g => Episode::Keen4,
+            ExeVersion::Keen5_1_0 | ExeVersion::Keen5_1_4 | ExeVersion::Keen5_1_4g => Episode::Keen5,
+            ExeVersion::Keen6_1_0Demo | ExeVersion::Keen6_1_0Promo | ExeVersion::Keen6_1_0 |
+                ExeVersion::Keen6_1_4 | ExeVersion::Keen6_1_5 => Episode::Keen6,
+        }
+    }
+
     fn offsets(&self) -> ExeOffsets {
         match self {
-            ExeVersion::Keen4_1_0Demo => ExeOffsets { map_head_offset: 136336, map_head_len: 402, graph_head_offset: 159744, graph_head_len: 18780, graph_dict_offset: 229382, graph_dict_len: 1024 },
-            ExeVersion::Keen4_1_0 => ExeOffsets { map_head_offset: 156592, map_head_len: 402, graph_head_offset: 142352, graph_head_len: 14232, graph_dict_offset: 225782, graph_dict_len: 1024 },
-            ExeVersion::Keen4_1_1 => ExeOffsets { map_head_offset: 157568, map_head_len: 402, graph_head_offset: 143328, graph_head_len: 14232, graph_dict_offset: 226946, graph_dict_len: 1024 },
-            ExeVersion::Keen4_1_2 => ExeOffsets { map_head_offset: 158176, map_head_len: 402, graph_head_offset: 143920, graph_head_len: 14256, graph_dict_offset: 227636, graph_dict_len: 1024 },
-            ExeVersion::Keen4_1_4 => ExeOffsets { map_head_offset: 161328, map_head_len: 402, graph_head_offset: 147072, graph_head_len: 14256, graph_dict_offset: 231158, graph_dict_len: 1024 },
-            ExeVersion::Keen4_1_4g => ExeOffsets { map_head_offset: 162576, map_head_len: 402, graph_head_offset: 148320, graph_head_len: 14256, graph_dict_offset: 232406, graph_dict_len: 1024 },
-            ExeVersion::Keen5_1_0 => ExeOffsets { map_head_offset: 161664, map_head_len: 402, graph_head_offset: 146864, graph_head_len: 14796, graph_dict_offset: 229258, graph_dict_len: 1024 },
-            ExeVersion::Keen5_1_4 => ExeOffsets { map_head_offset: 165264, map_head_len: 402, graph_head_offset: 150464, graph_head_len: 14796, graph_dict_offset: 233156, graph_dict_len: 1024 },
-            ExeVersion::Keen5_1_4g => ExeOffsets { map_head_offset: 166640, map_head_len: 402, graph_head_offset: 151840, graph_head_len: 14796, graph_dict_offset: 234532, graph_dict_len: 1024 },
-            ExeVersion::Keen6_1_0Demo => ExeOffsets { map_head_offset: 137568, map_head_len: 402, graph_head_offset: 124464, graph_head_len: 13098, graph_dict_offset: 204352, graph_dict_len: 1024 },
-            ExeVersion::Keen6_1_0Promo => ExeOffsets { map_head_offset: 139920, map_head_len: 402, graph_head_offset: 126816, graph_head_len: 13098, graph_dict_offset: 206614, graph_dict_len: 1024 },
-            ExeVersion::Keen6_1_0 => ExeOffsets { map_head_offset: 157776, map_head_len: 402, graph_head_offset: 141088, graph_head_len: 16683, graph_dict_offset: 231698, graph_dict_len: 1024 },
-            ExeVersion::Keen6_1_4 => ExeOffsets { map_head_offset: 162944, map_head_len: 402, graph_head_offset: 146256, graph_head_len: 16683, graph_dict_offset: 237294, graph_dict_len: 1024 },
-            ExeVersion::Keen6_1_5 => ExeOffsets { map_head_offset: 181984, map_head_len: 402, graph_head_offset: 165296, graph_head_len: 16683, graph_dict_offset: 236366, graph_dict_len: 1024 },
+            ExeVersion::Keen4_1_0Demo => ExeOffsets { map_head_offset: 136336, map_head_len: 402, graph_head_offset: 159744, graph_head_len: 18780, graph_dict_offset: 229382, graph_dict_len: 1024, audio: None },
+            ExeVersion::Keen4_1_0 => ExeOffsets { map_head_offset: 156592, map_head_len: 402, graph_head_offset: 142352, graph_head_len: 14232, graph_dict_offset: 225782, graph_dict_len: 1024, audio: None },
+            ExeVersion::Keen4_1_1 => ExeOffsets { map_head_offset: 157568, map_head_len: 402, graph_head_offset: 143328, graph_head_len: 14232, graph_dict_offset: 226946, graph_dict_len: 1024, audio: None },
+            ExeVersion::Keen4_1_2 => ExeOffsets { map_head_offset: 158176, map_head_len: 402, graph_head_offset: 143920, graph_head_len: 14256, graph_dict_offset: 227636, graph_dict_len: 1024, audio: None },
+            ExeVersion::Keen4_1_4 => ExeOffsets { map_head_offset: 161328, map_head_len: 402, graph_head_offset: 147072, graph_head_len: 14256, graph_dict_offset: 231158, graph_dict_len: 1024, audio: Some(AudioOffsets { head_offset: 146416, head_len: 652, dict_offset: 230134, dict_len: 1024 }) },
+            ExeVersion::Keen4_1_4g => ExeOffsets { map_head_offset: 162576, map_head_len: 402, graph_head_offset: 148320, graph_head_len: 14256, graph_dict_offset: 232406, graph_dict_len: 1024, audio: None },
+            ExeVersion::Keen5_1_0 => ExeOffsets { map_head_offset: 161664, map_head_len: 402, graph_head_offset: 146864, graph_head_len: 14796, graph_dict_offset: 229258, graph_dict_len: 1024, audio: None },
+            ExeVersion::Keen5_1_4 => ExeOffsets { map_head_offset: 165264, map_head_len: 402, graph_head_offset: 150464, graph_head_len: 14796, graph_dict_offset: 233156, graph_dict_len: 1024, audio: None },
+            ExeVersion::Keen5_1_4g => ExeOffsets { map_head_offset: 166640, map_head_len: 402, graph_head_offset: 151840, graph_head_len: 14796, graph_dict_offset: 234532, graph_dict_len: 1024, audio: None },
+            ExeVersion::Keen6_1_0Demo => ExeOffsets { map_head_offset: 137568, map_head_len: 402, graph_head_offset: 124464, graph_head_len: 13098, graph_dict_offset: 204352, graph_dict_len: 1024, audio: None },
+            ExeVersion::Keen6_1_0Promo => ExeOffsets { map_head_offset: 139920, map_head_len: 402, graph_head_offset: 126816, graph_head_len: 13098, graph_dict_offset: 206614, graph_dict_len: 1024, audio: None },
+            ExeVersion::Keen6_1_0 => ExeOffsets { map_head_offset: 157776, map_head_len: 402, graph_head_offset: 141088, graph_head_len: 16683, graph_dict_offset: 231698, graph_dict_len: 1024, audio: None },
+            ExeVersion::Keen6_1_4 => ExeOffsets { map_head_offset: 162944, map_head_len: 402, graph_head_offset: 146256, graph_head_len: 16683, graph_dict_offset: 237294, graph_dict_len: 1024, audio: None },
+            ExeVersion::Keen6_1_5 => ExeOffsets { map_head_offset: 181984, map_head_len: 402, graph_head_offset: 165296, graph_head_len: 16683, graph_dict_offset: 236366, graph_dict_len: 1024, audio: None },
         }
     }
 }
 
+#[derive(Debug, PartialEq)]
 pub struct ExeOffsets {
     pub map_head_offset: usize,
     pub map_head_len: usize,
@@ -79,4 +301,273 @@ pub struct ExeOffsets {
     pub graph_head_len: usize,
     pub graph_dict_offset: usize,
     pub graph_dict_len: usize,
+    pub audio: Option<AudioOffsets>, // AUDIOHED and AUDIODICT, if known for this build or found by searching.
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct AudioOffsets {
+    pub head_offset: usize,
+    pub head_len: usize,
+    pub dict_offset: usize,
+    pub dict_len: usize,
+}
+
+const MAP_HEAD_LEN: usize = 402; // RLEW key, then 100 map offsets.
+const DICT_LEN: usize = 1024; // 256 nodes, the last of which is unused.
+const RLEW_KEY: [u8; 2] = [0xcd, 0xab]; // 0xABCD little-endian.
+
+impl ExeOffsets {
+    // Checks the tables at these offsets look like the real thing, using the given ones in place of the exe's.
+    fn are_valid(&self, exe: &[u8], graph_data: &dyn ByteSource, maps_len: usize, given: GivenGraphTables) -> bool {
+        let Some(map_head) = given.map_head().or(exe.get(self.map_head_offset .. self.map_head_offset + self.map_head_len)) else { return false };
+        let Some(graph_head) = given.head.or(exe.get(self.graph_head_offset .. self.graph_head_offset + self.graph_head_len)) else { return false };
+        let Some(graph_dict) = given.dict.or(exe.get(self.graph_dict_offset .. self.graph_dict_offset + self.graph_dict_len)) else { return false };
+        is_map_head(map_head, maps_len)
+            && graph_head_len_at(graph_head, 0, graph_data.len()) == Some(graph_head.len())
+            && is_huffman_dict(graph_dict)
+            && dict_fits_graph(graph_dict, graph_head, graph_data)
+    }
+}
+
+impl AudioOffsets {
+    fn are_valid(&self, exe: &[u8], audio_data: &[u8]) -> bool {
+        let Some(audio_head) = exe.get(self.head_offset .. self.head_offset + self.head_len) else { return false };
+        let Some(audio_dict) = exe.get(self.dict_offset .. self.dict_offset + self.dict_len) else { return false };
+        audio_head_len_before(exe, self.head_offset + self.head_len - 4, audio_data.len()) == Some(audio_head.len())
+            && is_huffman_dict(audio_dict)
+            && dict_fits_chunks(audio_dict, &audio_head_offsets(audio_head), &audio_data)
+    }
+}
+
+// Looks through the whole exe for the three tables, for when the build isn't a known one.
+// Tables that were given aren't searched for, and are left as empty ranges.
+fn search_offsets(exe: &[u8], graph_data: &dyn ByteSource, maps_len: usize, given: GivenGraphTables) -> Option<ExeOffsets> {
+    let (map_head_offset, map_head_len) = match given.map_head() {
+        Some(map_head) => if is_map_head(map_head, maps_len) { (0, 0) } else { return None },
+        None => (exe.windows(MAP_HEAD_LEN).position(|map_head| is_map_head(map_head, maps_len))?, MAP_HEAD_LEN),
+    };
+    let (graph_head_offset, graph_head_len) = match given.head {
+        Some(_) => (0, 0),
+        None => (0 .. exe.len()).find_map(|o| graph_head_len_at(exe, o, graph_data.len()).map(|len| (o, len)))?,
+    };
+    let graph_head = given.head.unwrap_or(&exe[graph_head_offset .. graph_head_offset + graph_head_len]);
+    // There's more than one huffman dictionary in the exe (eg audio), so check it decodes the graphics.
+    let (graph_dict_offset, graph_dict_len) = match given.dict {
+        Some(dict) => if dict_fits_graph(dict, graph_head, graph_data) { (0, 0) } else { return None },
+        None => (exe.windows(DICT_LEN).position(|dict| is_huffman_dict(dict) && dict_fits_graph(dict, graph_head, graph_data))?, DICT_LEN),
+    };
+    Some(ExeOffsets {
+        map_head_offset,
+        map_head_len,
+        graph_head_offset,
+        graph_head_len,
+        graph_dict_offset,
+        graph_dict_len,
+        audio: None,
+    })
+}
+
+// Looks through the whole exe for the audio head, which ends with the audio file's length, and the dictionary that decodes it.
+fn search_audio_offsets(exe: &[u8], audio_data: &[u8]) -> Option<AudioOffsets> {
+    let audio_len = audio_data.len();
+    let (head_offset, head_len) = (0 ..= exe.len().saturating_sub(4))
+        .find_map(|end| audio_head_len_before(exe, end, audio_len).map(|len| (end + 4 - len, len)))?;
+    let chunk_offsets = audio_head_offsets(&exe[head_offset .. head_offset + head_len]);
+    let dict_offset = exe.windows(DICT_LEN)
+        .position(|dict| is_huffman_dict(dict) && dict_fits_chunks(dict, &chunk_offsets, &audio_data))?;
+    Some(AudioOffsets { head_offset, head_len, dict_offset, dict_len: DICT_LEN })
+}
+
+// An audio head is 4-byte offsets, starting at 0 and ending with the AUDIO file length.
+// Unlike the graph head, these don't always ascend: Keen 4's unused digitised sound chunks point past the music.
+// So this works backwards from the end to the 0 at the start, and returns the length in bytes if there's one ending at the given offset.
+fn audio_head_len_before(data: &[u8], end: usize, audio_len: usize) -> Option<usize> {
+    const MIN_CHUNKS: usize = 10; // Avoid matching a few stray values.
+    let value_at = |offset: usize| data.get(offset .. offset + 4).map(|c| u32::from_le_bytes(c.try_into().unwrap()) as usize);
+    if value_at(end)? != audio_len { return None }
+    let mut start = end;
+    loop {
+        start = start.checked_sub(4)?;
+        let value = value_at(start)?;
+        if value > audio_len { return None }
+        if value == 0 { break }
+    }
+    let len = end + 4 - start;
+    if len / 4 > MIN_CHUNKS { Some(len) } else { None }
+}
+
+pub fn audio_head_offsets(data: &[u8]) -> Vec<usize> {
+    data.chunks_exact(4).map(|c| u32::from_le_bytes(c.try_into().unwrap()) as usize).collect()
+}
+
+// A map head is the RLEW key followed by offsets into gamemaps, which ascend apart from zeroes for missing maps.
+fn is_map_head(data: &[u8], maps_len: usize) -> bool {
+    if data.len() != MAP_HEAD_LEN || data[0..2] != RLEW_KEY { return false }
+    let mut previous: usize = 0;
+    for c in data[2..].chunks_exact(4) {
+        let offset = u32::from_le_bytes(c.try_into().unwrap()) as usize;
+        if offset == 0 { continue }
+        if offset <= previous || offset >= maps_len { return false }
+        previous = offset;
+    }
+    previous != 0 // Must have at least one map.
+}
+
+// A graph head is 3-byte offsets starting at 0, ascending (or 0xffffff for empty chunks), ending at the EGAGRAPH length.
+// Returns the length of the graph head in bytes if there's one at the given offset.
+fn graph_head_len_at(data: &[u8], offset: usize, graph_len: usize) -> Option<usize> {
+    const MIN_CHUNKS: usize = 100; // Avoid matching a few stray zeroes.
+    let mut previous: usize = 0;
+    for (index, c) in data.get(offset..)?.chunks_exact(3).enumerate() {
+        let value = (c[0] as usize) + ((c[1] as usize) << 8) + ((c[2] as usize) << 16);
+        if index == 0 {
+            if value != 0 { return None }
+            continue
+        }
+        if value == 0xffffff { continue }
+        if value <= previous || value > graph_len { return None } // Non-empty chunks are at least 4 bytes, so offsets strictly ascend.
+        if value == graph_len {
+            return if index >= MIN_CHUNKS { Some((index + 1) * 3) } else { None }
+        }
+        previous = value;
+    }
+    None
+}
+
+// A huffman dictionary is 255 nodes, each child either a leaf byte or an earlier node (+256),
+// with every byte value appearing as a leaf exactly once.
+fn is_huffman_dict(data: &[u8]) -> bool {
+    if data.len() != DICT_LEN { return false }
+    let mut seen_leaves = [false; 256];
+    for (index, node) in data.chunks_exact(4).take(255).enumerate() {
+        for child in node.chunks_exact(2) {
+            let value = (child[0] as usize) + ((child[1] as usize) << 8);
+            if value < 256 {
+                if seen_leaves[value] { return false }
+                seen_leaves[value] = true;
+            } else if value - 256 >= index {
+                return false
+            }
+        }
+    }
+    true // 255 nodes have 510 children, of which 254 are nodes, so 256 unique leaves means all were seen.
+}
+
+fn dict_fits_graph(dict: &[u8], graph_head: &[u8], graph_data: &dyn ByteSource) -> bool {
+    let offsets: Vec<usize> = graph_head
+        .chunks_exact(3)
+        .map(|c| (c[0] as usize) + ((c[1] as usize) << 8) + ((c[2] as usize) << 16))
+        .filter(|o| *o != 0xffffff)
+        .collect();
+    dict_fits_chunks(dict, &offsets, graph_data)
+}
+
+// Checks the dictionary decodes the first few chunks (which have length headers) using up their compressed data.
+// The wrong dictionary either runs out of data or leaves lots over.
+fn dict_fits_chunks(dict: &[u8], offsets: &[usize], data: &dyn ByteSource) -> bool {
+    const CHUNKS_TO_CHECK: usize = 8;
+    const MAX_SLACK: usize = 4; // Compressed chunks are sometimes padded by a few bytes.
+    let Ok(dict) = huffman::parse_dict(dict) else { return false };
+    offsets.windows(2).filter(|w| w[1] > w[0]).take(CHUNKS_TO_CHECK).all(|w| {
+        let Ok(chunk) = data.read_at(w[0], w[1] - w[0]) else { return false };
+        if chunk.len() < 4 { return false }
+        let len = u32::from_le_bytes(chunk[0..4].try_into().unwrap()) as usize;
+        let compressed = &chunk[4..];
+        huffman::bits_needed(compressed, &dict, len).is_some_and(|bits| compressed.len() - bits.div_ceil(8) <= MAX_SLACK)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn keen4() -> (Vec<u8>, Vec<u8>, usize) {
+        let exe = std::fs::read("data/keen4/keen4.exe").unwrap();
+        let graph = std::fs::read("data/keen4/egagraph.ck4").unwrap();
+        let maps_len = std::fs::metadata("data/keen4/gamemaps.ck4").unwrap().len() as usize;
+        (exe, graph, maps_len)
+    }
+
+    #[test]
+    fn test_fingerprint() {
+        let (exe, graph, maps_len) = keen4();
+        let detection = determine_detection(&exe, &graph.as_slice(), maps_len, GivenGraphTables::default()).unwrap();
+        assert_eq!(detection.version, Some(ExeVersion::Keen4_1_4));
+        assert_eq!(detection.confidence, Confidence::Fingerprint);
+    }
+
+    #[test]
+    fn test_search_finds_known_offsets() {
+        let (mut exe, graph, maps_len) = keen4();
+        exe.extend_from_slice(&[0; 100]); // Pad it so neither the fingerprint nor size match.
+        let audio = std::fs::read("data/keen4/audio.ck4").unwrap();
+        let detection = determine(&exe, &graph.as_slice(), maps_len, Some(&audio), GivenGraphTables::default()).unwrap();
+        assert_eq!(detection.version, None);
+        assert_eq!(detection.confidence, Confidence::Search);
+        assert_eq!(detection.offsets, ExeVersion::Keen4_1_4.offsets());
+    }
+
+    #[test]
+    fn test_search_finds_table_ending_at_eof() {
+        let (mut exe, graph, maps_len) = keen4();
+        let expected = ExeVersion::Keen4_1_4.offsets();
+        exe.truncate(expected.graph_dict_offset + expected.graph_dict_len); // The dictionary is the last table.
+        let offsets = search_offsets(&exe, &graph.as_slice(), maps_len, GivenGraphTables::default()).unwrap();
+        assert_eq!(offsets.graph_dict_offset, expected.graph_dict_offset);
+    }
+
+    #[test]
+    fn test_given_graph_head_checked_instead_of_exe() {
+        // A modded EGAGRAPH no longer matches the exe's graph head, but does match the one given with it.
+        let (mut exe, mut graph, maps_len) = keen4();
+        graph.extend_from_slice(&[0; 100]);
+        let offsets = ExeVersion::Keen4_1_4.offsets();
+        let mut graph_head = exe[offsets.graph_head_offset .. offsets.graph_head_offset + offsets.graph_head_len].to_vec();
+        let end = graph_head.len() - 3;
+        graph_head[end .. end + 3].copy_from_slice(&(graph.len() as u32).to_le_bytes()[0..3]);
+        *exe.last_mut().unwrap() ^= 1; // So it only matches by size.
+        assert!(determine(&exe, &graph.as_slice(), maps_len, None, GivenGraphTables::default()).is_err());
+        let given = GivenGraphTables { head: Some(&graph_head), ..Default::default() };
+        let detection = determine(&exe, &graph.as_slice(), maps_len, None, given).unwrap();
+        assert_eq!(detection.confidence, Confidence::Size);
+        assert_eq!(detection.offsets, offsets);
+
+        // And when the exe has to be searched:
+        exe.extend_from_slice(&[0; 100]);
+        let detection = determine(&exe, &graph.as_slice(), maps_len, None, given).unwrap();
+        assert_eq!(detection.confidence, Confidence::Search);
+        assert_eq!(detection.offsets.graph_dict_offset, offsets.graph_dict_offset);
+    }
+
+    #[test]
+    fn test_given_map_head_checked_instead_of_exe() {
+        // A modded GAMEMAPS with only one map no longer fits the exe's map head, but does fit the MAPHEAD given with it.
+        let (mut exe, graph, _) = keen4();
+        let maps_len = 100;
+        let mut map_head = vec![0xcd, 0xab, 8, 0, 0, 0];
+        map_head.resize(MAP_HEAD_LEN + 10, 0); // Followed by tile info.
+        *exe.last_mut().unwrap() ^= 1; // So it only matches by size.
+        assert!(determine(&exe, &graph.as_slice(), maps_len, None, GivenGraphTables::default()).is_err());
+        let given = GivenGraphTables { map_head: Some(&map_head), ..Default::default() };
+        let detection = determine(&exe, &graph.as_slice(), maps_len, None, given).unwrap();
+        assert_eq!(detection.confidence, Confidence::Size);
+
+        // And when the exe has to be searched:
+        exe.extend_from_slice(&[0; 100]);
+        let detection = determine(&exe, &graph.as_slice(), maps_len, None, given).unwrap();
+        assert_eq!(detection.confidence, Confidence::Search);
+        assert_eq!(detection.offsets.map_head_len, 0);
+    }
+
+    #[test]
+    fn test_chunk_layout() {
+        // Keen 4 1.4's, as in its GFXE_CK4.H.
+        let layout = ChunkLayout::new(Episode::Keen4, 115, 3, 397, 4751).unwrap();
+        assert_eq!((layout.start_sprites(), layout.start_tile_8(), layout.start_tile_16()), (124, 521, 523));
+        assert_eq!((layout.start_masked_tile_16(), layout.start_externs(), layout.externs()), (1819, 4735, 16));
+        assert_eq!(ChunkLayout::guess(115, 3, 397, 4751).unwrap(), (Episode::Keen4, layout));
+        assert!(ChunkLayout::new(Episode::Keen5, 115, 3, 397, 4751).is_err());
+        assert_eq!((layout.extern_kind(0), layout.extern_kind(15)), (Some(ExternKind::Screen), Some(ExternKind::Demo)));
+        assert_eq!(ChunkLayout::new(Episode::Keen4, 115, 3, 397, 4752).unwrap().extern_kind(0), None); // An extra extern.
+    }
 }
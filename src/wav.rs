@@ -0,0 +1,30 @@
+// This is responsible for writing 16-bit mono WAV files.
+// http://soundfile.sapp.org/doc/WaveFormat/
+
+pub fn wav(samples: &[i16], sample_rate: u32) -> Vec<u8> {
+    const CHANNELS: u16 = 1;
+    const BITS_PER_SAMPLE: u16 = 16;
+    let block_align = CHANNELS * BITS_PER_SAMPLE / 8;
+    let data_len = samples.len() as u32 * block_align as u32;
+
+    let mut output = Vec::<u8>::new();
+    output.extend_from_slice(b"RIFF");
+    output.extend_from_slice(&(36 + data_len).to_le_bytes()); // Size of everything after this.
+    output.extend_from_slice(b"WAVE");
+
+    output.extend_from_slice(b"fmt ");
+    output.extend_from_slice(&16u32.to_le_bytes()); // Size of this chunk.
+    output.extend_from_slice(&1u16.to_le_bytes()); // PCM.
+    output.extend_from_slice(&CHANNELS.to_le_bytes());
+    output.extend_from_slice(&sample_rate.to_le_bytes());
+    output.extend_from_slice(&(sample_rate * block_align as u32).to_le_bytes()); // Bytes per second.
+    output.extend_from_slice(&block_align.to_le_bytes());
+    output.extend_from_slice(&BITS_PER_SAMPLE.to_le_bytes());
+
+    output.extend_from_slice(b"data");
+    output.extend_from_slice(&data_len.to_le_bytes());
+    for sample in samples {
+        output.extend_from_slice(&sample.to_le_bytes());
+    }
+    output
+}